@@ -0,0 +1,254 @@
+///! Signal discovery using McGuire SMDE.
+///!
+///! A rolling waterfall is built from the wideband stream and scored with
+///! `mcguire_smde::single`. Neighbouring bins that score high are gathered
+///! into candidate channels with an estimated center frequency and
+///! bandwidth.
+
+use super::SignalMap;
+use super::mcguire_smde;
+use dsp::{Complex, fft};
+use std::collections::VecDeque;
+use std;
+
+///! A channel that appears to hold a signal.
+#[derive(Clone)]
+pub struct Candidate {
+    ///! The estimated center frequency in hertz.
+    pub center:     f64,
+    ///! The estimated bandwidth in hertz.
+    pub bandwidth:  f64,
+    ///! The highest score of the bins in the channel from 0.0 to 1.0.
+    pub score:      f64,
+}
+
+pub struct Discovery {
+    sps:            f64,
+    center:         f64,
+    ///! The number of bins in a waterfall row, a power of two.
+    fftsize:        usize,
+    ///! The number of transforms averaged into each row.
+    pub avg:        usize,
+    ///! The number of rows in the waterfall.
+    pub rows:       usize,
+    ///! The number of new rows between each evaluation.
+    pub every:      usize,
+    ///! Bins scoring at or above this are considered signal.
+    pub threshold:  f64,
+    ///! The number of low scoring bins allowed inside a single channel.
+    pub gap:        usize,
+    ///! The number of bins around the center that are ignored since they
+    ///! hold the DC offset of the receiver.
+    pub dcskip:     usize,
+    waterfall:      VecDeque<Vec<f64>>,
+    pending:        Vec<Complex<f32>>,
+    acc:            Vec<f64>,
+    acccnt:         usize,
+    newrows:        usize,
+}
+
+impl Discovery {
+    ///! Create for a stream at `sps` samples per second centered at
+    ///! `center` hertz.
+    pub fn new(sps: f64, center: f64) -> Result<Discovery, String> {
+        Discovery::new_with_size(sps, center, 1024)
+    }
+
+    ///! Like `new` but with `fftsize` bins in each waterfall row, which
+    ///! must be a power of two.
+    pub fn new_with_size(sps: f64, center: f64, fftsize: usize) -> Result<Discovery, String> {
+        if !(sps > 0.0) || !sps.is_finite() {
+            return Result::Err(format!("{} is not a sample rate", sps));
+        }
+        if !center.is_finite() {
+            return Result::Err(format!("{} is not a center frequency", center));
+        }
+        if fftsize < 2 || !fftsize.is_power_of_two() {
+            return Result::Err(format!("{} bins are not a power of two", fftsize));
+        }
+        Result::Ok(Discovery {
+            sps:        sps,
+            center:     center,
+            fftsize:    fftsize,
+            avg:        16,
+            rows:       64,
+            every:      64,
+            threshold:  0.8,
+            gap:        2,
+            dcskip:     2,
+            waterfall:  VecDeque::new(),
+            pending:    Vec::new(),
+            acc:        Vec::new(),
+            acccnt:     0,
+            newrows:    0,
+        })
+    }
+
+    pub fn fftsize(&self) -> usize {
+        self.fftsize
+    }
+
+    ///! Feed samples from the stream. Once enough new rows have been built
+    ///! the waterfall is evaluated and the candidates are returned.
+    pub fn work(&mut self, buf: &Vec<Complex<f32>>) -> Option<Vec<Candidate>> {
+        let mut found: Option<Vec<Candidate>> = Option::None;
+
+        for x in 0..buf.len() {
+            self.pending.push(buf[x].clone());
+            if self.pending.len() < self.fftsize {
+                continue;
+            }
+
+            let mut block: Vec<Complex<f32>> = Vec::with_capacity(self.fftsize);
+            std::mem::swap(&mut block, &mut self.pending);
+            fft(&mut block);
+
+            if self.acc.len() != self.fftsize {
+                self.acc = vec![0.0f64; self.fftsize];
+            }
+
+            // Shift the output so the lowest frequency is in the first bin.
+            let half = self.fftsize / 2;
+            for b in 0..self.fftsize {
+                let s = &block[(b + half) % self.fftsize];
+                self.acc[b] += (s.i * s.i + s.q * s.q) as f64;
+            }
+            self.acccnt += 1;
+
+            if self.acccnt >= self.avg {
+                let mut row: Vec<f64> = Vec::with_capacity(self.fftsize);
+                for b in 0..self.fftsize {
+                    row.push((self.acc[b] / self.acccnt as f64 + 1e-20).log(10f64));
+                    self.acc[b] = 0.0;
+                }
+                self.acccnt = 0;
+
+                self.waterfall.push_back(row);
+                while self.waterfall.len() > self.rows {
+                    self.waterfall.pop_front();
+                }
+
+                self.newrows += 1;
+                if self.newrows >= self.every && self.waterfall.len() >= self.rows {
+                    self.newrows = 0;
+                    found = Option::Some(self.evaluate());
+                }
+            }
+        }
+
+        found
+    }
+
+    ///! Score the current waterfall and gather the candidates.
+    pub fn evaluate(&self) -> Vec<Candidate> {
+        let mut v: Vec<f64> = Vec::with_capacity(self.fftsize * self.waterfall.len());
+        for row in self.waterfall.iter() {
+            for b in 0..row.len() {
+                v.push(row[b]);
+            }
+        }
+
+        let mut smap = SignalMap {
+            v:      v,
+            w:      self.fftsize,
+            h:      self.waterfall.len(),
+        };
+        smap.normalize();
+
+        let scores = mcguire_smde::single(&smap, 0, smap.h);
+
+        cluster(&scores, self.threshold, self.gap, self.dcskip, self.sps, self.center)
+    }
+}
+
+///! True if bin `x` lies within `dcskip` bins of the center bin `half`.
+fn in_dc(x: usize, half: usize, dcskip: usize) -> bool {
+    x + dcskip > half && x < half + dcskip
+}
+
+///! Gather runs of bins scoring at or above `threshold` into candidates. The
+///! bins are assumed to span `sps` hertz with `center` in the middle.
+pub fn cluster(scores: &Vec<f64>, threshold: f64, gap: usize, dcskip: usize, sps: f64, center: f64) -> Vec<Candidate> {
+    let mut out: Vec<Candidate> = Vec::new();
+    let n = scores.len();
+    let binw = sps / n as f64;
+    let half = n / 2;
+
+    let mut x = 0usize;
+    while x < n {
+        if scores[x] < threshold || in_dc(x, half, dcskip) {
+            x += 1;
+            continue;
+        }
+
+        // Walk the channel allowing for small dips in the score.
+        let s = x;
+        let mut e = x;
+        let mut misses = 0usize;
+        let mut wsum = 0f64;
+        let mut wbin = 0f64;
+        let mut max = 0f64;
+        while x < n && misses <= gap {
+            if scores[x] >= threshold && !in_dc(x, half, dcskip) {
+                e = x;
+                misses = 0;
+                wsum += scores[x];
+                wbin += scores[x] * x as f64;
+                if scores[x] > max {
+                    max = scores[x];
+                }
+            } else {
+                misses += 1;
+            }
+            x += 1;
+        }
+
+        let bin = wbin / wsum;
+        out.push(Candidate {
+            center:     center + (bin - half as f64) * binw,
+            bandwidth:  (e - s + 1) as f64 * binw,
+            score:      max,
+        });
+
+        x = e + 1;
+    }
+
+    out
+}
+
+#[test]
+fn test_discovery_tone() {
+    let sps = 1000000.0;
+    assert!(Discovery::new_with_size(sps, 146000000.0, 1000).is_err());
+    assert!(Discovery::new_with_size(sps, 146000000.0, 0).is_err());
+    assert!(Discovery::new(sps, std::f64::NEG_INFINITY).is_err());
+    assert!(Discovery::new(std::f64::NAN, 146000000.0).is_err());
+
+    let mut disc = Discovery::new_with_size(sps, 146000000.0, 256).unwrap();
+    disc.avg = 4;
+    disc.rows = 16;
+    disc.every = 16;
+
+    // A tone 125 kHz above the center over a little noise, just enough for
+    // one evaluation.
+    let mut seed = 12345u32;
+    let mut buf: Vec<Complex<f32>> = Vec::new();
+    for x in 0..disc.fftsize() * disc.avg * disc.rows {
+        let a = 2.0 * std::f64::consts::PI * 125000.0 * x as f64 / sps;
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        let ni = (seed >> 8) as f32 / 16777216.0 - 0.5;
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        let nq = (seed >> 8) as f32 / 16777216.0 - 0.5;
+        buf.push(Complex { i: a.cos() as f32 * 0.5 + ni * 0.01, q: a.sin() as f32 * 0.5 + nq * 0.01 });
+    }
+
+    let (first, rest) = buf.split_at(buf.len() - 1);
+    assert!(disc.work(&first.to_vec()).is_none());
+    let cands = disc.work(&rest.to_vec()).unwrap();
+    assert_eq!(cands.len(), 1);
+    // Within a bin of the tone.
+    let binw = sps / disc.fftsize() as f64;
+    assert!((cands[0].center - 146125000.0).abs() < binw);
+    assert!(cands[0].bandwidth <= binw * 4.0);
+    assert!(cands[0].score >= disc.threshold);
+}
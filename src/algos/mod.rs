@@ -3,5 +3,6 @@
 
 mod signalmap;
 pub mod mcguire_smde;
+pub mod discovery;
 
 pub use self::signalmap::SignalMap;
\ No newline at end of file
@@ -0,0 +1,205 @@
+///! Receives the audio of monitors from an `AudioServer`.
+
+use super::{AudioFrame, AudioError, Encoding, KIND_SUBSCRIBE, KIND_UNSUBSCRIBE, KIND_OK, KIND_FRAME, KIND_END, KIND_ERROR, decode_frame};
+use ::muds::block::net::{Connection, Credentials, Message, FrameError};
+use ::dsp::{Alsa, WavWriter};
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::io;
+
+pub enum AudioEvent {
+    Frame(AudioFrame),
+    ///! The transmission on `monitor` ended at `time`.
+    End { monitor: usize, time: f64 },
+}
+
+///! Somewhere decoded audio can be written.
+pub trait AudioOut {
+    fn write_audio(&mut self, samples: &[f32]) -> io::Result<()>;
+}
+
+impl AudioOut for Alsa {
+    fn write_audio(&mut self, samples: &[f32]) -> io::Result<()> {
+        self.write(&samples.to_vec());
+        Result::Ok(())
+    }
+}
+
+impl AudioOut for WavWriter {
+    fn write_audio(&mut self, samples: &[f32]) -> io::Result<()> {
+        self.write(samples)
+    }
+}
+
+pub struct AudioClient {
+    conn:       Connection,
+    ///! Events that arrived while waiting for a response.
+    events:     VecDeque<AudioEvent>,
+}
+
+fn decode_end(mut buf: &[u8]) -> Result<AudioEvent, AudioError> {
+    let monitor = try!(buf.read_u32::<LittleEndian>().map_err(|_| AudioError::Malformed));
+    let time = try!(buf.read_f64::<LittleEndian>().map_err(|_| AudioError::Malformed));
+    Result::Ok(AudioEvent::End { monitor: monitor as usize, time: time })
+}
+
+///! Return the event carried by `msg`, or `None` if it is not one.
+fn decode_event(msg: &Message) -> Result<Option<AudioEvent>, AudioError> {
+    match msg.kind {
+        KIND_FRAME => Result::Ok(Option::Some(AudioEvent::Frame(try!(decode_frame(&msg.payload))))),
+        KIND_END => Result::Ok(Option::Some(try!(decode_end(&msg.payload)))),
+        _ => Result::Ok(Option::None),
+    }
+}
+
+impl AudioClient {
+    pub fn connect(addr: &SocketAddr) -> Result<AudioClient, AudioError> {
+        Result::Ok(AudioClient::from_connection(try!(Connection::connect(addr))))
+    }
+
+    ///! Connect to a server that wants listeners to authenticate.
+    pub fn connect_with_key(addr: &SocketAddr, creds: &Credentials) -> Result<AudioClient, AudioError> {
+        Result::Ok(AudioClient::from_connection(try!(Connection::connect_with_key(addr, creds))))
+    }
+
+    fn from_connection(conn: Connection) -> AudioClient {
+        AudioClient {
+            conn:       conn,
+            events:     VecDeque::new(),
+        }
+    }
+
+    ///! Send a request and wait for the server to accept it.
+    fn request(&mut self, kind: u16, body: Vec<u8>) -> Result<(), AudioError> {
+        try!(self.conn.send_msg(&Message::new(kind, body)));
+        loop {
+            let msg = try!(self.conn.recv_msg());
+            match msg.kind {
+                KIND_OK => return Result::Ok(()),
+                KIND_ERROR => return Result::Err(AudioError::Remote(String::from_utf8_lossy(&msg.payload).into_owned())),
+                _ => (),
+            }
+            match try!(decode_event(&msg)) {
+                Option::Some(ev) => self.events.push_back(ev),
+                Option::None => (),
+            }
+        }
+    }
+
+    ///! Listen to `monitors`, or to every monitor if empty, replacing what
+    ///! was listened to before.
+    pub fn subscribe(&mut self, monitors: &[usize], encoding: Encoding) -> Result<(), AudioError> {
+        let mut body: Vec<u8> = Vec::new();
+        body.push(encoding.to_u8());
+        body.write_u16::<LittleEndian>(monitors.len() as u16).unwrap();
+        for m in monitors.iter() {
+            body.write_u32::<LittleEndian>(*m as u32).unwrap();
+        }
+        self.request(KIND_SUBSCRIBE, body)
+    }
+
+    pub fn unsubscribe(&mut self) -> Result<(), AudioError> {
+        self.request(KIND_UNSUBSCRIBE, Vec::new())
+    }
+
+    ///! Block until the next frame or end of a transmission arrives.
+    pub fn next_event(&mut self) -> Result<AudioEvent, AudioError> {
+        match self.events.pop_front() {
+            Option::Some(ev) => return Result::Ok(ev),
+            Option::None => (),
+        }
+        loop {
+            let msg = try!(self.conn.recv_msg());
+            match try!(decode_event(&msg)) {
+                Option::Some(ev) => return Result::Ok(ev),
+                Option::None => (),
+            }
+        }
+    }
+
+    ///! Write the audio of `monitor`, or of every monitor, to `out` until
+    ///! the server closes the connection.
+    pub fn pipe(&mut self, monitor: Option<usize>, out: &mut AudioOut) -> Result<(), AudioError> {
+        loop {
+            let frame = match self.next_event() {
+                Result::Ok(AudioEvent::Frame(frame)) => frame,
+                Result::Ok(AudioEvent::End { .. }) => continue,
+                Result::Err(AudioError::Frame(FrameError::Closed)) => return Result::Ok(()),
+                Result::Err(err) => return Result::Err(err),
+            };
+            if monitor.map_or(true, |m| m == frame.monitor) {
+                try!(out.write_audio(&frame.samples).map_err(|e| AudioError::Frame(FrameError::Io(e))));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_audio_stream() {
+    use super::{AudioServer, AUDIO_RATE};
+    use ::RouterEvent;
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    let (tx, rx) = channel::<RouterEvent>();
+    let server = AudioServer::new("127.0.0.1:0", rx).unwrap();
+
+    let mut pcm = AudioClient::connect(&server.local_addr()).unwrap();
+    pcm.subscribe(&[2], Encoding::Pcm16).unwrap();
+    let mut adpcm = AudioClient::connect(&server.local_addr()).unwrap();
+    adpcm.subscribe(&[], Encoding::Adpcm).unwrap();
+
+    let chunk: Vec<f32> = (0..1600).map(|x| (x as f32 * 0.05).sin() * 0.25).collect();
+    let events = chunk.clone();
+    // Stands in for the router.
+    thread::spawn(move || {
+        tx.send(RouterEvent::Started { monitor: 1, time: 50.0 }).unwrap();
+        tx.send(RouterEvent::Audio { monitor: 1, chunk: events.clone() }).unwrap();
+        tx.send(RouterEvent::Started { monitor: 2, time: 100.0 }).unwrap();
+        tx.send(RouterEvent::Audio { monitor: 2, chunk: events.clone() }).unwrap();
+        tx.send(RouterEvent::Audio { monitor: 2, chunk: events }).unwrap();
+        tx.send(RouterEvent::Ended { monitor: 2, summary: ::TransmissionSummary {
+            freq: 146520000.0, start: 100.0, duration: 0.2, published: false, split: false,
+        }}).unwrap();
+    });
+
+    let mut times: Vec<f64> = Vec::new();
+    for _ in 0..2 {
+        match pcm.next_event().unwrap() {
+            AudioEvent::Frame(frame) => {
+                assert_eq!(frame.monitor, 2);
+                assert_eq!(frame.rate, AUDIO_RATE);
+                for (a, b) in frame.samples.iter().zip(chunk.iter()) {
+                    assert!((a - b).abs() < 0.0001);
+                }
+                times.push(frame.time);
+            },
+            _ => panic!("expected a frame"),
+        }
+    }
+    assert_eq!(times[0], 100.0);
+    assert!((times[1] - 100.1).abs() < 1e-9);
+    match pcm.next_event().unwrap() {
+        AudioEvent::End { monitor, time } => {
+            assert_eq!(monitor, 2);
+            assert!((time - 100.2).abs() < 1e-9);
+        },
+        _ => panic!("expected the end"),
+    }
+
+    // The compressed listener hears both monitors.
+    let mut monitors: Vec<usize> = Vec::new();
+    for _ in 0..3 {
+        match adpcm.next_event().unwrap() {
+            AudioEvent::Frame(frame) => {
+                for (a, b) in frame.samples.iter().zip(chunk.iter()) {
+                    assert!((a - b).abs() < 0.02);
+                }
+                monitors.push(frame.monitor);
+            },
+            _ => panic!("expected a frame"),
+        }
+    }
+    assert_eq!(monitors, vec![1, 2, 2]);
+}
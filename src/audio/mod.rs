@@ -0,0 +1,276 @@
+///! Live audio of monitors for listeners on the network.
+///!
+///! `AudioServer` takes the router events and sends the audio of the
+///! monitors each listener subscribed to as frames over a net server.
+///! `AudioClient` subscribes and decodes the frames again.
+///!
+///! A frame is the monitor id, the host time of its first sample, the rate,
+///! the encoding and the sample count, followed by the samples either as
+///! 16 bit PCM or as IMA ADPCM at a quarter of the size. Every frame carries
+///! its own ADPCM state so a frame that is dropped does not spoil the next.
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+use ::muds::block::net::FrameError;
+use std;
+
+mod server;
+mod client;
+
+pub use self::server::AudioServer;
+pub use self::client::{AudioClient, AudioEvent, AudioOut};
+
+///! The rate of the audio produced by monitors.
+pub const AUDIO_RATE: u32 = 16000;
+
+pub const KIND_SUBSCRIBE: u16 = 0x0301;
+pub const KIND_UNSUBSCRIBE: u16 = 0x0302;
+pub const KIND_OK: u16 = 0x0303;
+pub const KIND_FRAME: u16 = 0x0310;
+pub const KIND_END: u16 = 0x0311;
+pub const KIND_ERROR: u16 = 0x03ff;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Pcm16,
+    ///! IMA ADPCM, four bits a sample.
+    Adpcm,
+}
+
+impl Encoding {
+    pub fn to_u8(&self) -> u8 {
+        match *self {
+            Encoding::Pcm16 => 0,
+            Encoding::Adpcm => 1,
+        }
+    }
+
+    pub fn from_u8(v: u8) -> Option<Encoding> {
+        match v {
+            0 => Option::Some(Encoding::Pcm16),
+            1 => Option::Some(Encoding::Adpcm),
+            _ => Option::None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AudioError {
+    Frame(FrameError),
+    ///! The server refused the request with this reason.
+    Remote(String),
+    ///! A message could not be understood.
+    Malformed,
+}
+
+impl From<FrameError> for AudioError {
+    fn from(err: FrameError) -> AudioError {
+        AudioError::Frame(err)
+    }
+}
+
+///! Audio of one monitor.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioFrame {
+    pub monitor:    usize,
+    ///! The host time in seconds of the first sample.
+    pub time:       f64,
+    pub rate:       u32,
+    pub samples:    Vec<f32>,
+}
+
+fn to_i16(v: f32) -> i16 {
+    let v = v * 32767.0;
+    if v > 32767.0 {
+        32767
+    } else if v < -32768.0 {
+        -32768
+    } else {
+        v as i16
+    }
+}
+
+const INDEX_TABLE: [i32; 16] = [-1, -1, -1, -1, 2, 4, 6, 8, -1, -1, -1, -1, 2, 4, 6, 8];
+
+const STEP_TABLE: [i32; 89] = [
+    7, 8, 9, 10, 11, 12, 13, 14, 16, 17, 19, 21, 23, 25, 28, 31, 34, 37, 41, 45,
+    50, 55, 60, 66, 73, 80, 88, 97, 107, 118, 130, 143, 157, 173, 190, 209, 230,
+    253, 279, 307, 337, 371, 408, 449, 494, 544, 598, 658, 724, 796, 876, 963,
+    1060, 1166, 1282, 1411, 1552, 1707, 1878, 2066, 2272, 2499, 2749, 3024, 3327,
+    3660, 4026, 4428, 4871, 5358, 5894, 6484, 7132, 7845, 8630, 9493, 10442,
+    11487, 12635, 13899, 15289, 16818, 18500, 20350, 22385, 24623, 27086, 29794,
+    32767,
+];
+
+///! The state shared by the ADPCM encoder and decoder.
+struct Adpcm {
+    predictor:  i32,
+    index:      i32,
+}
+
+impl Adpcm {
+    ///! Move on by the step `nibble` describes and return the new sample.
+    fn step(&mut self, nibble: u8) -> i16 {
+        let step = STEP_TABLE[self.index as usize];
+        let mut delta = step >> 3;
+        if nibble & 4 != 0 {
+            delta += step;
+        }
+        if nibble & 2 != 0 {
+            delta += step >> 1;
+        }
+        if nibble & 1 != 0 {
+            delta += step >> 2;
+        }
+        self.predictor += if nibble & 8 != 0 { -delta } else { delta };
+        self.predictor = std::cmp::max(-32768, std::cmp::min(32767, self.predictor));
+        self.index = std::cmp::max(0, std::cmp::min(88, self.index + INDEX_TABLE[nibble as usize]));
+        self.predictor as i16
+    }
+
+    fn encode(&mut self, sample: i16) -> u8 {
+        let step = STEP_TABLE[self.index as usize];
+        let mut diff = sample as i32 - self.predictor;
+        let mut nibble = 0u8;
+        if diff < 0 {
+            nibble = 8;
+            diff = -diff;
+        }
+        if diff >= step {
+            nibble |= 4;
+            diff -= step;
+        }
+        if diff >= step >> 1 {
+            nibble |= 2;
+            diff -= step >> 1;
+        }
+        if diff >= step >> 2 {
+            nibble |= 1;
+        }
+        // Track what the decoder will make of it.
+        self.step(nibble);
+        nibble
+    }
+}
+
+///! Return the payload of a frame message.
+pub fn encode_frame(frame: &AudioFrame, encoding: Encoding) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::with_capacity(21 + frame.samples.len() * 2);
+    out.write_u32::<LittleEndian>(frame.monitor as u32).unwrap();
+    out.write_f64::<LittleEndian>(frame.time).unwrap();
+    out.write_u32::<LittleEndian>(frame.rate).unwrap();
+    out.push(encoding.to_u8());
+    out.write_u32::<LittleEndian>(frame.samples.len() as u32).unwrap();
+    match encoding {
+        Encoding::Pcm16 => {
+            for s in frame.samples.iter() {
+                out.write_i16::<LittleEndian>(to_i16(*s)).unwrap();
+            }
+        },
+        Encoding::Adpcm => {
+            // Start from the first sample and a step that fits the first
+            // change so the decoder has little to catch up on.
+            let first = frame.samples.first().map(|s| to_i16(*s)).unwrap_or(0);
+            let change = match frame.samples.get(1) {
+                Option::Some(s) => (to_i16(*s) as i32 - first as i32).abs(),
+                Option::None => 0,
+            };
+            let mut index = 0;
+            while index < 88 && STEP_TABLE[index] < change {
+                index += 1;
+            }
+            let mut state = Adpcm { predictor: first as i32, index: index as i32 };
+            out.write_i16::<LittleEndian>(first).unwrap();
+            out.push(index as u8);
+            for pair in frame.samples.chunks(2) {
+                let lo = state.encode(to_i16(pair[0]));
+                let hi = if pair.len() > 1 { state.encode(to_i16(pair[1])) } else { 0 };
+                out.push(lo | hi << 4);
+            }
+        },
+    }
+    out
+}
+
+///! Parse the payload of a frame message.
+pub fn decode_frame(mut buf: &[u8]) -> Result<AudioFrame, AudioError> {
+    let monitor = try!(buf.read_u32::<LittleEndian>().map_err(|_| AudioError::Malformed));
+    let time = try!(buf.read_f64::<LittleEndian>().map_err(|_| AudioError::Malformed));
+    let rate = try!(buf.read_u32::<LittleEndian>().map_err(|_| AudioError::Malformed));
+    let encoding = match Encoding::from_u8(try!(buf.read_u8().map_err(|_| AudioError::Malformed))) {
+        Option::Some(encoding) => encoding,
+        Option::None => return Result::Err(AudioError::Malformed),
+    };
+    let count = try!(buf.read_u32::<LittleEndian>().map_err(|_| AudioError::Malformed)) as usize;
+
+    // Check the count against what arrived before allocating for it.
+    let needed = match encoding {
+        Encoding::Pcm16 => count.saturating_mul(2),
+        Encoding::Adpcm => 3 + (count + 1) / 2,
+    };
+    if buf.len() < needed {
+        return Result::Err(AudioError::Malformed);
+    }
+
+    let mut samples: Vec<f32> = Vec::with_capacity(count);
+    match encoding {
+        Encoding::Pcm16 => {
+            for _ in 0..count {
+                samples.push(buf.read_i16::<LittleEndian>().unwrap() as f32 / 32767.0);
+            }
+        },
+        Encoding::Adpcm => {
+            let first = buf.read_i16::<LittleEndian>().unwrap();
+            let index = buf.read_u8().unwrap() as i32;
+            if index > 88 {
+                return Result::Err(AudioError::Malformed);
+            }
+            let mut state = Adpcm { predictor: first as i32, index: index };
+            for x in 0..count {
+                let byte = buf[x / 2];
+                let nibble = if x % 2 == 0 { byte & 0xf } else { byte >> 4 };
+                samples.push(state.step(nibble) as f32 / 32767.0);
+            }
+        },
+    }
+
+    Result::Ok(AudioFrame {
+        monitor:    monitor as usize,
+        time:       time,
+        rate:       rate,
+        samples:    samples,
+    })
+}
+
+#[test]
+fn test_audio_codec() {
+    let mut samples: Vec<f32> = Vec::new();
+    for x in 0..1001 {
+        samples.push((x as f32 * 2.0 * 3.14159 * 440.0 / 16000.0).sin() * 0.5);
+    }
+    let frame = AudioFrame { monitor: 3, time: 1000.5, rate: AUDIO_RATE, samples: samples };
+
+    let pcm = encode_frame(&frame, Encoding::Pcm16);
+    let adpcm = encode_frame(&frame, Encoding::Adpcm);
+    assert_eq!(pcm.len(), 21 + 1001 * 2);
+    assert_eq!(adpcm.len(), 21 + 3 + 501);
+
+    for (buf, tolerance) in vec![(pcm, 0.0001), (adpcm, 0.02)] {
+        let got = decode_frame(&buf).unwrap();
+        assert_eq!(got.monitor, 3);
+        assert_eq!(got.time, 1000.5);
+        assert_eq!(got.rate, AUDIO_RATE);
+        assert_eq!(got.samples.len(), frame.samples.len());
+        for (a, b) in got.samples.iter().zip(frame.samples.iter()) {
+            assert!((a - b).abs() < tolerance);
+        }
+    }
+    assert!(decode_frame(&[1, 2, 3]).is_err());
+
+    // A count far beyond what arrived is refused rather than allocated.
+    let mut huge = encode_frame(&AudioFrame { monitor: 0, time: 0.0, rate: AUDIO_RATE, samples: vec![0.0; 4] }, Encoding::Pcm16);
+    huge[17] = 0xff;
+    huge[18] = 0xff;
+    huge[19] = 0xff;
+    huge[20] = 0xff;
+    assert!(decode_frame(&huge).is_err());
+}
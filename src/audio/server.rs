@@ -0,0 +1,229 @@
+///! Sends the audio of monitors to the listeners subscribed to them.
+
+use super::{AudioFrame, Encoding, AUDIO_RATE, KIND_SUBSCRIBE, KIND_UNSUBSCRIBE, KIND_OK, KIND_FRAME, KIND_END, KIND_ERROR, encode_frame};
+use ::RouterEvent;
+use ::muds::block::net::{Server, ServerConfig, ControlInfo, Client, Message, Queue};
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Receiver;
+use std::net::SocketAddr;
+use std::thread;
+
+///! Listeners taking longer than this to accept a frame are dropped.
+const WRITE_TIMEOUT: f64 = 2.0;
+
+///! The messages held for a listener before the oldest are dropped.
+const QUEUE_LEN: usize = 64;
+
+struct Listener {
+    queue:      Arc<Queue>,
+    ///! The monitors listened to, `None` for all of them.
+    monitors:   Option<Vec<usize>>,
+    encoding:   Encoding,
+}
+
+impl Listener {
+    ///! Start sending to `client` from a thread of its own, so a listener
+    ///! that falls behind only loses its own frames.
+    fn new(luid: u64, client: &Arc<Mutex<Client>>, monitors: Option<Vec<usize>>, encoding: Encoding) -> Listener {
+        let queue = Arc::new(Queue::new(QUEUE_LEN));
+        let q = queue.clone();
+        let client = client.clone();
+        let writer = {
+            let mut lck = client.lock().unwrap();
+            let _ = lck.set_write_timeout(Option::Some(WRITE_TIMEOUT));
+            lck.writer()
+        };
+        thread::spawn(move || {
+            loop {
+                let msg = match q.pop() {
+                    Option::Some(msg) => msg,
+                    Option::None => return,
+                };
+                if writer.send_msg(&msg).is_err() {
+                    // The server then reports it gone, which removes it.
+                    println!("[audio] dropped listener {}", luid);
+                    client.lock().unwrap().close();
+                    return;
+                }
+            }
+        });
+        Listener { queue: queue, monitors: monitors, encoding: encoding }
+    }
+
+    fn wants(&self, monitor: usize) -> bool {
+        match self.monitors {
+            Option::Some(ref monitors) => monitors.contains(&monitor),
+            Option::None => true,
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.queue.close();
+    }
+}
+
+type Listeners = Arc<Mutex<HashMap<u64, Listener>>>;
+
+pub struct AudioServer {
+    addr:       SocketAddr,
+}
+
+impl AudioServer {
+    ///! Listen on `addr` and stream the audio of the router `events`, as
+    ///! subscribed with `RouterCommand::Subscribe` or `RouterConfig::events`.
+    pub fn new(addr: &str, events: Receiver<RouterEvent>) -> Option<AudioServer> {
+        AudioServer::new_with_config(addr, events, ServerConfig::new())
+    }
+
+    ///! Like `new` but with the settings of the net server, for instance to
+    ///! require listeners to authenticate.
+    pub fn new_with_config(addr: &str, events: Receiver<RouterEvent>, cfg: ServerConfig) -> Option<AudioServer> {
+        let server = match Server::new_with_config(addr, cfg) {
+            Option::Some(server) => server,
+            Option::None => return Option::None,
+        };
+        let local = server.local_addr();
+        let listeners: Listeners = Arc::new(Mutex::new(HashMap::new()));
+        let l = listeners.clone();
+        thread::spawn(move || serve(server, l));
+        thread::spawn(move || stream(events, listeners));
+        Option::Some(AudioServer { addr: local })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+fn serve(server: Server, listeners: Listeners) {
+    loop {
+        let (luid, client) = match server.read() {
+            Result::Ok(ControlInfo::ClientData { luid, client }) => (luid, client),
+            Result::Ok(ControlInfo::ClientBye { luid, .. }) => {
+                listeners.lock().unwrap().remove(&luid);
+                continue;
+            },
+            Result::Ok(_) => continue,
+            Result::Err(_) => return,
+        };
+        loop {
+            let msg = match client.lock().unwrap().recv_msg() {
+                Result::Ok(Option::Some(msg)) => msg,
+                Result::Ok(Option::None) => break,
+                Result::Err(_) => {
+                    server.disconnect(luid);
+                    break;
+                },
+            };
+            let reply = match handle(&msg, luid, &client, &listeners) {
+                Result::Ok(()) => Message::new(KIND_OK, Vec::new()),
+                Result::Err(err) => Message::new(KIND_ERROR, err.into_bytes()),
+            };
+            let writer = client.lock().unwrap().writer();
+            let _ = writer.send_msg(&reply);
+        }
+    }
+}
+
+fn malformed() -> String {
+    String::from("malformed request")
+}
+
+fn handle(msg: &Message, luid: u64, client: &Arc<Mutex<Client>>, listeners: &Listeners) -> Result<(), String> {
+    let mut buf = &msg.payload[..];
+    match msg.kind {
+        KIND_SUBSCRIBE => {
+            let encoding = match Encoding::from_u8(try!(buf.read_u8().map_err(|_| malformed()))) {
+                Option::Some(encoding) => encoding,
+                Option::None => return Result::Err(String::from("unknown encoding")),
+            };
+            let count = try!(buf.read_u16::<LittleEndian>().map_err(|_| malformed()));
+            let mut monitors: Vec<usize> = Vec::new();
+            for _ in 0..count {
+                monitors.push(try!(buf.read_u32::<LittleEndian>().map_err(|_| malformed())) as usize);
+            }
+            let monitors = if count == 0 { Option::None } else { Option::Some(monitors) };
+            let mut listeners = listeners.lock().unwrap();
+            // Subscribing again only changes what is sent.
+            match listeners.get_mut(&luid) {
+                Option::Some(listener) => {
+                    listener.monitors = monitors;
+                    listener.encoding = encoding;
+                    return Result::Ok(());
+                },
+                Option::None => (),
+            }
+            listeners.insert(luid, Listener::new(luid, client, monitors, encoding));
+            Result::Ok(())
+        },
+        KIND_UNSUBSCRIBE => {
+            listeners.lock().unwrap().remove(&luid);
+            Result::Ok(())
+        },
+        _ => Result::Err(format!("unknown request {}", msg.kind)),
+    }
+}
+
+///! Pass the audio of each monitor to its listeners until the router stops.
+fn stream(events: Receiver<RouterEvent>, listeners: Listeners) {
+    // The host time of the next sample of each transmitting monitor.
+    let mut next: HashMap<usize, f64> = HashMap::new();
+    for ev in events.iter() {
+        let (monitor, frame, end) = match ev {
+            RouterEvent::Started { monitor, time } => {
+                next.insert(monitor, time);
+                continue;
+            },
+            RouterEvent::Audio { monitor, chunk } => {
+                let time = match next.get_mut(&monitor) {
+                    Option::Some(time) => {
+                        let t = *time;
+                        *time += chunk.len() as f64 / AUDIO_RATE as f64;
+                        t
+                    },
+                    Option::None => ::now(),
+                };
+                let frame = AudioFrame { monitor: monitor, time: time, rate: AUDIO_RATE, samples: chunk };
+                (monitor, Option::Some(frame), Option::None)
+            },
+            RouterEvent::Ended { monitor, .. } => {
+                let time = next.remove(&monitor).unwrap_or_else(::now);
+                let mut out: Vec<u8> = Vec::new();
+                out.write_u32::<LittleEndian>(monitor as u32).unwrap();
+                out.write_f64::<LittleEndian>(time).unwrap();
+                (monitor, Option::None, Option::Some(Message::new(KIND_END, out)))
+            },
+        };
+
+        // Each encoding in use is encoded once.
+        let mut pcm: Option<Message> = Option::None;
+        let mut adpcm: Option<Message> = Option::None;
+        // Only queued here, the sending is left to the thread of each
+        // listener.
+        let listeners = listeners.lock().unwrap();
+        for listener in listeners.values() {
+            if !listener.wants(monitor) {
+                continue;
+            }
+            let msg = match (&frame, &end) {
+                (&Option::Some(ref frame), _) => {
+                    let cache = match listener.encoding {
+                        Encoding::Pcm16 => &mut pcm,
+                        Encoding::Adpcm => &mut adpcm,
+                    };
+                    if cache.is_none() {
+                        *cache = Option::Some(Message::new(KIND_FRAME, encode_frame(frame, listener.encoding)));
+                    }
+                    cache.as_ref().unwrap().clone()
+                },
+                (_, &Option::Some(ref end)) => end.clone(),
+                _ => continue,
+            };
+            listener.queue.push(msg);
+        }
+    }
+}
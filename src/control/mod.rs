@@ -0,0 +1,528 @@
+///! Remote control of a running router.
+///!
+///! `ControlServer` accepts framed messages on a net server and turns them
+///! into `RouterCommand`s for the router given the receiving end through
+///! `RouterConfig::control`. `ControlClient` is the other end for scripts.
+///!
+///! Every request starts with a `u32` request id which is repeated at the
+///! start of the response so responses can be told apart from events.
+
+use ::{RouterCommand, RouterEvent, MonitorInfo, TransmissionSummary};
+use ::muds::block::net::{Server, ServerConfig, ControlInfo, Client, ClientWriter, Connection, Credentials, Permission, Message, FrameError, Queue};
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Sender, Receiver, channel};
+use std::net::SocketAddr;
+use std::thread;
+
+const KIND_LIST: u16 = 0x0201;
+const KIND_ADD: u16 = 0x0202;
+const KIND_REMOVE: u16 = 0x0203;
+const KIND_GAIN: u16 = 0x0204;
+const KIND_STATS: u16 = 0x0205;
+const KIND_SUBSCRIBE: u16 = 0x0206;
+const KIND_MONITORS: u16 = 0x0281;
+const KIND_ADDED: u16 = 0x0282;
+const KIND_OK: u16 = 0x0283;
+const KIND_STATS_JSON: u16 = 0x0284;
+const KIND_EVENT: u16 = 0x02f0;
+const KIND_ERROR: u16 = 0x02ff;
+
+///! The requests allowed to wait on the router at once.
+const MAX_WAITING: usize = 64;
+
+///! The events held for a subscriber before the oldest are dropped.
+const QUEUE_LEN: usize = 256;
+
+///! Subscribers taking longer than this to accept an event are dropped.
+const WRITE_TIMEOUT: f64 = 2.0;
+
+const EVENT_STARTED: u8 = 0;
+const EVENT_ENDED: u8 = 1;
+
+#[derive(Debug)]
+pub enum ControlError {
+    Frame(FrameError),
+    ///! The router refused the request with this reason.
+    Remote(String),
+    ///! A message could not be understood.
+    Malformed,
+}
+
+impl From<FrameError> for ControlError {
+    fn from(err: FrameError) -> ControlError {
+        ControlError::Frame(err)
+    }
+}
+
+fn gone(id: u32) -> (u32, String) {
+    (id, String::from("router is not running"))
+}
+
+fn malformed(id: u32) -> (u32, String) {
+    (id, String::from("malformed request"))
+}
+
+///! Start the payload of a message answering or making request `id`.
+fn start(id: u32) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::new();
+    out.write_u32::<LittleEndian>(id).unwrap();
+    out
+}
+
+fn encode_event(ev: &RouterEvent) -> Option<Vec<u8>> {
+    let mut out: Vec<u8> = Vec::new();
+    match *ev {
+        RouterEvent::Started { monitor, time } => {
+            out.push(EVENT_STARTED);
+            out.write_u32::<LittleEndian>(monitor as u32).unwrap();
+            out.write_f64::<LittleEndian>(time).unwrap();
+        },
+        RouterEvent::Ended { monitor, ref summary } => {
+            out.push(EVENT_ENDED);
+            out.write_u32::<LittleEndian>(monitor as u32).unwrap();
+            out.write_f64::<LittleEndian>(summary.freq).unwrap();
+            out.write_f64::<LittleEndian>(summary.start).unwrap();
+            out.write_f64::<LittleEndian>(summary.duration).unwrap();
+            out.push(summary.published as u8);
+            out.push(summary.split as u8);
+        },
+        // Audio is left to the streaming service.
+        RouterEvent::Audio { .. } => return Option::None,
+    }
+    Option::Some(out)
+}
+
+fn decode_event(mut buf: &[u8]) -> Result<RouterEvent, ControlError> {
+    let kind = try!(buf.read_u8().map_err(|_| ControlError::Malformed));
+    let monitor = try!(buf.read_u32::<LittleEndian>().map_err(|_| ControlError::Malformed)) as usize;
+    match kind {
+        EVENT_STARTED => {
+            let time = try!(buf.read_f64::<LittleEndian>().map_err(|_| ControlError::Malformed));
+            Result::Ok(RouterEvent::Started { monitor: monitor, time: time })
+        },
+        EVENT_ENDED => {
+            let freq = try!(buf.read_f64::<LittleEndian>().map_err(|_| ControlError::Malformed));
+            let start = try!(buf.read_f64::<LittleEndian>().map_err(|_| ControlError::Malformed));
+            let duration = try!(buf.read_f64::<LittleEndian>().map_err(|_| ControlError::Malformed));
+            let published = try!(buf.read_u8().map_err(|_| ControlError::Malformed)) != 0;
+            let split = try!(buf.read_u8().map_err(|_| ControlError::Malformed)) != 0;
+            Result::Ok(RouterEvent::Ended { monitor: monitor, summary: TransmissionSummary {
+                freq:       freq,
+                start:      start,
+                duration:   duration,
+                published:  published,
+                split:      split,
+            }})
+        },
+        _ => Result::Err(ControlError::Malformed),
+    }
+}
+
+pub struct ControlServer {
+    addr:       SocketAddr,
+}
+
+impl ControlServer {
+    ///! Listen on `addr` and pass requests to the router through `commands`.
+    pub fn new(addr: &str, commands: Sender<RouterCommand>) -> Option<ControlServer> {
+        ControlServer::new_with_config(addr, commands, ServerConfig::new())
+    }
+
+    ///! Like `new` but with the settings of the net server, for instance to
+    ///! require clients to authenticate.
+    pub fn new_with_config(addr: &str, commands: Sender<RouterCommand>, cfg: ServerConfig) -> Option<ControlServer> {
+        let server = match Server::new_with_config(addr, cfg) {
+            Option::Some(server) => server,
+            Option::None => return Option::None,
+        };
+        let local = server.local_addr();
+        thread::spawn(move || serve(server, commands));
+        Option::Some(ControlServer { addr: local })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+fn serve(server: Server, commands: Sender<RouterCommand>) {
+    let waiting = Arc::new(AtomicUsize::new(0));
+    // The events waiting for each subscribed connection.
+    let mut subscriptions: HashMap<u64, Arc<Queue>> = HashMap::new();
+    loop {
+        let (luid, client) = match server.read() {
+            Result::Ok(ControlInfo::ClientData { luid, client }) => (luid, client),
+            Result::Ok(ControlInfo::ClientBye { luid, .. }) => {
+                match subscriptions.remove(&luid) {
+                    Option::Some(queue) => queue.close(),
+                    Option::None => (),
+                }
+                continue;
+            },
+            Result::Ok(_) => continue,
+            Result::Err(_) => return,
+        };
+        loop {
+            let msg = match client.lock().unwrap().recv_msg() {
+                Result::Ok(Option::Some(msg)) => msg,
+                Result::Ok(Option::None) => break,
+                Result::Err(_) => {
+                    server.disconnect(luid);
+                    break;
+                },
+            };
+            let writer = client.lock().unwrap().writer();
+            match handle(&msg, luid, &client, &writer, &commands, &waiting, &mut subscriptions) {
+                Result::Ok(Option::None) => (),
+                Result::Ok(Option::Some(reply)) => { let _ = writer.send_msg(&reply); },
+                Result::Err(err) => { let _ = writer.send_msg(&error(err)); },
+            }
+        }
+    }
+}
+
+fn error((id, err): (u32, String)) -> Message {
+    let mut out = start(id);
+    out.extend(err.into_bytes().into_iter());
+    Message::new(KIND_ERROR, out)
+}
+
+///! Answer request `id` from a thread of its own once the router replies on
+///! `rx`, so a busy router does not hold up the requests of other clients.
+///! `answer` is given the reply and the start of the response.
+fn later<T, F>(id: u32, rx: Receiver<T>, writer: &Arc<ClientWriter>, waiting: &Arc<AtomicUsize>, answer: F)
+    where T: Send + 'static, F: FnOnce(T, Vec<u8>) -> Result<Message, (u32, String)> + Send + 'static {
+    let writer = writer.clone();
+    let waiting = waiting.clone();
+    waiting.fetch_add(1, Ordering::SeqCst);
+    thread::spawn(move || {
+        let reply = match rx.recv() {
+            Result::Ok(v) => answer(v, start(id)),
+            Result::Err(_) => Result::Err(gone(id)),
+        };
+        let _ = writer.send_msg(&match reply {
+            Result::Ok(reply) => reply,
+            Result::Err(err) => error(err),
+        });
+        waiting.fetch_sub(1, Ordering::SeqCst);
+    });
+}
+
+///! Carry out one request. Returns the response, or `None` if it is sent
+///! later, or the request id and the reason it failed.
+fn handle(msg: &Message, luid: u64, client: &Arc<Mutex<Client>>, writer: &Arc<ClientWriter>, commands: &Sender<RouterCommand>,
+          waiting: &Arc<AtomicUsize>, subscriptions: &mut HashMap<u64, Arc<Queue>>) -> Result<Option<Message>, (u32, String)> {
+    let mut buf = &msg.payload[..];
+    let id = match buf.read_u32::<LittleEndian>() {
+        Result::Ok(id) => id,
+        Result::Err(_) => return Result::Err(malformed(0)),
+    };
+
+    // Listeners may look but not change anything.
+    match msg.kind {
+        KIND_ADD | KIND_REMOVE | KIND_GAIN => {
+            if client.lock().unwrap().permission() != Permission::Control {
+                return Result::Err((id, String::from("not permitted")));
+            }
+        },
+        _ => (),
+    }
+
+    // Each request waiting on the router holds a thread.
+    match msg.kind {
+        KIND_LIST | KIND_ADD | KIND_REMOVE | KIND_GAIN | KIND_STATS => {
+            if waiting.load(Ordering::SeqCst) >= MAX_WAITING {
+                return Result::Err((id, String::from("too many requests waiting")));
+            }
+        },
+        _ => (),
+    }
+
+    match msg.kind {
+        KIND_LIST => {
+            let (tx, rx) = channel();
+            try!(commands.send(RouterCommand::ListMonitors { reply: tx }).map_err(|_| gone(id)));
+            later(id, rx, writer, waiting, |monitors: Vec<MonitorInfo>, mut out| {
+                out.write_u16::<LittleEndian>(monitors.len() as u16).unwrap();
+                for m in monitors.iter() {
+                    out.write_u32::<LittleEndian>(m.id as u32).unwrap();
+                    out.write_f64::<LittleEndian>(m.freq).unwrap();
+                    out.write_u16::<LittleEndian>(m.channel as u16).unwrap();
+                    out.push(m.temporary as u8);
+                }
+                Result::Ok(Message::new(KIND_MONITORS, out))
+            });
+            Result::Ok(Option::None)
+        },
+        KIND_ADD => {
+            let freq = try!(buf.read_f64::<LittleEndian>().map_err(|_| malformed(id)));
+            let (tx, rx) = channel();
+            try!(commands.send(RouterCommand::AddMonitor { freq: freq, reply: tx }).map_err(|_| gone(id)));
+            later(id, rx, writer, waiting, move |added: Result<usize, String>, mut out| {
+                let mid = try!(added.map_err(|e| (id, e)));
+                out.write_u32::<LittleEndian>(mid as u32).unwrap();
+                Result::Ok(Message::new(KIND_ADDED, out))
+            });
+            Result::Ok(Option::None)
+        },
+        KIND_REMOVE => {
+            let mid = try!(buf.read_u32::<LittleEndian>().map_err(|_| malformed(id)));
+            let (tx, rx) = channel();
+            try!(commands.send(RouterCommand::RemoveMonitor { id: mid as usize, reply: tx }).map_err(|_| gone(id)));
+            later(id, rx, writer, waiting, move |done: Result<(), String>, out| {
+                try!(done.map_err(|e| (id, e)));
+                Result::Ok(Message::new(KIND_OK, out))
+            });
+            Result::Ok(Option::None)
+        },
+        KIND_GAIN => {
+            let gain = try!(buf.read_f64::<LittleEndian>().map_err(|_| malformed(id)));
+            let (tx, rx) = channel();
+            try!(commands.send(RouterCommand::SetGain { gain: gain, reply: tx }).map_err(|_| gone(id)));
+            later(id, rx, writer, waiting, move |done: Result<(), String>, out| {
+                try!(done.map_err(|e| (id, e)));
+                Result::Ok(Message::new(KIND_OK, out))
+            });
+            Result::Ok(Option::None)
+        },
+        KIND_STATS => {
+            let (tx, rx) = channel();
+            try!(commands.send(RouterCommand::Stats { reply: tx }).map_err(|_| gone(id)));
+            later(id, rx, writer, waiting, move |json: Result<String, String>, mut out| {
+                let json = try!(json.map_err(|e| (id, e)));
+                out.extend(json.into_bytes().into_iter());
+                Result::Ok(Message::new(KIND_STATS_JSON, out))
+            });
+            Result::Ok(Option::None)
+        },
+        KIND_SUBSCRIBE => {
+            // One subscription per connection, ended along with it.
+            if subscriptions.contains_key(&luid) {
+                return Result::Err((id, String::from("already subscribed")));
+            }
+            let (tx, rx) = channel();
+            try!(commands.send(RouterCommand::Subscribe { events: tx, audio: false }).map_err(|_| gone(id)));
+            let queue = Arc::new(Queue::new(QUEUE_LEN));
+            subscriptions.insert(luid, queue.clone());
+            let _ = client.lock().unwrap().set_write_timeout(Option::Some(WRITE_TIMEOUT));
+
+            // Events are taken as they come so the router never holds them
+            // for a slow subscriber, and are sent from a thread of their own.
+            let q = queue.clone();
+            thread::spawn(move || {
+                for ev in rx.iter() {
+                    // Dropping the receiver unsubscribes.
+                    if q.is_closed() {
+                        return;
+                    }
+                    match encode_event(&ev) {
+                        Option::Some(payload) => { q.push(Message::new(KIND_EVENT, payload)); },
+                        Option::None => (),
+                    }
+                }
+            });
+            let writer = writer.clone();
+            let client = client.clone();
+            thread::spawn(move || {
+                loop {
+                    let msg = match queue.pop() {
+                        Option::Some(msg) => msg,
+                        Option::None => return,
+                    };
+                    if writer.send_msg(&msg).is_err() {
+                        // The server then reports it gone, which removes it.
+                        queue.close();
+                        client.lock().unwrap().close();
+                        return;
+                    }
+                }
+            });
+            Result::Ok(Option::Some(Message::new(KIND_OK, start(id))))
+        },
+        _ => Result::Err((id, format!("unknown request {}", msg.kind))),
+    }
+}
+
+///! Drives a router through a `ControlServer`.
+pub struct ControlClient {
+    conn:       Connection,
+    next:       u32,
+    ///! Events that arrived while waiting for a response.
+    events:     VecDeque<RouterEvent>,
+}
+
+impl ControlClient {
+    pub fn connect(addr: &SocketAddr) -> Result<ControlClient, ControlError> {
+        Result::Ok(ControlClient::from_connection(try!(Connection::connect(addr))))
+    }
+
+    ///! Connect to a server that wants clients to authenticate.
+    pub fn connect_with_key(addr: &SocketAddr, creds: &Credentials) -> Result<ControlClient, ControlError> {
+        Result::Ok(ControlClient::from_connection(try!(Connection::connect_with_key(addr, creds))))
+    }
+
+    fn from_connection(conn: Connection) -> ControlClient {
+        ControlClient {
+            conn:       conn,
+            next:       1,
+            events:     VecDeque::new(),
+        }
+    }
+
+    ///! Send a request and wait for its response, returning the payload
+    ///! after the request id.
+    fn request(&mut self, kind: u16, body: &[u8], expect: u16) -> Result<Vec<u8>, ControlError> {
+        let id = self.next;
+        self.next += 1;
+        let mut out = start(id);
+        out.extend(body.iter().cloned());
+        try!(self.conn.send_msg(&Message::new(kind, out)));
+        loop {
+            let msg = try!(self.conn.recv_msg());
+            if msg.kind == KIND_EVENT {
+                self.events.push_back(try!(decode_event(&msg.payload)));
+                continue;
+            }
+            let mut buf = &msg.payload[..];
+            let rid = try!(buf.read_u32::<LittleEndian>().map_err(|_| ControlError::Malformed));
+            if rid != id {
+                continue;
+            }
+            if msg.kind == KIND_ERROR {
+                return Result::Err(ControlError::Remote(String::from_utf8_lossy(buf).into_owned()));
+            }
+            if msg.kind != expect {
+                return Result::Err(ControlError::Malformed);
+            }
+            return Result::Ok(buf.to_vec());
+        }
+    }
+
+    pub fn list_monitors(&mut self) -> Result<Vec<MonitorInfo>, ControlError> {
+        let payload = try!(self.request(KIND_LIST, &[], KIND_MONITORS));
+        let mut buf = &payload[..];
+        let n = try!(buf.read_u16::<LittleEndian>().map_err(|_| ControlError::Malformed));
+        let mut out: Vec<MonitorInfo> = Vec::new();
+        for _ in 0..n {
+            let id = try!(buf.read_u32::<LittleEndian>().map_err(|_| ControlError::Malformed));
+            let freq = try!(buf.read_f64::<LittleEndian>().map_err(|_| ControlError::Malformed));
+            let channel = try!(buf.read_u16::<LittleEndian>().map_err(|_| ControlError::Malformed));
+            let temporary = try!(buf.read_u8().map_err(|_| ControlError::Malformed));
+            out.push(MonitorInfo {
+                id:         id as usize,
+                freq:       freq,
+                channel:    channel as usize,
+                temporary:  temporary != 0,
+            });
+        }
+        Result::Ok(out)
+    }
+
+    ///! Add a monitor on `freq` and return its id.
+    pub fn add_monitor(&mut self, freq: f64) -> Result<usize, ControlError> {
+        let mut body: Vec<u8> = Vec::new();
+        body.write_f64::<LittleEndian>(freq).unwrap();
+        let payload = try!(self.request(KIND_ADD, &body, KIND_ADDED));
+        let id = try!((&payload[..]).read_u32::<LittleEndian>().map_err(|_| ControlError::Malformed));
+        Result::Ok(id as usize)
+    }
+
+    pub fn remove_monitor(&mut self, id: usize) -> Result<(), ControlError> {
+        let mut body: Vec<u8> = Vec::new();
+        body.write_u32::<LittleEndian>(id as u32).unwrap();
+        try!(self.request(KIND_REMOVE, &body, KIND_OK));
+        Result::Ok(())
+    }
+
+    pub fn set_gain(&mut self, gain: f64) -> Result<(), ControlError> {
+        let mut body: Vec<u8> = Vec::new();
+        body.write_f64::<LittleEndian>(gain).unwrap();
+        try!(self.request(KIND_GAIN, &body, KIND_OK));
+        Result::Ok(())
+    }
+
+    ///! Return the channel statistics as JSON.
+    pub fn stats(&mut self) -> Result<String, ControlError> {
+        let payload = try!(self.request(KIND_STATS, &[], KIND_STATS_JSON));
+        Result::Ok(String::from_utf8_lossy(&payload).into_owned())
+    }
+
+    ///! Ask for transmission events, which are then read with `next_event`.
+    pub fn subscribe(&mut self) -> Result<(), ControlError> {
+        try!(self.request(KIND_SUBSCRIBE, &[], KIND_OK));
+        Result::Ok(())
+    }
+
+    ///! Block until the next transmission event arrives.
+    pub fn next_event(&mut self) -> Result<RouterEvent, ControlError> {
+        match self.events.pop_front() {
+            Option::Some(ev) => return Result::Ok(ev),
+            Option::None => (),
+        }
+        loop {
+            let msg = try!(self.conn.recv_msg());
+            if msg.kind == KIND_EVENT {
+                return decode_event(&msg.payload);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_control() {
+    use std::sync::mpsc::channel;
+
+    let (tx, rx) = channel::<RouterCommand>();
+    let server = ControlServer::new("127.0.0.1:0", tx).unwrap();
+
+    // Stands in for the router loop.
+    thread::spawn(move || {
+        let mut monitors: Vec<MonitorInfo> = vec![
+            MonitorInfo { id: 0, freq: 146520000.0, channel: 0, temporary: false },
+        ];
+        for cmd in rx.iter() {
+            match cmd {
+                RouterCommand::ListMonitors { reply } => { let _ = reply.send(monitors.clone()); },
+                RouterCommand::AddMonitor { freq, reply } => {
+                    monitors.push(MonitorInfo { id: 1, freq: freq, channel: 0, temporary: false });
+                    let _ = reply.send(Result::Ok(1));
+                },
+                RouterCommand::RemoveMonitor { reply, .. } => { let _ = reply.send(Result::Err(String::from("no monitor"))); },
+                RouterCommand::SetGain { reply, .. } => { let _ = reply.send(Result::Ok(())); },
+                RouterCommand::Stats { reply } => { let _ = reply.send(Result::Ok(String::from("[]"))); },
+                RouterCommand::Subscribe { events, audio } => {
+                    assert!(!audio);
+                    let _ = events.send(RouterEvent::Started { monitor: 0, time: 10.0 });
+                },
+            }
+        }
+    });
+
+    let mut client = ControlClient::connect(&server.local_addr()).unwrap();
+    assert_eq!(client.add_monitor(146550000.0).unwrap(), 1);
+    let monitors = client.list_monitors().unwrap();
+    assert_eq!(monitors.len(), 2);
+    assert_eq!(monitors[1].freq, 146550000.0);
+    match client.remove_monitor(7) {
+        Result::Err(ControlError::Remote(msg)) => assert_eq!(msg, "no monitor"),
+        _ => panic!("expected an error"),
+    }
+    client.set_gain(20.0).unwrap();
+    assert_eq!(client.stats().unwrap(), "[]");
+    client.subscribe().unwrap();
+    match client.next_event().unwrap() {
+        RouterEvent::Started { monitor, time } => {
+            assert_eq!(monitor, 0);
+            assert_eq!(time, 10.0);
+        },
+        _ => panic!("expected a started event"),
+    }
+    // Only one subscription per connection.
+    match client.subscribe() {
+        Result::Err(ControlError::Remote(msg)) => assert_eq!(msg, "already subscribed"),
+        _ => panic!("expected an error"),
+    }
+}
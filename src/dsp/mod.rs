@@ -11,7 +11,9 @@ use std::collections::VecDeque;
 use std;
 use num;
 
+/// Laid out as an I and Q pair so UHD can write samples straight into it.
 #[derive(Clone)]
+#[repr(C)]
 pub struct Complex<T> {
     pub i:              T,
     pub q:              T,
@@ -96,6 +98,47 @@ pub fn buildsine(freq: f64, sps: f64, amp: f32) -> Option<Vec<Complex<f32>>> {
     Option::Some(out)
 }
 
+/// Compute the fast Fourier transform of `buf` in place. The length of
+/// `buf` must be a power of two.
+pub fn fft(buf: &mut Vec<Complex<f32>>) {
+    let n = buf.len();
+    
+    // Reorder the input into bit reversed order.
+    let mut j = 0usize;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            buf.swap(i, j);
+        }
+    }
+    
+    let mut len = 2usize;
+    while len <= n {
+        let ang = -2.0 * std::f64::consts::PI / len as f64;
+        let wl = Complex { i: ang.cos() as f32, q: ang.sin() as f32 };
+        let half = len / 2;
+        let mut s = 0usize;
+        while s < n {
+            let mut w = Complex { i: 1.0f32, q: 0.0f32 };
+            for k in 0..half {
+                let mut v = buf[s + k + half].clone();
+                v.mul(&w);
+                let u = buf[s + k].clone();
+                buf[s + k] = Complex { i: u.i + v.i, q: u.q + v.q };
+                buf[s + k + half] = Complex { i: u.i - v.i, q: u.q - v.q };
+                w.mul(&wl);
+            }
+            s += len;
+        }
+        len <<= 1;
+    }
+}
+
 pub struct FMDemod {
     sps:        f64,
     offset:     f64,
@@ -341,20 +384,129 @@ pub fn wavei8write(path: String, sps: u32, buf: &Vec<f32>) {
     //}
 }
 
+/// Something that produces samples.
+///
+/// The tuning methods do nothing unless a source overrides them, so that a
+/// recording can stand in for a device.
+pub trait Source {
+    type Error: std::fmt::Display;
+    
+    /// Return the next block of samples, or an empty block once there are
+    /// no more.
+    fn recv_block(&mut self) -> Result<Vec<Complex<f32>>, Self::Error>;
+    
+    /// Give back a block returned by `recv_block` so it can be reused.
+    fn recycle_block(&mut self, _buf: Vec<Complex<f32>>) {
+    }
+    
+    /// Return the sample rate in samples per second.
+    fn rate(&self) -> f64;
+    
+    fn set_freq(&mut self, _freq: f64) -> Result<(), Self::Error> {
+        Result::Ok(())
+    }
+    
+    fn set_rate(&mut self, _rate: f64) -> Result<(), Self::Error> {
+        Result::Ok(())
+    }
+    
+    /// Set a fixed gain in dB, or let the source control it with `None`.
+    fn set_gain(&mut self, _gain: Option<f64>) -> Result<(), Self::Error> {
+        Result::Ok(())
+    }
+    
+    /// Correct the frequency reference by `ppm` parts per million.
+    fn set_ppm(&mut self, _ppm: i32) -> Result<(), Self::Error> {
+        Result::Ok(())
+    }
+    
+    /// Return the gains in dB that `set_gain` accepts.
+    fn gains(&self) -> Vec<f64> {
+        Vec::new()
+    }
+}
+
+/// Writes mono samples to a WAV file as they arrive, in the format used by
+/// `wavei8write`. The sizes in the header are filled in by `finish` or when
+/// the writer is dropped.
+pub struct WavWriter {
+    fp:         File,
+    samples:    u32,
+}
+
+impl WavWriter {
+    pub fn new(path: String, sps: u32) -> std::io::Result<WavWriter> {
+        use std::io::Write;
+        
+        let mut fp = try!(File::create(path));
+        try!(fp.write_all("RIFF".as_bytes()));
+        try!(fp.write_all(&u32tou8ale(36)));
+        try!(fp.write_all("WAVEfmt ".as_bytes()));
+        try!(fp.write_all(&u32tou8ale(16)));
+        try!(fp.write_all(&u16tou8ale(3)));
+        try!(fp.write_all(&u16tou8ale(1)));
+        try!(fp.write_all(&u32tou8ale(sps)));
+        try!(fp.write_all(&u32tou8ale(sps * 4)));
+        try!(fp.write_all(&u16tou8ale(4)));
+        try!(fp.write_all(&u16tou8ale(32)));
+        try!(fp.write_all("data".as_bytes()));
+        try!(fp.write_all(&u32tou8ale(0)));
+        Result::Ok(WavWriter { fp: fp, samples: 0 })
+    }
+    
+    pub fn write(&mut self, buf: &[f32]) -> std::io::Result<()> {
+        use std::io::Write;
+        
+        let mut out: Vec<u8> = Vec::with_capacity(buf.len() * 4);
+        for x in 0..buf.len() {
+            out.write_f32::<LittleEndian>(buf[x]).unwrap();
+        }
+        try!(self.fp.write_all(&out));
+        self.samples += buf.len() as u32;
+        Result::Ok(())
+    }
+    
+    /// Fill in the sizes so the file can be played. Writing may go on.
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        use std::io::{Write, Seek, SeekFrom};
+        
+        let datasize = self.samples * 4;
+        try!(self.fp.seek(SeekFrom::Start(4)));
+        try!(self.fp.write_all(&u32tou8ale(datasize + 36)));
+        try!(self.fp.seek(SeekFrom::Start(40)));
+        try!(self.fp.write_all(&u32tou8ale(datasize)));
+        try!(self.fp.seek(SeekFrom::End(0)));
+        Result::Ok(())
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
 pub struct FileSource {
     fp:         File,    
+    /// The rate the samples were recorded at, as far as anyone has said.
+    rate:       f64,
 }
 
 impl FileSource {
     pub fn new(path: String) -> FileSource {
         FileSource {
             fp:     File::open(path).unwrap(),
+            rate:   0.0,
         }
     }
     
     pub fn recv(&mut self) -> Vec<Complex<f32>> {
+        self.read(1024*1024*20)
+    }
+    
+    fn read(&mut self, max: usize) -> Vec<Complex<f32>> {
         let mut out: Vec<Complex<f32>> = Vec::new();
-        for x in 0..1024*1024*20 {    
+        for _ in 0..max {    
             let i = match self.fp.read_f32::<LittleEndian>() {
                 Result::Ok(v) => v,
                 Result::Err(_) => break,
@@ -369,3 +521,208 @@ impl FileSource {
         out
     }
 }
+
+impl Source for FileSource {
+    type Error = std::io::Error;
+    
+    fn recv_block(&mut self) -> std::io::Result<Vec<Complex<f32>>> {
+        Result::Ok(self.read(16384))
+    }
+    
+    fn rate(&self) -> f64 {
+        self.rate
+    }
+    
+    fn set_rate(&mut self, rate: f64) -> std::io::Result<()> {
+        self.rate = rate;
+        Result::Ok(())
+    }
+}
+
+/// How a buffer handed to a `Sink` relates to a burst.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SendFlags {
+    pub start_of_burst: bool,
+    pub end_of_burst:   bool,
+    /// The time in seconds at which the first sample is to be sent. If not
+    /// set the samples are sent as soon as possible.
+    pub time:           Option<f64>,
+}
+
+impl SendFlags {
+    /// Part of a continuous stream.
+    pub fn continuous() -> SendFlags {
+        SendFlags { start_of_burst: false, end_of_burst: false, time: Option::None }
+    }
+
+    /// A complete burst, optionally sent at `time`.
+    pub fn burst(time: Option<f64>) -> SendFlags {
+        SendFlags { start_of_burst: true, end_of_burst: true, time: time }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SinkEventKind {
+    /// A burst was sent completely.
+    BurstAck,
+    /// The sink ran out of samples between packets.
+    Underflow,
+    /// The sink ran out of samples inside a packet.
+    UnderflowInPacket,
+    /// Packets arrived out of order.
+    SeqError,
+    SeqErrorInBurst,
+    /// A timed burst arrived after its time had passed.
+    TimeError,
+    UserPayload,
+}
+
+/// Something reported by a `Sink` after samples were handed to it.
+#[derive(Clone, Copy, Debug)]
+pub struct SinkEvent {
+    pub kind:       SinkEventKind,
+    pub channel:    usize,
+    pub time:       Option<f64>,
+}
+
+/// Something that takes samples for transmission.
+pub trait Sink {
+    type Error;
+    
+    /// Send the samples in `buf` and return how many were taken.
+    fn send(&mut self, buf: &[Complex<f32>], flags: SendFlags) -> Result<usize, Self::Error>;
+    
+    /// Return the next event reported by the sink waiting at most `timeout`
+    /// seconds for one.
+    fn recv_event(&mut self, timeout: f64) -> Result<Option<SinkEvent>, Self::Error>;
+}
+
+/// A sink that writes samples to a file in the format read by `FileSource`.
+///
+/// The position in the file stands in for the time of a device running at
+/// `rate`, so timed bursts are padded with silence and late bursts are
+/// reported with `SinkEventKind::TimeError`.
+pub struct FileSink {
+    fp:         File,
+    rate:       f64,
+    pos:        u64,
+    events:     VecDeque<SinkEvent>,
+}
+
+impl FileSink {
+    pub fn new(path: String, rate: f64) -> std::io::Result<FileSink> {
+        Result::Ok(FileSink {
+            fp:         try!(File::create(path)),
+            rate:       rate,
+            pos:        0,
+            events:     VecDeque::new(),
+        })
+    }
+    
+    fn write_sample(&mut self, i: f32, q: f32) -> std::io::Result<()> {
+        try!(self.fp.write_f32::<LittleEndian>(i).map_err(byteorder_to_io));
+        try!(self.fp.write_f32::<LittleEndian>(q).map_err(byteorder_to_io));
+        Result::Ok(())
+    }
+}
+
+fn byteorder_to_io(err: ::byteorder::Error) -> std::io::Error {
+    match err {
+        ::byteorder::Error::Io(err) => err,
+        ::byteorder::Error::UnexpectedEOF => std::io::Error::new(std::io::ErrorKind::Other, "unexpected end of file"),
+    }
+}
+
+impl Sink for FileSink {
+    type Error = std::io::Error;
+    
+    fn send(&mut self, buf: &[Complex<f32>], flags: SendFlags) -> std::io::Result<usize> {
+        match flags.time {
+            Option::Some(t) => {
+                let at = (t * self.rate).round() as u64;
+                if at < self.pos {
+                    self.events.push_back(SinkEvent {
+                        kind:       SinkEventKind::TimeError,
+                        channel:    0,
+                        time:       Option::Some(t),
+                    });
+                    return Result::Ok(0);
+                }
+                while self.pos < at {
+                    try!(self.write_sample(0.0, 0.0));
+                    self.pos += 1;
+                }
+            },
+            Option::None => (),
+        }
+        
+        for x in 0..buf.len() {
+            try!(self.write_sample(buf[x].i, buf[x].q));
+        }
+        self.pos += buf.len() as u64;
+        
+        if flags.end_of_burst {
+            self.events.push_back(SinkEvent {
+                kind:       SinkEventKind::BurstAck,
+                channel:    0,
+                time:       Option::Some(self.pos as f64 / self.rate),
+            });
+        }
+        
+        Result::Ok(buf.len())
+    }
+    
+    fn recv_event(&mut self, timeout: f64) -> std::io::Result<Option<SinkEvent>> {
+        Result::Ok(self.events.pop_front())
+    }
+}
+
+#[test]
+fn test_file_sink() {
+    let path = std::env::temp_dir().join("ham-test-file-sink");
+    let path = String::from(path.to_str().unwrap());
+    {
+        let mut sink = FileSink::new(path.clone(), 1000.0).unwrap();
+        let burst = vec![Complex { i: 0.5f32, q: -0.5f32 }; 10];
+        assert_eq!(sink.send(&burst, SendFlags::burst(Option::Some(0.01))).unwrap(), 10);
+        // This one is late since the first burst ended at 0.02 seconds.
+        assert_eq!(sink.send(&burst, SendFlags::burst(Option::Some(0.015))).unwrap(), 0);
+
+        let ev = sink.recv_event(0.0).unwrap().unwrap();
+        assert_eq!(ev.kind, SinkEventKind::BurstAck);
+        assert_eq!(ev.time, Option::Some(0.02));
+        assert_eq!(sink.recv_event(0.0).unwrap().unwrap().kind, SinkEventKind::TimeError);
+        assert!(sink.recv_event(0.0).unwrap().is_none());
+    }
+
+    let out = FileSource::new(path.clone()).recv();
+    assert_eq!(out.len(), 20);
+    assert_eq!(out[9].i, 0.0);
+    assert_eq!(out[10].i, 0.5);
+    assert_eq!(out[19].q, -0.5);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_fft_tone() {
+    let n = 64;
+    // A tone of 5 cycles over the block and one of -9 at a quarter of the
+    // amplitude.
+    let mut buf: Vec<Complex<f32>> = (0..n).map(|x| {
+        let a = 2.0 * std::f64::consts::PI * x as f64 / n as f64;
+        Complex {
+            i: ((5.0 * a).cos() + 0.25 * (-9.0 * a).cos()) as f32,
+            q: ((5.0 * a).sin() + 0.25 * (-9.0 * a).sin()) as f32,
+        }
+    }).collect();
+    fft(&mut buf);
+
+    let mag: Vec<f32> = buf.iter().map(|s| (s.i * s.i + s.q * s.q).sqrt()).collect();
+    assert!((mag[5] - n as f32).abs() < 1e-3);
+    assert!((mag[n - 9] - n as f32 * 0.25).abs() < 1e-3);
+    for b in 0..n {
+        if b != 5 && b != n - 9 {
+            assert!(mag[b] < 1e-3);
+        }
+    }
+}
@@ -0,0 +1,367 @@
+///! Gain control strategies.
+///!
+///! The router hands every block of samples it receives to a `GainControl`
+///! which may then adjust the gain of the `GainDevice` the samples came
+///! from.
+
+use dsp::Complex;
+use std;
+
+///! Something that has an adjustable receive gain.
+pub trait GainDevice {
+    ///! Set the gain in dB. The device clips this to its own range.
+    fn set_rx_gain(&mut self, gain: f64);
+    ///! Return the `(start, stop, step)` of the gain range in dB.
+    fn get_rx_gain_range(&mut self) -> (f64, f64, f64);
+    ///! Enable or disable the automatic gain control of the hardware.
+    fn set_rx_agc(&mut self, enable: bool);
+}
+
+pub trait GainControl {
+    ///! Called once with the device before any samples are seen. Strategies
+    ///! that set the gain themselves disable the hardware AGC here since a
+    ///! strategy may replace one which enabled it.
+    fn init(&mut self, dev: &mut GainDevice);
+    ///! Called with every block of samples received from the device.
+    fn update(&mut self, buf: &Vec<Complex<f32>>, dev: &mut GainDevice);
+}
+
+///! Clamp `gain` into the range reported by the device.
+fn clamp(gain: f64, range: (f64, f64, f64)) -> f64 {
+    if gain < range.0 {
+        range.0
+    } else if gain > range.1 {
+        range.1
+    } else {
+        gain
+    }
+}
+
+///! Sets the gain once and leaves it alone.
+pub struct FixedGain {
+    pub gain:       f64,
+}
+
+impl FixedGain {
+    pub fn new(gain: f64) -> FixedGain {
+        FixedGain { gain: gain }
+    }
+}
+
+impl GainControl for FixedGain {
+    fn init(&mut self, dev: &mut GainDevice) {
+        dev.set_rx_agc(false);
+        let range = dev.get_rx_gain_range();
+        self.gain = clamp(self.gain, range);
+        dev.set_rx_gain(self.gain);
+    }
+
+    fn update(&mut self, buf: &Vec<Complex<f32>>, dev: &mut GainDevice) {
+    }
+}
+
+///! Averages the magnitude over a window of samples and steps the gain
+///! up or down when the average falls outside of the thresholds.
+pub struct SteppedAgc {
+    ///! Below this average magnitude the gain is increased.
+    pub low:        f64,
+    ///! Above this average magnitude the gain is decreased.
+    pub high:       f64,
+    ///! The amount in dB the gain is changed by each step.
+    pub step:       f64,
+    ///! The number of samples averaged before each decision.
+    pub window:     usize,
+    pub min:        f64,
+    pub max:        f64,
+    cur:            f64,
+    avgpwr:         f64,
+    avgcnt:         usize,
+}
+
+impl SteppedAgc {
+    ///! Create with the thresholds the router has always used.
+    pub fn new(gain: f64) -> SteppedAgc {
+        SteppedAgc {
+            low:        0.03,
+            high:       0.20,
+            step:       1.0,
+            window:     500000,
+            min:        1.0,
+            max:        50.0,
+            cur:        gain,
+            avgpwr:     0.0,
+            avgcnt:     0,
+        }
+    }
+}
+
+impl GainControl for SteppedAgc {
+    fn init(&mut self, dev: &mut GainDevice) {
+        dev.set_rx_agc(false);
+        let range = dev.get_rx_gain_range();
+        self.min = clamp(self.min, range);
+        self.max = clamp(self.max, range);
+        self.cur = clamp(self.cur, (self.min, self.max, 0.0));
+        dev.set_rx_gain(self.cur);
+    }
+
+    fn update(&mut self, buf: &Vec<Complex<f32>>, dev: &mut GainDevice) {
+        for x in 0..buf.len() {
+            let spwr = (buf[x].i * buf[x].i + buf[x].q * buf[x].q).sqrt();
+            self.avgpwr += spwr as f64;
+            self.avgcnt += 1;
+        }
+
+        if self.avgcnt > self.window {
+            let avgpwr = self.avgpwr / self.avgcnt as f64;
+            if avgpwr > self.high {
+                self.cur -= self.step;
+                if self.cur < self.min {
+                    self.cur = self.min;
+                }
+                dev.set_rx_gain(self.cur);
+                println!("gain decreased to {} with avg pwr {}", self.cur, avgpwr);
+            }
+            if avgpwr < self.low {
+                self.cur += self.step;
+                if self.cur > self.max {
+                    self.cur = self.max;
+                }
+                dev.set_rx_gain(self.cur);
+                println!("gain increased to {} with avg pwr {}", self.cur, avgpwr);
+            }
+            self.avgcnt = 0;
+            self.avgpwr = 0.0;
+        }
+    }
+}
+
+///! Keeps the gain as high as possible while avoiding saturation of the
+///! ADC. Samples with either component at or beyond `clip` are counted as
+///! saturated and the gain is lowered if too many are seen in a window. The
+///! gain is raised again if the peak stays below `headroom`.
+pub struct ClipAvoidance {
+    ///! The level at which a component is considered saturated.
+    pub clip:       f32,
+    ///! The number of saturated samples per window that is tolerated.
+    pub maxclips:   usize,
+    ///! If the peak over a window stays below this the gain is raised.
+    pub headroom:   f32,
+    pub step:       f64,
+    pub window:     usize,
+    cur:            f64,
+    range:          (f64, f64, f64),
+    clips:          usize,
+    peak:           f32,
+    cnt:            usize,
+}
+
+impl ClipAvoidance {
+    pub fn new(gain: f64) -> ClipAvoidance {
+        ClipAvoidance {
+            clip:       0.99,
+            maxclips:   10,
+            headroom:   0.5,
+            step:       1.0,
+            window:     500000,
+            cur:        gain,
+            range:      (0.0, 0.0, 0.0),
+            clips:      0,
+            peak:       0.0,
+            cnt:        0,
+        }
+    }
+}
+
+impl GainControl for ClipAvoidance {
+    fn init(&mut self, dev: &mut GainDevice) {
+        dev.set_rx_agc(false);
+        self.range = dev.get_rx_gain_range();
+        self.cur = clamp(self.cur, self.range);
+        dev.set_rx_gain(self.cur);
+    }
+
+    fn update(&mut self, buf: &Vec<Complex<f32>>, dev: &mut GainDevice) {
+        for x in 0..buf.len() {
+            let i = buf[x].i.abs();
+            let q = buf[x].q.abs();
+            let m = if i > q { i } else { q };
+            if m >= self.clip {
+                self.clips += 1;
+            }
+            if m > self.peak {
+                self.peak = m;
+            }
+        }
+
+        self.cnt += buf.len();
+
+        // Saturation is acted on right away since every block that clips
+        // is a block of corrupted samples.
+        if self.clips > self.maxclips {
+            self.cur = clamp(self.cur - self.step, self.range);
+            dev.set_rx_gain(self.cur);
+            println!("gain decreased to {} after {} saturated samples", self.cur, self.clips);
+            self.clips = 0;
+            self.peak = 0.0;
+            self.cnt = 0;
+            return;
+        }
+
+        if self.cnt > self.window {
+            if self.peak < self.headroom && self.cur < self.range.1 {
+                self.cur = clamp(self.cur + self.step, self.range);
+                dev.set_rx_gain(self.cur);
+                println!("gain increased to {} with peak {}", self.cur, self.peak);
+            }
+            self.clips = 0;
+            self.peak = 0.0;
+            self.cnt = 0;
+        }
+    }
+}
+
+///! Leaves the gain to the automatic gain control of the hardware.
+pub struct HardwareAgc;
+
+impl GainControl for HardwareAgc {
+    fn init(&mut self, dev: &mut GainDevice) {
+        dev.set_rx_agc(true);
+    }
+
+    fn update(&mut self, buf: &Vec<Complex<f32>>, dev: &mut GainDevice) {
+    }
+}
+
+#[cfg(test)]
+struct TestDevice {
+    gain:       f64,
+    range:      (f64, f64, f64),
+    sets:       usize,
+    agc:        bool,
+}
+
+#[cfg(test)]
+impl GainDevice for TestDevice {
+    fn set_rx_gain(&mut self, gain: f64) {
+        self.gain = clamp(gain, self.range);
+        self.sets += 1;
+    }
+
+    fn get_rx_gain_range(&mut self) -> (f64, f64, f64) {
+        self.range
+    }
+
+    fn set_rx_agc(&mut self, enable: bool) {
+        self.agc = enable;
+    }
+}
+
+#[cfg(test)]
+fn tone(level: f32, len: usize) -> Vec<Complex<f32>> {
+    (0..len).map(|x| {
+        let p = x as f32 * 0.1;
+        Complex { i: level * p.cos(), q: level * p.sin() }
+    }).collect()
+}
+
+#[test]
+fn test_stepped_agc() {
+    let mut dev = TestDevice { gain: 0.0, range: (0.0, 30.0, 0.5), sets: 0, agc: false };
+    HardwareAgc.init(&mut dev);
+    assert!(dev.agc);
+    let mut agc = SteppedAgc::new(40.0);
+    agc.window = 1000;
+    agc.init(&mut dev);
+    // Taking over from the hardware.
+    assert!(!dev.agc);
+    // Clamped to the device range before it is first set.
+    assert_eq!(dev.gain, 30.0);
+    assert_eq!(agc.max, 30.0);
+
+    // Nothing changes until a window has been seen.
+    let strong = tone(0.5, 600);
+    agc.update(&strong, &mut dev);
+    assert_eq!(dev.sets, 1);
+    agc.update(&strong, &mut dev);
+    assert_eq!(dev.gain, 29.0);
+
+    // Between the thresholds the gain stays.
+    let fine = tone(0.1, 1001);
+    agc.update(&fine, &mut dev);
+    assert_eq!(dev.gain, 29.0);
+
+    // Weak signals step it up but not beyond the range.
+    let weak = tone(0.01, 1001);
+    for _ in 0..5 {
+        agc.update(&weak, &mut dev);
+    }
+    assert_eq!(dev.gain, 30.0);
+
+    // And strong ones step it down no further than the minimum.
+    agc.min = 27.0;
+    for _ in 0..5 {
+        agc.update(&tone(0.5, 1001), &mut dev);
+    }
+    assert_eq!(dev.gain, 27.0);
+}
+
+#[test]
+fn test_clip_avoidance() {
+    let mut dev = TestDevice { gain: 0.0, range: (0.0, 20.0, 1.0), sets: 0, agc: true };
+    let mut agc = ClipAvoidance::new(19.0);
+    agc.window = 1000;
+    agc.init(&mut dev);
+    assert!(!dev.agc);
+    assert_eq!(dev.gain, 19.0);
+
+    // Saturation lowers the gain at once, without waiting for the window.
+    let mut block = tone(0.6, 100);
+    for x in 0..11 {
+        block[x * 5] = Complex { i: 1.0, q: 0.0 };
+    }
+    agc.update(&block, &mut dev);
+    assert_eq!(dev.gain, 18.0);
+
+    // A few saturated samples are tolerated.
+    for x in 0..11 {
+        block[x * 5] = Complex { i: 0.6, q: 0.0 };
+    }
+    block[0] = Complex { i: 0.0, q: -1.0 };
+    agc.update(&block, &mut dev);
+    assert_eq!(dev.gain, 18.0);
+
+    // A peak above the headroom holds it where it is.
+    agc.update(&tone(0.7, 1001), &mut dev);
+    assert_eq!(dev.gain, 18.0);
+
+    // With room to spare the gain rises once a window is seen, up to the
+    // top of the range.
+    let quiet = tone(0.1, 1001);
+    for _ in 0..5 {
+        agc.update(&quiet, &mut dev);
+    }
+    assert_eq!(dev.gain, 20.0);
+    let sets = dev.sets;
+    agc.update(&quiet, &mut dev);
+    assert_eq!(dev.sets, sets);
+
+
+    // Saturating blocks keep lowering it down to the bottom of the range.
+    let loud = tone(1.5, 100);
+    for _ in 0..25 {
+        agc.update(&loud, &mut dev);
+    }
+    assert_eq!(dev.gain, 0.0);
+}
+
+#[test]
+fn test_fixed_gain() {
+    let mut dev = TestDevice { gain: 0.0, range: (0.0, 30.0, 0.5), sets: 0, agc: true };
+    let mut fixed = FixedGain::new(45.0);
+    fixed.init(&mut dev);
+    assert!(!dev.agc);
+    assert_eq!(dev.gain, 30.0);
+    fixed.update(&tone(1.5, 1000), &mut dev);
+    assert_eq!(dev.sets, 1);
+}
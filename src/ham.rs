@@ -3,8 +3,6 @@
 #![feature(path_ext)]
 #![feature(convert)]
 #![feature(deque_extras)]
-#![feature(heap_api)]
-#![feature(alloc)]
 #![allow(non_camel_case_types)]
 extern crate lodepng;
 extern crate byteorder;
@@ -12,7 +10,6 @@ extern crate alsa;
 extern crate num;
 extern crate time;
 extern crate libc;
-extern crate alloc;
 
 use std::cmp::Ordering;
 use std::io::{SeekFrom, Seek, Cursor, Read};
@@ -23,20 +20,33 @@ use std::mem;
 use std::str::FromStr;
 use std::thread;
 use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Sender, Receiver};
+use std::collections::VecDeque;
 
 pub mod usrp;
 pub mod algos;
 pub mod dsp;
+pub mod gain;
+pub mod stats;
+pub mod control;
+pub mod rtltcp;
+pub mod audio;
 
 pub use algos::SignalMap;
 pub use algos::mcguire_smde;
+pub use algos::discovery::{Discovery, Candidate};
 
 pub use dsp::Complex;
 pub use dsp::FMDemod;
 pub use dsp::wavei8write;
-pub use dsp::FileSource;
+pub use dsp::{FileSource, Source};
 
-pub use usrp::USRPSource;
+pub use usrp::{USRPSource, USRPSink};
+pub use usrp::UhdError;
+use usrp::RxError;
+
+pub use gain::GainControl;
+pub use stats::Stats;
 
 pub struct Transmission {
     pub freq:       f64,
@@ -47,13 +57,325 @@ pub struct MonitorSpec {
     pub freq:       f64,
 }
 
+/// Describes a transmission once it has ended.
+#[derive(Clone)]
+pub struct TransmissionSummary {
+    pub freq:       f64,
+    /// The host time in seconds at which the transmission started.
+    pub start:      f64,
+    /// The length of the audio in seconds.
+    pub duration:   f64,
+    /// True if the transmission was long enough to be placed into the
+    /// output buffer.
+    pub published:  bool,
+    /// True if the transmission was cut at the configured maximum length
+    /// and will continue as a new transmission.
+    pub split:      bool,
+}
+
+/// Events emitted by the router while transmissions are in progress. The
+/// `monitor` is the index of the monitor in the targets given to the router,
+/// or for discovered monitors an id following those of the targets.
+#[derive(Clone)]
+pub enum RouterEvent {
+    Started { monitor: usize, time: f64 },
+    Audio { monitor: usize, chunk: Vec<f32> },
+    Ended { monitor: usize, summary: TransmissionSummary },
+}
+
+/// Describes a monitor of a running router.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MonitorInfo {
+    pub id:         usize,
+    pub freq:       f64,
+    /// The receive channel the monitor is fed from.
+    pub channel:    usize,
+    /// True for monitors created by discovery.
+    pub temporary:  bool,
+}
+
+/// Requests handled by a running router between blocks of samples. The
+/// answer, if any, is sent on `reply`.
+pub enum RouterCommand {
+    ListMonitors { reply: Sender<Vec<MonitorInfo>> },
+    /// Add a monitor and answer with its id.
+    AddMonitor { freq: f64, reply: Sender<Result<usize, String>> },
+    RemoveMonitor { id: usize, reply: Sender<Result<(), String>> },
+    /// Fix the receive gain, replacing the configured gain control.
+    SetGain { gain: f64, reply: Sender<Result<(), String>> },
+    /// Answer with the statistics as JSON if they are being kept.
+    Stats { reply: Sender<Result<String, String>> },
+    /// Send every event from now on to `events` as well, leaving out
+    /// `RouterEvent::Audio` unless `audio` is set.
+    Subscribe { events: Sender<RouterEvent>, audio: bool },
+}
+
+/// Optional settings for the router.
+pub struct RouterConfig {
+    /// If set then live events are sent here as transmissions happen.
+    pub events:             Option<Sender<RouterEvent>>,
+    /// If set then transmissions longer than this many seconds are split.
+    pub max_transmission:   Option<f64>,
+    /// Seconds of audio kept from before activity starts and prepended to
+    /// the transmission so the first syllables are not lost.
+    pub preroll:            f64,
+    /// Seconds of inactivity tolerated before a transmission is closed.
+    pub hang:               f64,
+    /// Transmissions shorter than this many seconds are discarded.
+    pub min_duration:       f64,
+    /// Decides the receive gain of the device. With several channels the
+    /// gain follows the first channel and is applied to all of them.
+    pub gain:               Box<GainControl + Send>,
+    /// If set then the captured band is searched for unknown signals.
+    pub discovery:          Option<DiscoverySpec>,
+    /// If set then every monitor and its transmissions are recorded here.
+    pub stats:              Option<Arc<Mutex<Stats>>>,
+    /// The number of receive channels of the device to use. The targets
+    /// are split into this many bands each covered by its own channel.
+    pub channels:           usize,
+    /// If set then applied as the receive subdevice specification, which
+    /// some devices need to receive on more than one channel.
+    pub subdev:             Option<String>,
+    /// If set then the sensors of the device are checked this often in
+    /// seconds, but no more than once a second, and channels whose LO lost
+    /// lock are re-tuned. The checks are made between receives.
+    pub health:             Option<f64>,
+    /// If set then the reference clock and device time are configured with
+    /// this at startup so samples carry absolute timestamps.
+    pub time:               Option<usrp::TimeConfig>,
+    /// Selects the device to open.
+    pub device:             usrp::DeviceArgs,
+    /// The front end settings of each channel in order. Channels without
+    /// an entry keep the settings the device starts with.
+    pub frontend:           Vec<usrp::FrontEnd>,
+    /// If set then commands are taken from here while running.
+    pub control:            Option<Receiver<RouterCommand>>,
+    /// Listeners added with `RouterCommand::Subscribe` and whether they
+    /// take audio.
+    subscribers:            Mutex<Vec<(Sender<RouterEvent>, bool)>>,
+}
+
+impl RouterConfig {
+    pub fn new() -> RouterConfig {
+        RouterConfig {
+            events:             Option::None,
+            max_transmission:   Option::None,
+            preroll:            0.0,
+            hang:               0.0,
+            min_duration:       3.0,
+            gain:               Box::new(gain::SteppedAgc::new(1.0)),
+            discovery:          Option::None,
+            stats:              Option::None,
+            channels:           1,
+            subdev:             Option::None,
+            health:             Option::None,
+            time:               Option::None,
+            device:             usrp::DeviceArgs::new(),
+            frontend:           Vec::new(),
+            control:            Option::None,
+            subscribers:        Mutex::new(Vec::new()),
+        }
+    }
+
+    fn emit(&self, ev: RouterEvent) {
+        match self.events {
+            // A listener that went away should not stop the router.
+            Option::Some(ref tx) => { let _ = tx.send(ev.clone()); },
+            Option::None => (),
+        }
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.len() > 0 {
+            let audio = match ev {
+                RouterEvent::Audio { .. } => true,
+                _ => false,
+            };
+            subscribers.retain(|&(ref tx, wants)| (audio && !wants) || tx.send(ev.clone()).is_ok());
+        }
+    }
+}
+
+/// Settings for finding unknown active frequencies in the captured band.
+pub struct DiscoverySpec {
+    /// If set then the candidates found are sent here.
+    pub report:         Option<Sender<Vec<Candidate>>>,
+    /// If set then a temporary monitor is created for each candidate not
+    /// already monitored. It is removed once idle for this many seconds.
+    pub spawn_idle:     Option<f64>,
+    /// Bins scoring at or above this from 0.0 to 1.0 are considered signal.
+    pub threshold:      f64,
+}
+
+/// Return the host time in seconds.
+fn now() -> f64 {
+    let t = time::get_time();
+    t.sec as f64 + t.nsec as f64 / 1000.0 / 1000.0 / 1000.0
+}
+
 /// Internally used monitor structure.
 struct Monitor {
+    id:         usize,
+    /// The index of the receive channel the monitor is fed from.
+    chan:       usize,
     freq:       f64,
     offset:     f64,
     demod:      FMDemod,
     buf:        Vec<f32>,
     dead:       isize,
+    start:      f64,
+    pre:        VecDeque<f32>,
+    hang:       usize,
+    /// Set for discovered monitors to the seconds of idle before removal.
+    temporary:  Option<f64>,
+    /// The host time at which the monitor was last active.
+    active:     f64,
+}
+
+impl Monitor {
+    fn new(id: usize, chan: usize, freq: f64, center: f64, sps: f64, decim: usize, taps: Vec<f32>) -> Monitor {
+        Monitor {
+            id:         id,
+            chan:       chan,
+            freq:       freq,
+            offset:     center - freq,
+            demod:      FMDemod::new(sps, decim, center - freq, 15000.0, taps, 3),
+            buf:        Vec::new(),
+            dead:       1,
+            start:      0.0,
+            pre:        VecDeque::new(),
+            hang:       0,
+            temporary:  Option::None,
+            active:     now(),
+        }
+    }
+
+    /// Place the current buffer into the output if it is long enough and
+    /// report the end of the transmission.
+    fn finish(&mut self, rtrans: &Arc<Mutex<Vec<Transmission>>>, cfg: &RouterConfig, split: bool) {
+        let duration = self.buf.len() as f64 / 16000.0;
+        let published = duration >= cfg.min_duration;
+        
+        self.active = now();
+
+        if published {
+            // Place the transmission into the output buffer. The locking
+            // provides the synchronization to support an external thread
+            // accessing the buffer concurrently.
+            println!("[ham-router] placed transmission of {} seconds into output buffer", duration);
+            let mut tout = rtrans.lock().unwrap();
+
+            // Since we have ownership rules and rules that keep something from
+            // being uninitialized we must create a fresh buffer and swap it out
+            // with the buffer that we wish to put into the queue.
+            let mut tmpbuf: Vec<f32> = Vec::new();
+            std::mem::swap(&mut tmpbuf, &mut self.buf);
+
+            tout.push(Transmission {
+                freq:       self.freq,
+                buf:        tmpbuf,
+            });
+            
+        } else {
+            self.buf.clear();
+        }
+
+        match cfg.stats {
+            Option::Some(ref stats) => {
+                stats.lock().unwrap().record(self.id, self.freq, self.start, duration, published);
+            },
+            Option::None => (),
+        }
+
+        cfg.emit(RouterEvent::Ended {
+            monitor:    self.id,
+            summary:    TransmissionSummary {
+                freq:       self.freq,
+                start:      self.start,
+                duration:   duration,
+                published:  published,
+                split:      split,
+            },
+        });
+    }
+
+    /// Track activity on the demodulated output and collect transmissions.
+    fn feed(&mut self, out: &Vec<f32>, rtrans: &Arc<Mutex<Vec<Transmission>>>, cfg: &RouterConfig) {
+        let mut chunk: Vec<f32> = Vec::new();
+
+        let maxlen = match cfg.max_transmission {
+            Option::Some(secs) => (secs * 16000.0) as usize,
+            Option::None => 0,
+        };
+        let prelen = (cfg.preroll * 16000.0) as usize;
+        let hanglen = (cfg.hang * 16000.0) as usize;
+
+        for x in 0..out.len() {
+            if out[x].abs() > 0.0 {
+                self.dead -= 1;
+            } else {
+                self.dead += 1;
+            }
+
+            if self.dead < -8000 {
+                self.dead = -8000;
+            }
+
+            if self.dead > 30 {
+                self.dead = 30;
+            }
+
+            // If we have activity then start pushing the audio output
+            // samples into our monitor buffer. Once we see no activity
+            // then evaluate if it contains enough to be considered a
+            // transmission and if so then place it into the output queue
+            // and prepare for the next transmission.
+            if self.dead < 0 {
+                if self.buf.len() == 0 {
+                    self.start = now() - self.pre.len() as f64 / 16000.0;
+                    cfg.emit(RouterEvent::Started { monitor: self.id, time: self.start });
+                    // Prepend the audio leading up to the activity.
+                    while let Option::Some(s) = self.pre.pop_front() {
+                        self.buf.push(s);
+                        chunk.push(s);
+                    }
+                }
+                self.hang = 0;
+                self.buf.push(out[x]);
+                chunk.push(out[x]);
+
+                if maxlen > 0 && self.buf.len() >= maxlen {
+                    // Hand out what we have so far and keep going as a new
+                    // transmission on the next sample.
+                    if chunk.len() > 0 {
+                        cfg.emit(RouterEvent::Audio { monitor: self.id, chunk: chunk });
+                        chunk = Vec::new();
+                    }
+                    self.finish(rtrans, cfg, true);
+                }
+            } else if self.buf.len() > 0 && self.hang < hanglen {
+                // Keep the transmission open for a little while in case the
+                // activity resumes.
+                self.hang += 1;
+                self.buf.push(out[x]);
+                chunk.push(out[x]);
+            } else if self.buf.len() > 0 {
+                self.hang = 0;
+                if chunk.len() > 0 {
+                    cfg.emit(RouterEvent::Audio { monitor: self.id, chunk: chunk });
+                    chunk = Vec::new();
+                }
+                self.finish(rtrans, cfg, false);
+            } else if prelen > 0 {
+                self.pre.push_back(out[x]);
+                while self.pre.len() > prelen {
+                    self.pre.pop_front();
+                }
+            }
+        }
+
+        if chunk.len() > 0 {
+            cfg.emit(RouterEvent::Audio { monitor: self.id, chunk: chunk });
+        }
+    }
 }
 
 
@@ -107,208 +429,8 @@ fn test_server() {
 
 pub mod muds {
     pub mod block {
-        pub mod net {
-            use std::sync::{Arc, Mutex, Condvar};
-            use std::net::{TcpListener, TcpStream};
-            use std::thread;      
-            use std::collections::{VecDeque, HashMap};
-            use std::sync::mpsc::{Sender, Receiver, channel, RecvError};
-            use std;
-            
-            use std::io::{Read, Write};
-            
-            pub struct Client {
-                stream:      TcpStream,
-                luid:        u64,
-                buffer:      VecDeque<Vec<u8>>,
-                ctrltx:      Sender<ControlInfo>,
-                waitsz:      usize,
-                waitlimit:   usize,
-                fullsignal:  Arc<(Mutex<bool>, Condvar)>,
-            }
-            
-            impl Client {
-                pub fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-                    self.stream.write(buf)
-                }
-                pub fn read(&mut self) -> Option<Vec<u8>> {
-                    let mut mustnotify = false;
-                    if self.buffer.len() >= self.waitlimit {
-                        mustnotify = true;
-                    }
-                    match self.buffer.pop_front() {
-                        Option::Some(v) => {
-                            self.waitsz -= v.len();
-                            if mustnotify {
-                                // Obviously, the wait is happening now or is 
-                                // about to happen.
-                                {
-                                    let mut started = self.fullsignal.0.lock().unwrap();
-                                    *started = true;
-                                }
-                                self.fullsignal.1.notify_one();                            
-                            }
-                            Option::Some(v)
-                        },
-                        Option::None => Option::None,
-                    }
-                }
-                pub fn get_luid(&self) -> u64 {
-                    self.luid
-                }
-                pub fn can_read(&self) -> bool {
-                    self.buffer.len() > 0
-                }
-            }
-            
-            pub enum ControlInfo {
-                ClientHello { luid: u64, client: Arc<Mutex<Client>> },
-                ClientBye { luid: u64, client: Arc<Mutex<Client>> },
-                ClientData { luid: u64, client: Arc<Mutex<Client>> },
-                ClientFull { luid: u64, client: Arc<Mutex<Client>> },
-            }
-        
-            pub struct Server {
-                strms:      Arc<Mutex<HashMap<u64, Arc<Mutex<Client>>>>>,
-                ctrlrx:     Receiver<ControlInfo>,
-            }
-            
-            impl Server {
-                pub fn write(&self, luid: u64, buf: &[u8]) -> std::io::Result<usize> {
-                    let strms = self.strms.lock().unwrap();
-                    let mut lck = strms.get(&luid).unwrap().lock().unwrap();
-                    lck.write(buf)
-                }
-                
-                pub fn read(&self) -> Result<ControlInfo, RecvError> {
-                    self.ctrlrx.recv()
-                }        
-            
-                pub fn new(addr: &str) -> Option<Server>  {                    
-                    let srv = TcpListener::bind(addr);
-                    
-                    // TODO: add method to shutdown this server.. maybe using
-                    //       a client whom connections with a special message
-                    match srv {
-                        Result::Ok(srv) => {
-                            let strms: Arc<Mutex<HashMap<u64, Arc<Mutex<Client>>>>> = Arc::new(Mutex::new(HashMap::new()));
-                            let strms_clone = strms.clone();
-                            let (ctrltx, ctrlrx) = channel::<ControlInfo>();                        
-                            thread::spawn(move || {
-                                let mut luid: u64 = 100;
-                                for stream in srv.incoming() {
-                                    match stream {
-                                        Result::Ok(stream) => {
-                                            luid += 1;
-                                            stream.set_read_timeout(Option::None);
-                                            stream.set_write_timeout(Option::None);
-                                            let mut stream_clone = stream.try_clone().unwrap();
-                                            let client = Arc::new(Mutex::new(Client {
-                                                stream:     stream,
-                                                luid:       luid,
-                                                buffer:     VecDeque::new(),
-                                                ctrltx:     ctrltx.clone(),   
-                                                waitsz:    0,
-                                                waitlimit:  1024 * 1024 * 16,
-                                                fullsignal: Arc::new((Mutex::new(false), Condvar::new())),                                                                                          
-                                            }));
-                                            let ctrltx_clone = ctrltx.clone();
-                                            let client_clone = client.clone();
-                                            let mut lck = strms.lock().unwrap();
-                                            lck.insert(luid, client);
-                                            ctrltx.send(ControlInfo::ClientHello { luid: luid, client: client_clone.clone() });
-                                            thread::spawn(move || {
-                                                let bufsz = 2048;
-                                                let mut buf: Vec<u8>;
-                                                loop {
-                                                    buf = Vec::with_capacity(bufsz);
-                                                    unsafe {
-                                                        buf.set_len(bufsz);
-                                                    }                                                
-                                                    let rsz = match stream_clone.read(buf.as_mut_slice()) {
-                                                        Result::Ok(v) => v,
-                                                        Result::Err(_) => {
-                                                            // Terminate this reader thread.
-                                                            ctrltx_clone.send(ControlInfo::ClientBye { luid: luid, client: client_clone.clone() });
-                                                            return;
-                                                        },
-                                                    };
-                                                    
-                                                    println!("DATA!!!");                          
-                                                    
-                                                    if rsz < 1 {
-                                                        continue;
-                                                    }
-                                                    unsafe {
-                                                        buf.set_len(rsz);
-                                                    }
-                                                    
-                                                    let mut fullsignal: Option<Arc<(Mutex<bool>, Condvar)>> = Option::None;
-                                                    {
-                                                        // Only lock long enough to put data into
-                                                        // the buffer.
-                                                        let mut lck = client_clone.lock().unwrap();
-                                                        if lck.waitsz < lck.waitlimit {
-                                                            lck.waitsz += buf.len();
-                                                            lck.buffer.push_back(buf);
-                                                            if lck.buffer.len() == 1 {
-                                                                lck.ctrltx.send(ControlInfo::ClientData { luid: luid, client: client_clone.clone() });
-                                                            }
-                                                            if lck.waitsz >= lck.waitlimit {
-                                                                // Get setup to stop reading from the socket.
-                                                                lck.ctrltx.send(ControlInfo::ClientFull { luid: luid, client: client_clone.clone() });
-                                                                fullsignal = Option::Some(lck.fullsignal.clone());    
-                                                            }
-                                                        } 
-                                                    }
-                                                    
-                                                    // The point here is to stop reading from the socket which will
-                                                    // cause the TCP stream receive window to decrease to zero if needed
-                                                    // until we can resume reading from the socket. This is needed to
-                                                    // prevent the remote end from exhausting our memory. We can not simply
-                                                    // spin here and eat CPU either so we need to block for a signal. If by
-                                                    // the time we wake the socket is dead then we shall know gracefully and
-                                                    // exit gracefully up above this point in the loop.
-                                                    match fullsignal {
-                                                        Option::Some(fullsignal) => {
-                                                            let mut started = fullsignal.0.lock().unwrap();
-                                                            while !*started {
-                                                                started = fullsignal.1.wait(started).unwrap();
-                                                            }
-                                                        },
-                                                        Option::None => (),
-                                                    }
-                                                }
-                                            });
-                                        },
-                                        Result::Err(err) => (),
-                                    }
-                                }
-                            });
-                            Option::Some(Server {
-                                strms:      strms_clone,
-                                ctrlrx:     ctrlrx,
-                            })
-                        },
-                        Result::Err(err) => Option::None,                   
-                    }
-                } 
-            }
-        }
-        mod directory {
-            pub fn net() {
-                
-            }
-            
-            pub fn sys() {
-            }
-            
-            pub fn link_to(sysid: Vec<u8>, compid: Vec<u8>, portid: Vec<u8>) {
-            }
-            
-            pub fn link_from(compid: Vec<u8>, portid: Vec<u8>) {
-            }
-        }
+        pub mod net;
+        pub mod directory;
         
         pub fn usrp() {            
         }
@@ -316,36 +438,165 @@ pub mod muds {
 }
 
 
-pub fn router(rtrans: Arc<Mutex<Vec<Transmission>>>, targets: Vec<MonitorSpec>) {        
+pub fn router(rtrans: Arc<Mutex<Vec<Transmission>>>, targets: Vec<MonitorSpec>) {
+    router_with_config(rtrans, targets, RouterConfig::new());
+}
+
+/// Compare with NaN after every other value, so sorting cannot panic.
+fn cmp_f64(a: f64, b: f64) -> Ordering {
+    match a.partial_cmp(&b) {
+        Option::Some(ord) => ord,
+        Option::None => a.is_nan().cmp(&b.is_nan()),
+    }
+}
+
+/// Split the targets into `n` bands of neighbouring frequencies by cutting
+/// at the widest gaps between them. Each band is a list of indices into
+/// `targets` and there are never more bands than targets.
+fn split_bands(targets: &Vec<MonitorSpec>, n: usize) -> Vec<Vec<usize>> {
+    let mut order: Vec<usize> = (0..targets.len()).collect();
+    order.sort_by(|a, b| cmp_f64(targets[*a].freq, targets[*b].freq));
+    
+    let n = if n > order.len() { order.len() } else { n };
+    if n < 2 {
+        return vec![order];
+    }
+    
+    // The positions in `order` after which a new band starts.
+    let mut gaps: Vec<usize> = (0..order.len() - 1).collect();
+    gaps.sort_by(|a, b| {
+        let ga = targets[order[*a + 1]].freq - targets[order[*a]].freq;
+        let gb = targets[order[*b + 1]].freq - targets[order[*b]].freq;
+        cmp_f64(gb, ga)
+    });
+    let mut cuts: Vec<usize> = gaps[0..n - 1].to_vec();
+    cuts.sort();
+    
+    let mut bands: Vec<Vec<usize>> = Vec::new();
+    let mut from = 0;
+    for cut in cuts.iter() {
+        bands.push(order[from..*cut + 1].to_vec());
+        from = *cut + 1;
+    }
+    bands.push(order[from..].to_vec());
+    bands
+}
+
+#[test]
+fn test_split_bands() {
+    let targets: Vec<MonitorSpec> = vec![146.52, 146.55, 440.0, 145.0, 441.0, 29.6]
+        .into_iter().map(|f| MonitorSpec { freq: f * 1e6 }).collect();
+    
+    // Cut at the widest gaps, 29.6 to 145.0 and 146.55 to 440.0.
+    assert_eq!(split_bands(&targets, 3), vec![vec![5], vec![3, 0, 1], vec![2, 4]]);
+    assert_eq!(split_bands(&targets, 2), vec![vec![5, 3, 0, 1], vec![2, 4]]);
+    // Never more bands than targets.
+    assert_eq!(split_bands(&targets, 10).len(), 6);
+    assert!(split_bands(&targets, 10).iter().all(|b| b.len() == 1));
+    // One band for fewer than two.
+    assert_eq!(split_bands(&targets, 1), vec![vec![5, 3, 0, 1, 2, 4]]);
+    assert_eq!(split_bands(&targets, 0), vec![vec![5, 3, 0, 1, 2, 4]]);
+    
+    // A NaN sorts last rather than panicking.
+    let odd = vec![MonitorSpec { freq: std::f64::NAN }, MonitorSpec { freq: 1.0 }];
+    assert_eq!(split_bands(&odd, 1), vec![vec![1, 0]]);
+}
+
+/// Return the channel whose center is nearest to `freq`, as long as the
+/// offset is well inside its captured band of `sps` so the oscillator of
+/// the demodulator can be built for it.
+fn nearest_channel(centers: &Vec<f64>, sps: f64, freq: f64) -> Result<usize, String> {
+    if !freq.is_finite() {
+        return Result::Err(format!("{} is not a frequency", freq));
+    }
+    if centers.len() == 0 || !(sps > 0.0) {
+        return Result::Err(String::from("nothing is captured"));
+    }
+    let mut best = 0;
+    for chan in 1..centers.len() {
+        if (centers[chan] - freq).abs() < (centers[best] - freq).abs() {
+            best = chan;
+        }
+    }
+    // Written so a NaN offset is refused too.
+    if !((centers[best] - freq).abs() <= sps / 4.0 - 15000.0) {
+        return Result::Err(format!("{} is outside of the captured band", freq));
+    }
+    Result::Ok(best)
+}
+
+#[test]
+fn test_nearest_channel() {
+    let centers = vec![146e6, 440e6];
+    assert_eq!(nearest_channel(&centers, 2e6, 146.4e6), Result::Ok(0));
+    assert_eq!(nearest_channel(&centers, 2e6, 439.7e6), Result::Ok(1));
+    assert!(nearest_channel(&centers, 2e6, 147e6).is_err());
+    assert!(nearest_channel(&centers, 2e6, std::f64::NAN).is_err());
+    assert!(nearest_channel(&centers, 2e6, std::f64::INFINITY).is_err());
+    assert!(nearest_channel(&centers, std::f64::NAN, 146e6).is_err());
+    assert!(nearest_channel(&vec![std::f64::NAN], 2e6, 146e6).is_err());
+    assert!(nearest_channel(&Vec::new(), 2e6, 146e6).is_err());
+}
+
+/// The receive calls allowed to fail in a row before the router gives up
+/// on the device.
+const MAX_RECV_FAILURES: u32 = 10;
+
+/// Like `router` but with the optional settings in `cfg`.
+pub fn router_with_config(rtrans: Arc<Mutex<Vec<Transmission>>>, targets: Vec<MonitorSpec>, mut cfg: RouterConfig) {        
     println!("[ham-router] initializing");
     
-    // Let us determine the actual spread of these frequencies so we know
-    // we sample rate we need to run at in order to capture them.
-    let mut target_min = std::f64::MAX;
-    let mut target_max = std::f64::MIN;
-    for x in 0..targets.len() {
-        if targets[x].freq > target_max {
-            target_max = targets[x].freq;
+    if targets.len() == 0 {
+        println!("[ham-router] nothing to monitor");
+        return;
+    }
+
+    match targets.iter().find(|t| !t.freq.is_finite()) {
+        Option::Some(t) => {
+            println!("[ham-router] refusing to monitor the frequency {}", t.freq);
+            return;
+        },
+        Option::None => (),
+    }
+    
+    let bands = split_bands(&targets, cfg.channels);
+    
+    // Let us determine the actual spread of the frequencies of each band so
+    // we know the sample rate we need to run at in order to capture them.
+    let mut spread = 0f64;
+    let mut centers: Vec<f64> = Vec::new();
+    for band in bands.iter() {
+        let mut target_min = std::f64::MAX;
+        let mut target_max = std::f64::MIN;
+        for &x in band.iter() {
+            if targets[x].freq > target_max {
+                target_max = targets[x].freq;
+            }
+            
+            if targets[x].freq < target_min {
+                target_min = targets[x].freq;
+            }
         }
         
-        if targets[x].freq < target_min {
-            target_min = targets[x].freq;
+        if target_max - target_min > spread {
+            spread = target_max - target_min;
         }
+        
+        // Set the center frequency in the middle of the spread.
+        centers.push(target_min + (target_max - target_min) * 0.5);
     }
     
     // Add 100khz on both sides just to be safe, and this will
     // be our sample rate.
-    let mut sps = (target_max - target_min) + 200000.0 * 2.0;
+    let mut sps = spread + 200000.0 * 2.0;
     
     if sps < 4000000.0 {
         sps = 4000000.0;
     }
     
-
-    // Set our center frequency in the middle of the spread.
-    let freq_center = target_min + (target_max - target_min) * 0.5;
-    
-    println!("[ham-router] setting center frequency to {}", freq_center);
+    for chan in 0..centers.len() {
+        println!("[ham-router] setting center frequency of channel {} to {}", chan, centers[chan]);
+    }
     println!("[ham-router] setting sample rate to {}", sps);
     
     // This forms a nice filter for 15khz FM.
@@ -357,15 +608,44 @@ pub fn router(rtrans: Arc<Mutex<Vec<Transmission>>>, targets: Vec<MonitorSpec>)
     
     println!("decim set to {}", decim);   
     
-    for x in 0..targets.len() {
-        println!("offset:{} frequency:{}", freq_center - targets[x].freq, targets[x].freq);
-        monitors.push(Monitor {
-            freq:       targets[x].freq,
-            offset:     freq_center - targets[x].freq,
-            demod:      FMDemod::new(sps, decim, freq_center - targets[x].freq, 15000.0, taps.clone(), 3),
-            buf:        Vec::new(),  
-            dead:       1,
-        });
+    for chan in 0..bands.len() {
+        for &x in bands[chan].iter() {
+            println!("channel:{} offset:{} frequency:{}", chan, centers[chan] - targets[x].freq, targets[x].freq);
+            monitors.push(Monitor::new(x, chan, targets[x].freq, centers[chan], sps, decim, taps.clone()));
+        }
+    }
+    
+    // Idle channels are listed from the start.
+    match cfg.stats {
+        Option::Some(ref stats) => {
+            let mut stats = stats.lock().unwrap();
+            for m in monitors.iter() {
+                stats.register(m.id, m.freq, now());
+            }
+        },
+        Option::None => (),
+    }
+
+    // Monitors created later on are given ids following those of the targets.
+    let mut nextid = targets.len();
+    
+    // One searcher for each channel when discovery is enabled.
+    let mut discovery: Vec<Discovery> = Vec::new();
+    match cfg.discovery {
+        Option::Some(ref spec) => {
+            for chan in 0..centers.len() {
+                let mut d = match Discovery::new(sps, centers[chan]) {
+                    Result::Ok(d) => d,
+                    Result::Err(err) => {
+                        println!("[ham-router] unable to search channel {}: {}", chan, err);
+                        return;
+                    },
+                };
+                d.threshold = spec.threshold;
+                discovery.push(d);
+            }
+        },
+        Option::None => (),
     }
 
     //     
@@ -375,10 +655,24 @@ pub fn router(rtrans: Arc<Mutex<Vec<Transmission>>>, targets: Vec<MonitorSpec>)
     
     //let mut alsa = dsp::Alsa::new(16000);
 
-    // At the moment the gain is locked at 70dB since my setup is currently
-    // using a very low gain antenna. However, in the future I can look at
-    // auto adjusting the gain lower if it causes over-saturation.
-    let mut ausrp = USRPSource::new(sps, freq_center, 1.0);
+    // The channels open with a low gain which `cfg.gain` takes over once
+    // the device is ready.
+    let mut chans: Vec<usrp::ChannelConfig> = Vec::new();
+    for chan in 0..centers.len() {
+        let mut cc = usrp::ChannelConfig::new(centers[chan], 1.0);
+        if chan < cfg.frontend.len() {
+            cc.frontend = cfg.frontend[chan].clone();
+        }
+        chans.push(cc);
+    }
+    let subdev = cfg.subdev.clone();
+    let mut ausrp = match USRPSource::open_channels(&cfg.device, sps, subdev.as_ref().map(|s| &s[..]), &chans) {
+        Result::Ok(ausrp) => ausrp,
+        Result::Err(err) => {
+            println!("[ham-router] unable to open device: {}", err);
+            return;
+        },
+    };
     let mut usrp = ausrp.lock().unwrap();
     
     // A debugging source that mimics the USRP as a source.    
@@ -392,106 +686,225 @@ pub fn router(rtrans: Arc<Mutex<Vec<Transmission>>>, targets: Vec<MonitorSpec>)
     
     println!("[ham-router] capturing broadband signal..");
 
-    let mut avgpwr = 0f64;
-    let mut avgcnt = 0usize;
-    let mut curgain = 1.0f64;
-    let mut maxgain = 50.0f64;
+    match cfg.time {
+        Option::Some(ref tc) => match usrp.align_time(tc) {
+            Result::Ok(_) => (),
+            Result::Err(err) => println!("[ham-router] unable to align device time: {}", err),
+        },
+        Option::None => (),
+    }
+    
+    cfg.gain.init(&mut *usrp);
+    
+    let mut health = cfg.health.map(|interval| usrp::HealthCheck::new(interval));
+    let mut failures = 0;
 
     loop {
-        let mut ibuf = usrp.recv();
+        let mut ibufs = match usrp.recv_channels() {
+            Result::Ok((ibufs, info)) => {
+                match info.error {
+                    RxError::None | RxError::Timeout => (),
+                    err => {
+                        let c = usrp.counters();
+                        println!("[ham-router] receive reported {:?} overflows:{} out_of_sequence:{} dropped:{}",
+                            err, c.overflows, c.out_of_sequence, c.dropped
+                        );
+                    },
+                }
+                failures = 0;
+                ibufs
+            },
+            Result::Err(err) => {
+                // Errors in the samples themselves come back in `info`, so
+                // this is the device or its stream failing. Back off and
+                // give up if it keeps failing.
+                failures += 1;
+                if failures >= MAX_RECV_FAILURES {
+                    println!("[ham-router] receive failed {} times, stopping: {}", failures, err);
+                    return;
+                }
+                println!("[ham-router] receive failed: {}", err);
+                thread::sleep_ms(10 << failures);
+                continue;
+            },
+        };
+        
+        cfg.gain.update(&ibufs[0], &mut *usrp);
         
-        // Try to establish AGC.
-        for x in 0..ibuf.len() {
-            let spwr = (ibuf[x].i * ibuf[x].i + ibuf[x].q * ibuf[x].q).sqrt();
-            avgpwr += spwr as f64;
-            avgcnt += 1;
+        match health {
+            Option::Some(ref mut hc) => { hc.poll(&mut *usrp, now()); },
+            Option::None => (),
         }
         
-        if avgcnt > 500000 {
-            avgpwr = avgpwr / avgcnt as f64;
-            if avgpwr > 0.20 {
-                curgain -= 1.0;
-                if curgain < 1.0 {
-                    curgain = 1.0;
-                }
-                usrp.set_rx_gain(curgain);
-                println!("gain decreased to {} with avg pwr {}", curgain, avgpwr);
+        loop {
+            let cmd = match cfg.control {
+                Option::Some(ref rx) => match rx.try_recv() {
+                    Result::Ok(cmd) => cmd,
+                    Result::Err(_) => break,
+                },
+                Option::None => break,
+            };
+            match cmd {
+                RouterCommand::ListMonitors { reply } => {
+                    let _ = reply.send(monitors.iter().map(|m| MonitorInfo {
+                        id:         m.id,
+                        freq:       m.freq,
+                        channel:    m.chan,
+                        temporary:  m.temporary.is_some(),
+                    }).collect());
+                },
+                RouterCommand::AddMonitor { freq, reply } => {
+                    // Sent from the network, so anything may arrive here.
+                    let best = match nearest_channel(&centers, sps, freq) {
+                        Result::Ok(best) => best,
+                        Result::Err(err) => {
+                            let _ = reply.send(Result::Err(err));
+                            continue;
+                        },
+                    };
+                    println!("[ham-router] adding monitor {} on {}", nextid, freq);
+                    monitors.push(Monitor::new(nextid, best, freq, centers[best], sps, decim, taps.clone()));
+                    match cfg.stats {
+                        Option::Some(ref stats) => stats.lock().unwrap().register(nextid, freq, now()),
+                        Option::None => (),
+                    }
+                    let _ = reply.send(Result::Ok(nextid));
+                    nextid += 1;
+                },
+                RouterCommand::RemoveMonitor { id, reply } => {
+                    match monitors.iter().position(|m| m.id == id) {
+                        Option::Some(x) => {
+                            println!("[ham-router] removing monitor {}", id);
+                            let mut m = monitors.remove(x);
+                            // Subscribers saw it start so they hear it end.
+                            if m.buf.len() > 0 {
+                                m.finish(&rtrans, &cfg, false);
+                            }
+                            match cfg.stats {
+                                Option::Some(ref stats) => stats.lock().unwrap().retire(id, now()),
+                                Option::None => (),
+                            }
+                            let _ = reply.send(Result::Ok(()));
+                        },
+                        Option::None => { let _ = reply.send(Result::Err(format!("no monitor {}", id))); },
+                    }
+                },
+                RouterCommand::SetGain { gain, reply } => {
+                    if !gain.is_finite() {
+                        let _ = reply.send(Result::Err(format!("{} is not a gain", gain)));
+                        continue;
+                    }
+                    // The hardware AGC of a previous strategy would fight the
+                    // fixed gain. Not every front end has one to disable.
+                    match usrp.set_rx_agc(false) {
+                        Result::Ok(_) => (),
+                        Result::Err(err) => println!("[ham-router] unable to disable agc: {}", err),
+                    }
+                    match usrp.set_rx_gain(gain) {
+                        Result::Ok(_) => {
+                            println!("[ham-router] gain fixed at {}, replacing the gain strategy", gain);
+                            cfg.gain = Box::new(gain::FixedGain::new(gain));
+                            let _ = reply.send(Result::Ok(()));
+                        },
+                        Result::Err(err) => {
+                            let _ = reply.send(Result::Err(format!("unable to set gain: {}", err)));
+                        },
+                    }
+                },
+                RouterCommand::Stats { reply } => {
+                    let _ = reply.send(match cfg.stats {
+                        Option::Some(ref stats) => Result::Ok(stats.lock().unwrap().to_json(now())),
+                        Option::None => Result::Err(String::from("statistics are not kept")),
+                    });
+                },
+                RouterCommand::Subscribe { events, audio } => {
+                    cfg.subscribers.lock().unwrap().push((events, audio));
+                },
             }
-            if avgpwr < 0.03 {
-                curgain += 1.0;
-                if curgain > maxgain {
-                    curgain = maxgain;
-                }
-                usrp.set_rx_gain(curgain);
-                println!("gain increased to {} with avg pwr {}", curgain, avgpwr);
+        }
+        
+        for chan in 0..discovery.len() {
+            let freq_center = centers[chan];
+            let cands = match discovery[chan].work(&ibufs[chan]) {
+                Option::Some(cands) => cands,
+                Option::None => continue,
+            };
+
+            let spec = cfg.discovery.as_ref().unwrap();
+            match spec.spawn_idle {
+                Option::Some(idle) => {
+                    for c in cands.iter() {
+                        // The oscillator of the demodulator can only be built for
+                        // offsets well inside the captured band.
+                        if (c.center - freq_center).abs() > sps / 4.0 - 15000.0 {
+                            continue;
+                        }
+                        if monitors.iter().any(|m| (m.freq - c.center).abs() < 12500.0) {
+                            continue;
+                        }
+                        println!("[ham-router] discovered signal at {} with bandwidth {}", c.center, c.bandwidth);
+                        let mut mon = Monitor::new(nextid, chan, c.center, freq_center, sps, decim, taps.clone());
+                        mon.temporary = Option::Some(idle);
+                        monitors.push(mon);
+                        match cfg.stats {
+                            Option::Some(ref stats) => stats.lock().unwrap().register(nextid, c.center, now()),
+                            Option::None => (),
+                        }
+                        nextid += 1;
+                    }
+                },
+                Option::None => (),
+            }
+            match spec.report {
+                Option::Some(ref tx) => { let _ = tx.send(cands); },
+                Option::None => (),
             }
-            avgcnt = 0;
-            avgpwr = 0.0;
         }
+        
+        // Retire discovered monitors that have gone quiet.
+        let t = now();
+        let expired = |m: &Monitor| match m.temporary {
+            Option::Some(idle) => m.buf.len() == 0 && t - m.active >= idle,
+            Option::None => false,
+        };
+        match cfg.stats {
+            Option::Some(ref stats) => {
+                let mut stats = stats.lock().unwrap();
+                for m in monitors.iter().filter(|m| expired(*m)) {
+                    stats.retire(m.id, t);
+                }
+            },
+            Option::None => (),
+        }
+        monitors.retain(|m| !expired(m));
                 
-        total_samps += ibuf.len();
+        total_samps += ibufs[0].len();
 
         let st = time::precise_time_ns() as f64 / 1000.0 / 1000.0 / 1000.0;
 
         for mon in monitors.iter_mut() {        
         
-            let mut out = mon.demod.work(&ibuf);
+            let mut out = mon.demod.work(&ibufs[mon.chan]);
 
             if total_samps > 4000000 {            
                 println!("freq:{} sq:{} dead:{}", mon.freq, mon.demod.sq, mon.dead);
             }   
         
-            for x in 0..out.len() {
-                if out[x].abs() > 0.0 {
-                    mon.dead -= 1;
-                } else {
-                    mon.dead += 1;
-                }
-            
-                if mon.dead < -8000 {
-                    mon.dead = -8000;
-                }
-                
-                if mon.dead > 30 {
-                    mon.dead = 30;
-                }
-                
-                // If we have activity then start pushing the audio output
-                // samples into our monitor buffer. Once we see no activity
-                // then evaluate if it contains enough to be considered a
-                // transmission and if so then place it into the output queue
-                // and prepare for the next transmission.
-                if mon.dead < 0 {
-                    mon.buf.push(out[x]);     
-                } else {
-                    if mon.buf.len() / 16000 > 2 {
-                        // Place the transmission into the output buffer. The locking
-                        // provides the synchronization to support an external thread
-                        // accessing the buffer concurrently.
-                        println!("[ham-router] placed transmission of {} seconds into output buffer", mon.buf.len() as f32 / 16000.0);
-                        let mut tout = rtrans.lock().unwrap();
-
-                        // Since we have ownership rules and rules that keep something from
-                        // being uninitialized we must create a fresh buffer and swap it out
-                        // with the buffer that we wish to put into the queue.
-                        let mut tmpbuf: Vec<f32> = Vec::new();
-                        std::mem::swap(&mut tmpbuf, &mut mon.buf);                        
-                        
-                        tout.push(Transmission {
-                            freq:       mon.freq,
-                            buf:        tmpbuf,
-                        });
-                    } else {
-                        mon.buf.clear();
-                    }
-                }
-            }        
+            mon.feed(&out, &rtrans, &cfg);
         }
                 
         if total_samps > 4000000 {
+            let c = usrp.counters();
+            println!("[ham-router] overflows:{} timeouts:{} out_of_sequence:{} dropped:{}",
+                c.overflows, c.timeouts, c.out_of_sequence, c.dropped
+            );
             total_samps = 0;
         }
         
+        for buf in ibufs {
+            usrp.recycle(buf);
+        }
+        
         //println!("done {}", (time::precise_time_ns() - st) as f64 / 1000.0 / 1000.0 / 1000.0);
         //println!("wavbuf:{}", buf.len());
         //println!("total_samps:{} time:{}", total_samps, st as f64 / 1000.0 / 1000.0 / 1000.0);
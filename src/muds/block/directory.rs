@@ -0,0 +1,462 @@
+///! A registry of named ports that can be linked across the network.
+///!
+///! Each system, named by a `sysid`, runs a `Directory`. Components of the
+///! system advertise ports named by a `compid` and a `portid`. A source
+///! port sends data to everything linked to it and a sink port receives
+///! everything sent to it. Once the directory is served with `net` and the
+///! address of another system is made known with `sys`, a local port can be
+///! linked to a port of that system by name with `link_to` or `link_from`.
+
+use super::net::{Server, ServerStop, ControlInfo, Client, Connection, Permission, Message, FrameError, put_bytes, get_bytes};
+use std::collections::HashMap;
+use std::fmt;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Sender, Receiver, channel};
+use std::net::SocketAddr;
+use std::thread;
+use std;
+
+const KIND_LIST: u16 = 0x0101;
+const KIND_PORTS: u16 = 0x0102;
+const KIND_LINK_TO: u16 = 0x0103;
+const KIND_LINK_FROM: u16 = 0x0104;
+const KIND_DATA: u16 = 0x0105;
+const KIND_OK: u16 = 0x0106;
+const KIND_ERROR: u16 = 0x0107;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PortKind {
+    Source,
+    Sink,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PortInfo {
+    pub compid:     Vec<u8>,
+    pub portid:     Vec<u8>,
+    pub kind:       PortKind,
+}
+
+#[derive(Debug)]
+pub enum DirectoryError {
+    Frame(FrameError),
+    ///! The `sysid` has not been made known with `sys`.
+    UnknownSystem(Vec<u8>),
+    ///! The port does not exist or is not of the kind needed.
+    NoPort(Vec<u8>, Vec<u8>),
+    ///! The other system refused the request.
+    Refused(String),
+}
+
+impl fmt::Display for DirectoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DirectoryError::Frame(ref err) => write!(f, "{}: {}", self.description(), err),
+            DirectoryError::UnknownSystem(ref sysid) => {
+                write!(f, "{}: {}", self.description(), String::from_utf8_lossy(sysid))
+            },
+            DirectoryError::NoPort(ref compid, ref portid) => {
+                write!(f, "{}: {}/{}", self.description(), String::from_utf8_lossy(compid), String::from_utf8_lossy(portid))
+            },
+            DirectoryError::Refused(ref reason) => write!(f, "{}: {}", self.description(), reason),
+        }
+    }
+}
+
+impl Error for DirectoryError {
+    fn description(&self) -> &str {
+        match *self {
+            DirectoryError::Frame(_) => "connection failed",
+            DirectoryError::UnknownSystem(_) => "unknown system",
+            DirectoryError::NoPort(_, _) => "no such port",
+            DirectoryError::Refused(_) => "refused",
+        }
+    }
+}
+
+impl From<FrameError> for DirectoryError {
+    fn from(err: FrameError) -> DirectoryError {
+        DirectoryError::Frame(err)
+    }
+}
+
+struct Port {
+    kind:           PortKind,
+    ///! Where the data of a source port goes.
+    subscribers:    Vec<Sender<Vec<u8>>>,
+    ///! Where the data sent to a sink port goes.
+    sink:           Option<Sender<Vec<u8>>>,
+}
+
+type Ports = Arc<Mutex<HashMap<(Vec<u8>, Vec<u8>), Port>>>;
+
+///! The sending end of a source port.
+pub struct SourcePort {
+    ports:      Ports,
+    key:        (Vec<u8>, Vec<u8>),
+}
+
+impl SourcePort {
+    ///! Send `data` to every port linked to this one. Links that went away
+    ///! are dropped.
+    pub fn send(&self, data: &[u8]) {
+        let mut ports = self.ports.lock().unwrap();
+        match ports.get_mut(&self.key) {
+            Option::Some(port) => port.subscribers.retain(|s| s.send(data.to_vec()).is_ok()),
+            Option::None => (),
+        }
+    }
+}
+
+pub struct Directory {
+    sysid:      Vec<u8>,
+    systems:    Mutex<HashMap<Vec<u8>, SocketAddr>>,
+    ports:      Ports,
+    ///! The servers started by `net`.
+    servers:    Mutex<Vec<ServerStop>>,
+}
+
+impl Directory {
+    pub fn new(sysid: &[u8]) -> Directory {
+        Directory {
+            sysid:      sysid.to_vec(),
+            systems:    Mutex::new(HashMap::new()),
+            ports:      Arc::new(Mutex::new(HashMap::new())),
+            servers:    Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn sysid(&self) -> &[u8] {
+        &self.sysid
+    }
+
+    ///! Make the system `sysid` known as reachable at `addr`.
+    pub fn sys(&self, sysid: &[u8], addr: SocketAddr) {
+        self.systems.lock().unwrap().insert(sysid.to_vec(), addr);
+    }
+
+    ///! Advertise a source port.
+    pub fn source(&self, compid: &[u8], portid: &[u8]) -> SourcePort {
+        let key = (compid.to_vec(), portid.to_vec());
+        self.ports.lock().unwrap().insert(key.clone(), Port {
+            kind:           PortKind::Source,
+            subscribers:    Vec::new(),
+            sink:           Option::None,
+        });
+        SourcePort { ports: self.ports.clone(), key: key }
+    }
+
+    ///! Advertise a sink port and return where its data arrives.
+    pub fn sink(&self, compid: &[u8], portid: &[u8]) -> Receiver<Vec<u8>> {
+        let (tx, rx) = channel();
+        self.ports.lock().unwrap().insert((compid.to_vec(), portid.to_vec()), Port {
+            kind:           PortKind::Sink,
+            subscribers:    Vec::new(),
+            sink:           Option::Some(tx),
+        });
+        rx
+    }
+
+    ///! Return the ports advertised here.
+    pub fn ports(&self) -> Vec<PortInfo> {
+        list(&self.ports)
+    }
+
+    ///! Return the ports advertised by the system `sysid`.
+    pub fn remote_ports(&self, sysid: &[u8]) -> Result<Vec<PortInfo>, DirectoryError> {
+        let mut conn = try!(self.connect(sysid));
+        try!(conn.send_msg(&Message::new(KIND_LIST, Vec::new())));
+        let msg = try!(conn.recv_msg());
+        match msg.kind {
+            KIND_PORTS => Result::Ok(decode_ports(&msg.payload)),
+            _ => Result::Err(refused(&msg)),
+        }
+    }
+
+    ///! Serve the ports of this directory to other systems on `addr`.
+    ///! Returns the address listened on.
+    pub fn net(&self, addr: &str) -> Option<SocketAddr> {
+        let server = match Server::new(addr) {
+            Option::Some(server) => server,
+            Option::None => return Option::None,
+        };
+        let local = server.local_addr();
+        self.servers.lock().unwrap().push(server.stop());
+        let ports = self.ports.clone();
+        thread::spawn(move || serve(server, ports));
+        Option::Some(local)
+    }
+
+    ///! Stop serving the ports to other systems and close the connections
+    ///! of every system linked through them. Links made from here with
+    ///! `link_to` or `link_from` end once the other system closes them.
+    pub fn shutdown(&self) {
+        let servers = std::mem::replace(&mut *self.servers.lock().unwrap(), Vec::new());
+        for server in servers.iter() {
+            server.shutdown();
+        }
+    }
+
+    ///! Link the local source port to the sink port of the system `sysid`
+    ///! so everything sent on the local port arrives there.
+    pub fn link_to(&self, sysid: &[u8], compid: &[u8], portid: &[u8], from_compid: &[u8], from_portid: &[u8]) -> Result<(), DirectoryError> {
+        let rx = try!(self.subscribe(from_compid, from_portid));
+        let mut conn = try!(self.connect(sysid));
+        try!(conn.send_msg(&Message::new(KIND_LINK_TO, encode_port(compid, portid))));
+        let reply = try!(conn.recv_msg());
+        if reply.kind != KIND_OK {
+            return Result::Err(refused(&reply));
+        }
+        thread::spawn(move || {
+            loop {
+                let data = match rx.recv() {
+                    Result::Ok(data) => data,
+                    Result::Err(_) => return,
+                };
+                if conn.send_msg(&Message::new(KIND_DATA, data)).is_err() {
+                    return;
+                }
+            }
+        });
+        Result::Ok(())
+    }
+
+    ///! Link the source port of the system `sysid` to the local sink port
+    ///! so everything sent on the remote port arrives here.
+    pub fn link_from(&self, sysid: &[u8], compid: &[u8], portid: &[u8], to_compid: &[u8], to_portid: &[u8]) -> Result<(), DirectoryError> {
+        let tx = match self.ports.lock().unwrap().get(&(to_compid.to_vec(), to_portid.to_vec())) {
+            Option::Some(&Port { sink: Option::Some(ref tx), .. }) => tx.clone(),
+            _ => return Result::Err(DirectoryError::NoPort(to_compid.to_vec(), to_portid.to_vec())),
+        };
+        let mut conn = try!(self.connect(sysid));
+        try!(conn.send_msg(&Message::new(KIND_LINK_FROM, encode_port(compid, portid))));
+        let reply = try!(conn.recv_msg());
+        if reply.kind != KIND_OK {
+            return Result::Err(refused(&reply));
+        }
+        thread::spawn(move || {
+            loop {
+                match conn.recv_msg() {
+                    Result::Ok(msg) => {
+                        if msg.kind == KIND_DATA && tx.send(msg.payload).is_err() {
+                            return;
+                        }
+                    },
+                    Result::Err(_) => return,
+                }
+            }
+        });
+        Result::Ok(())
+    }
+
+    fn subscribe(&self, compid: &[u8], portid: &[u8]) -> Result<Receiver<Vec<u8>>, DirectoryError> {
+        subscribe(&self.ports, compid, portid)
+    }
+
+    fn connect(&self, sysid: &[u8]) -> Result<Connection, DirectoryError> {
+        let addr = match self.systems.lock().unwrap().get(sysid) {
+            Option::Some(addr) => *addr,
+            Option::None => return Result::Err(DirectoryError::UnknownSystem(sysid.to_vec())),
+        };
+        Result::Ok(try!(Connection::connect(&addr)))
+    }
+}
+
+impl Drop for Directory {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn refused(msg: &Message) -> DirectoryError {
+    DirectoryError::Refused(String::from_utf8_lossy(&msg.payload).into_owned())
+}
+
+fn subscribe(ports: &Ports, compid: &[u8], portid: &[u8]) -> Result<Receiver<Vec<u8>>, DirectoryError> {
+    let mut ports = ports.lock().unwrap();
+    match ports.get_mut(&(compid.to_vec(), portid.to_vec())) {
+        Option::Some(port) if port.kind == PortKind::Source => {
+            let (tx, rx) = channel();
+            port.subscribers.push(tx);
+            Result::Ok(rx)
+        },
+        _ => Result::Err(DirectoryError::NoPort(compid.to_vec(), portid.to_vec())),
+    }
+}
+
+fn list(ports: &Ports) -> Vec<PortInfo> {
+    ports.lock().unwrap().iter().map(|(k, p)| PortInfo {
+        compid:     k.0.clone(),
+        portid:     k.1.clone(),
+        kind:       p.kind,
+    }).collect()
+}
+
+fn encode_port(compid: &[u8], portid: &[u8]) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::new();
+    put_bytes(&mut out, compid);
+    put_bytes(&mut out, portid);
+    out
+}
+
+fn decode_port(buf: &mut &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let compid = match get_bytes(buf) { Option::Some(v) => v, Option::None => return Option::None };
+    let portid = match get_bytes(buf) { Option::Some(v) => v, Option::None => return Option::None };
+    Option::Some((compid, portid))
+}
+
+fn decode_ports(payload: &[u8]) -> Vec<PortInfo> {
+    let mut out: Vec<PortInfo> = Vec::new();
+    let mut buf = payload;
+    loop {
+        let (compid, portid) = match decode_port(&mut buf) {
+            Option::Some(v) => v,
+            Option::None => return out,
+        };
+        if buf.len() < 1 {
+            return out;
+        }
+        let kind = if buf[0] == 0 { PortKind::Source } else { PortKind::Sink };
+        buf = &buf[1..];
+        out.push(PortInfo { compid: compid, portid: portid, kind: kind });
+    }
+}
+
+///! Answer the requests of other systems until the server stops.
+fn serve(server: Server, ports: Ports) {
+    // The sink each connection that linked to one feeds.
+    let mut feeds: HashMap<u64, Sender<Vec<u8>>> = HashMap::new();
+    loop {
+        let (luid, client) = match server.read() {
+            Result::Ok(ControlInfo::ClientData { luid, client }) => (luid, client),
+            Result::Ok(ControlInfo::ClientBye { luid, .. }) => {
+                feeds.remove(&luid);
+                continue;
+            },
+            Result::Ok(_) => continue,
+            Result::Err(_) => return,
+        };
+        loop {
+            let msg = match client.lock().unwrap().recv_msg() {
+                Result::Ok(Option::Some(msg)) => msg,
+                Result::Ok(Option::None) => break,
+                Result::Err(_) => {
+                    server.disconnect(luid);
+                    break;
+                },
+            };
+            handle(&msg, luid, &client, &ports, &mut feeds);
+        }
+    }
+}
+
+fn reply(client: &Arc<Mutex<Client>>, kind: u16, payload: Vec<u8>) {
+    let writer = client.lock().unwrap().writer();
+    let _ = writer.send_msg(&Message::new(kind, payload));
+}
+
+fn handle(msg: &Message, luid: u64, client: &Arc<Mutex<Client>>, ports: &Ports, feeds: &mut HashMap<u64, Sender<Vec<u8>>>) {
+    match msg.kind {
+        KIND_LIST => {
+            let mut out: Vec<u8> = Vec::new();
+            for p in list(ports).iter() {
+                put_bytes(&mut out, &p.compid);
+                put_bytes(&mut out, &p.portid);
+                out.push(if p.kind == PortKind::Source { 0 } else { 1 });
+            }
+            reply(client, KIND_PORTS, out);
+        },
+        KIND_LINK_TO => {
+            // Feeding a sink writes into the pipeline.
+            if client.lock().unwrap().permission() != Permission::Control {
+                return reply(client, KIND_ERROR, b"not permitted".to_vec());
+            }
+            let key = match decode_port(&mut &msg.payload[..]) {
+                Option::Some(key) => key,
+                Option::None => return reply(client, KIND_ERROR, b"malformed request".to_vec()),
+            };
+            let tx = match ports.lock().unwrap().get(&key) {
+                Option::Some(&Port { sink: Option::Some(ref tx), .. }) => tx.clone(),
+                _ => return reply(client, KIND_ERROR, b"no such sink port".to_vec()),
+            };
+            feeds.insert(luid, tx);
+            reply(client, KIND_OK, Vec::new());
+        },
+        KIND_LINK_FROM => {
+            let rx = match decode_port(&mut &msg.payload[..]) {
+                Option::Some((compid, portid)) => match subscribe(ports, &compid, &portid) {
+                    Result::Ok(rx) => rx,
+                    Result::Err(_) => return reply(client, KIND_ERROR, b"no such source port".to_vec()),
+                },
+                Option::None => return reply(client, KIND_ERROR, b"malformed request".to_vec()),
+            };
+            reply(client, KIND_OK, Vec::new());
+            let writer = client.lock().unwrap().writer();
+            thread::spawn(move || {
+                loop {
+                    let data = match rx.recv() {
+                        Result::Ok(data) => data,
+                        Result::Err(_) => return,
+                    };
+                    if writer.send_msg(&Message::new(KIND_DATA, data)).is_err() {
+                        return;
+                    }
+                }
+            });
+        },
+        KIND_DATA => {
+            match feeds.get(&luid) {
+                Option::Some(tx) => { let _ = tx.send(msg.payload.clone()); },
+                Option::None => (),
+            }
+        },
+        _ => reply(client, KIND_ERROR, b"unknown request".to_vec()),
+    }
+}
+
+#[test]
+fn test_link() {
+    let a = Directory::new(b"alpha");
+    let b = Directory::new(b"bravo");
+    let b_addr = b.net("127.0.0.1:0").unwrap();
+    let a_addr = a.net("127.0.0.1:0").unwrap();
+    a.sys(b"bravo", b_addr);
+    b.sys(b"alpha", a_addr);
+
+    let iq = a.source(b"usrp", b"iq");
+    let demod_in = b.sink(b"demod", b"iq");
+    let audio = b.source(b"demod", b"audio");
+    let speaker = a.sink(b"speaker", b"in");
+
+    let remote = a.remote_ports(b"bravo").unwrap();
+    assert_eq!(remote.len(), 2);
+    assert!(remote.contains(&PortInfo { compid: b"demod".to_vec(), portid: b"iq".to_vec(), kind: PortKind::Sink }));
+
+    a.link_to(b"bravo", b"demod", b"iq", b"usrp", b"iq").unwrap();
+    a.link_from(b"bravo", b"demod", b"audio", b"speaker", b"in").unwrap();
+    assert!(a.link_to(b"bravo", b"nope", b"iq", b"usrp", b"iq").is_err());
+
+    iq.send(&[1, 2, 3]);
+    assert_eq!(demod_in.recv().unwrap(), vec![1, 2, 3]);
+    audio.send(&[4, 5]);
+    assert_eq!(speaker.recv().unwrap(), vec![4, 5]);
+}
+
+#[test]
+fn test_directory_shutdown() {
+    let a = Directory::new(b"alpha");
+    let b = Directory::new(b"bravo");
+    let b_addr = b.net("127.0.0.1:0").unwrap();
+    a.sys(b"bravo", b_addr);
+    let _iq = b.source(b"usrp", b"iq");
+    assert_eq!(a.remote_ports(b"bravo").unwrap().len(), 1);
+
+    b.shutdown();
+    // No one answers any more.
+    match a.remote_ports(b"bravo") {
+        Result::Err(err) => assert!(format!("{}", err).starts_with("connection failed")),
+        Result::Ok(_) => panic!("expected the directory to be gone"),
+    }
+    assert_eq!(format!("{}", a.remote_ports(b"charlie").unwrap_err()), "unknown system: charlie");
+}
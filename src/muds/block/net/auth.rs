@@ -0,0 +1,456 @@
+///! Pre-shared key authentication and optional session encryption.
+///!
+///! When the server has an `AuthConfig` its hello says so and, after both
+///! hellos, it sends a random challenge. The client answers with the name
+///! of its key, its own random nonce and an HMAC over both nonces and the
+///! name. The server answers with the permission the key grants and an
+///! HMAC over the nonces, the name and the permission, proving it holds the
+///! key too. Until then nothing reaches the
+///! services behind the server.
+///!
+///! After the handshake every message is sealed: wrapped in a `KIND_SEALED`
+///! message with a sequence number and an HMAC over both, so messages that
+///! were altered, replayed or slipped in are refused. With encryption the
+///! message is also run through ChaCha20 before the HMAC is taken. Each
+///! direction has its own keys, derived from the shared key, both nonces
+///! and whether to encrypt.
+
+use super::frame::{Message, FrameReader, FrameError, put_bytes, get_bytes};
+use super::crypto::{ChaCha20, hmac_sha256, ct_eq, random_bytes};
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+use std::collections::HashMap;
+use std::net::TcpStream;
+use std::io::{Read, Write};
+use std;
+
+pub const KIND_CHALLENGE: u16 = 0x0010;
+pub const KIND_RESPONSE: u16 = 0x0011;
+pub const KIND_ACCEPT: u16 = 0x0012;
+pub const KIND_REFUSE: u16 = 0x0013;
+pub const KIND_SEALED: u16 = 0x0014;
+
+const NONCE_LEN: usize = 16;
+
+///! What a client may do once connected.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Permission {
+    ///! May only receive, for instance subscribe or link from a source.
+    Listen,
+    ///! May also change things, for instance tune or feed a sink.
+    Control,
+}
+
+impl Permission {
+    fn to_u8(&self) -> u8 {
+        match *self {
+            Permission::Listen => 0,
+            Permission::Control => 1,
+        }
+    }
+
+    fn from_u8(v: u8) -> Permission {
+        match v {
+            1 => Permission::Control,
+            _ => Permission::Listen,
+        }
+    }
+}
+
+///! The keys a server accepts.
+pub struct AuthConfig {
+    keys:           HashMap<String, (Vec<u8>, Permission)>,
+    ///! Encrypt everything after the handshake.
+    pub encrypt:    bool,
+}
+
+impl AuthConfig {
+    pub fn new() -> AuthConfig {
+        AuthConfig {
+            keys:       HashMap::new(),
+            encrypt:    false,
+        }
+    }
+
+    ///! Accept clients holding `key` under `name`, granting them `permission`.
+    pub fn add_key(&mut self, name: &str, key: &[u8], permission: Permission) {
+        self.keys.insert(String::from(name), (key.to_vec(), permission));
+    }
+}
+
+///! The key a client authenticates with.
+#[derive(Clone)]
+pub struct Credentials {
+    pub name:       String,
+    pub key:        Vec<u8>,
+}
+
+impl Credentials {
+    pub fn new(name: &str, key: &[u8]) -> Credentials {
+        Credentials { name: String::from(name), key: key.to_vec() }
+    }
+}
+
+///! Seals or opens the messages going one way in a session.
+///!
+///! A sealed message holds a `u64` sequence number, the kind and payload of
+///! the message and an HMAC over all of it. The sequence number is also the
+///! nonce the message is encrypted with, so each message stands alone and
+///! one dropped under backpressure does not spoil the next. Numbers must go
+///! up but may skip for the same reason.
+pub struct Seal {
+    key:        [u8; 32],
+    mac:        [u8; 32],
+    encrypt:    bool,
+    ///! The next number to send, or the lowest number still accepted.
+    seq:        u64,
+}
+
+impl Seal {
+    fn new(key: &[u8], direction: &[u8], challenge: &[u8], cn: &[u8], encrypt: bool) -> Seal {
+        Seal {
+            key:        mac(key, b"muds-key", direction, challenge, cn),
+            mac:        mac(key, b"muds-mac", direction, challenge, cn),
+            encrypt:    encrypt,
+            seq:        0,
+        }
+    }
+
+    fn cipher(&self, seq: u64) -> ChaCha20 {
+        let mut nonce = [0u8; 12];
+        (&mut nonce[4..]).write_u64::<LittleEndian>(seq).unwrap();
+        ChaCha20::new(&self.key, &nonce, 0)
+    }
+
+    pub fn seal(&mut self, msg: &Message) -> Result<Message, FrameError> {
+        if self.seq == std::u64::MAX {
+            return Result::Err(FrameError::Exhausted);
+        }
+        let seq = self.seq;
+        self.seq += 1;
+
+        let mut out: Vec<u8> = Vec::with_capacity(8 + 2 + msg.payload.len() + 32);
+        out.write_u64::<LittleEndian>(seq).unwrap();
+        out.write_u16::<LittleEndian>(msg.kind).unwrap();
+        out.extend(msg.payload.iter().cloned());
+        if self.encrypt && !self.cipher(seq).apply(&mut out[8..]) {
+            return Result::Err(FrameError::Exhausted);
+        }
+        let tag = hmac_sha256(&self.mac, &out);
+        out.extend(tag.iter().cloned());
+        Result::Ok(Message::new(KIND_SEALED, out))
+    }
+
+    pub fn open(&mut self, msg: &Message) -> Result<Message, FrameError> {
+        if msg.kind != KIND_SEALED || msg.payload.len() < 8 + 2 + 32 {
+            return Result::Err(FrameError::Tampered);
+        }
+        let (body, tag) = msg.payload.split_at(msg.payload.len() - 32);
+        if !ct_eq(&hmac_sha256(&self.mac, body), tag) {
+            return Result::Err(FrameError::Tampered);
+        }
+        let seq = (&body[0..8]).read_u64::<LittleEndian>().unwrap();
+        if seq < self.seq {
+            return Result::Err(FrameError::Tampered);
+        }
+        if seq == std::u64::MAX {
+            return Result::Err(FrameError::Exhausted);
+        }
+        self.seq = seq + 1;
+
+        let mut inner = body[8..].to_vec();
+        if self.encrypt && !self.cipher(seq).apply(&mut inner) {
+            return Result::Err(FrameError::Exhausted);
+        }
+        let kind = (&inner[0..2]).read_u16::<LittleEndian>().unwrap();
+        Result::Ok(Message::new(kind, inner[2..].to_vec()))
+    }
+}
+
+///! The outcome of a handshake. Sessions that authenticated seal their
+///! messages both ways.
+pub struct Session {
+    pub permission: Permission,
+    pub send:       Option<Seal>,
+    pub recv:       Option<Seal>,
+}
+
+impl Session {
+    ///! A session for servers without authentication.
+    pub fn open() -> Session {
+        Session { permission: Permission::Control, send: Option::None, recv: Option::None }
+    }
+}
+
+///! Read one message straight from the stream.
+pub fn read_msg(stream: &mut TcpStream, frames: &mut FrameReader) -> Result<Message, FrameError> {
+    let mut buf = [0u8; 512];
+    loop {
+        match try!(frames.next()) {
+            Option::Some(msg) => return Result::Ok(msg),
+            Option::None => (),
+        }
+        let n = try!(stream.read(&mut buf));
+        if n == 0 {
+            return Result::Err(FrameError::Closed);
+        }
+        frames.push(&buf[0..n]);
+    }
+}
+
+fn mac(key: &[u8], label: &[u8], a: &[u8], b: &[u8], name: &[u8]) -> [u8; 32] {
+    let mut data: Vec<u8> = label.to_vec();
+    data.extend(a.iter().cloned());
+    data.extend(b.iter().cloned());
+    data.extend(name.iter().cloned());
+    hmac_sha256(key, &data)
+}
+
+///! The name followed by the permission granted to it, as covered by the
+///! HMAC of the server.
+fn granted(name: &[u8], permission: u8) -> Vec<u8> {
+    let mut out = name.to_vec();
+    out.push(permission);
+    out
+}
+
+///! Return the seals for messages from the client and from the server.
+fn seals(key: &[u8], challenge: &[u8], cn: &[u8]) -> (Seal, Seal) {
+    // The challenge ends with whether to encrypt, so a changed flag leads
+    // to keys that do not match.
+    let encrypt = challenge[NONCE_LEN] != 0;
+    (Seal::new(key, b"c2s", challenge, cn, encrypt), Seal::new(key, b"s2c", challenge, cn, encrypt))
+}
+
+fn nonce() -> Result<Vec<u8>, FrameError> {
+    Result::Ok(try!(random_bytes(NONCE_LEN)))
+}
+
+fn refuse(stream: &mut TcpStream) -> FrameError {
+    let _ = stream.write_all(&Message::new(KIND_REFUSE, b"authentication failed".to_vec()).encode());
+    FrameError::Denied
+}
+
+///! Run the server side once the hellos have been exchanged.
+pub fn server_handshake(stream: &mut TcpStream, frames: &mut FrameReader, cfg: &AuthConfig) -> Result<Session, FrameError> {
+    let mut challenge = try!(nonce());
+    challenge.push(cfg.encrypt as u8);
+    try!(stream.write_all(&Message::new(KIND_CHALLENGE, challenge.clone()).encode()));
+
+    let msg = try!(read_msg(stream, frames));
+    if msg.kind != KIND_RESPONSE {
+        return Result::Err(refuse(stream));
+    }
+    let mut buf = &msg.payload[..];
+    let name = match get_bytes(&mut buf) {
+        Option::Some(name) => name,
+        Option::None => return Result::Err(refuse(stream)),
+    };
+    if buf.len() != NONCE_LEN + 32 {
+        return Result::Err(refuse(stream));
+    }
+    let cn = &buf[0..NONCE_LEN];
+    let theirs = &buf[NONCE_LEN..];
+
+    let (key, permission) = match cfg.keys.get(&String::from_utf8_lossy(&name).into_owned()) {
+        Option::Some(&(ref key, permission)) => (key.clone(), permission),
+        Option::None => return Result::Err(refuse(stream)),
+    };
+    if !ct_eq(&mac(&key, b"muds-client", &challenge, cn, &name), theirs) {
+        return Result::Err(refuse(stream));
+    }
+
+    let mut accept: Vec<u8> = vec![permission.to_u8()];
+    accept.extend(mac(&key, b"muds-server", cn, &challenge, &granted(&name, permission.to_u8())).iter().cloned());
+    try!(stream.write_all(&Message::new(KIND_ACCEPT, accept).encode()));
+
+    let (c2s, s2c) = seals(&key, &challenge, cn);
+    Result::Ok(Session { permission: permission, send: Option::Some(s2c), recv: Option::Some(c2s) })
+}
+
+///! Run the client side once the hellos have been exchanged.
+pub fn client_handshake(stream: &mut TcpStream, frames: &mut FrameReader, creds: &Credentials) -> Result<Session, FrameError> {
+    let msg = try!(read_msg(stream, frames));
+    if msg.kind != KIND_CHALLENGE || msg.payload.len() != NONCE_LEN + 1 {
+        return Result::Err(FrameError::Denied);
+    }
+    let challenge = &msg.payload[..];
+
+    let cn = try!(nonce());
+    let name = creds.name.as_bytes();
+    let mut response: Vec<u8> = Vec::new();
+    put_bytes(&mut response, name);
+    response.extend(cn.iter().cloned());
+    response.extend(mac(&creds.key, b"muds-client", challenge, &cn, name).iter().cloned());
+    try!(stream.write_all(&Message::new(KIND_RESPONSE, response).encode()));
+
+    let accept = try!(read_msg(stream, frames));
+    if accept.kind != KIND_ACCEPT || accept.payload.len() != 33 {
+        return Result::Err(FrameError::Denied);
+    }
+    // Make sure the server holds the key too before trusting it, and that
+    // the permission is the one it granted.
+    if !ct_eq(&mac(&creds.key, b"muds-server", &cn, challenge, &granted(name, accept.payload[0])), &accept.payload[1..]) {
+        return Result::Err(FrameError::Denied);
+    }
+
+    let (c2s, s2c) = seals(&creds.key, challenge, &cn);
+    Result::Ok(Session { permission: Permission::from_u8(accept.payload[0]), send: Option::Some(c2s), recv: Option::Some(s2c) })
+}
+
+#[test]
+fn test_auth() {
+    use super::{Server, ServerConfig, ControlInfo, Connection};
+
+    let mut auth = AuthConfig::new();
+    auth.add_key("ops", b"control secret", Permission::Control);
+    auth.add_key("viewer", b"listen secret", Permission::Listen);
+    auth.encrypt = true;
+    let mut cfg = ServerConfig::new();
+    cfg.auth = Option::Some(auth);
+    let server = Server::new_with_config("127.0.0.1:0", cfg).unwrap();
+    let addr = server.local_addr();
+
+    assert!(Connection::connect(&addr).is_err());
+    assert!(Connection::connect_with_key(&addr, &Credentials::new("ops", b"wrong")).is_err());
+    assert!(Connection::connect_with_key(&addr, &Credentials::new("nobody", b"control secret")).is_err());
+
+    let viewer = Connection::connect_with_key(&addr, &Credentials::new("viewer", b"listen secret")).unwrap();
+    assert_eq!(viewer.permission(), Permission::Listen);
+    match server.read().unwrap() {
+        ControlInfo::ClientHello { client, .. } => assert_eq!(client.lock().unwrap().permission(), Permission::Listen),
+        _ => panic!("expected a hello"),
+    }
+
+    let mut ops = Connection::connect_with_key(&addr, &Credentials::new("ops", b"control secret")).unwrap();
+    assert_eq!(ops.permission(), Permission::Control);
+    assert!(ops.try_clone().is_err());
+    let luid = match server.read().unwrap() {
+        ControlInfo::ClientHello { luid, client } => {
+            assert_eq!(client.lock().unwrap().permission(), Permission::Control);
+            luid
+        },
+        _ => panic!("expected a hello"),
+    };
+
+    ops.send_msg(&Message::new(7, b"tune".to_vec())).unwrap();
+    loop {
+        match server.read().unwrap() {
+            ControlInfo::ClientData { luid: from, client } => {
+                assert_eq!(from, luid);
+                match client.lock().unwrap().recv_msg().unwrap() {
+                    Option::Some(msg) => {
+                        assert_eq!(msg, Message::new(7, b"tune".to_vec()));
+                        break;
+                    },
+                    Option::None => (),
+                }
+            },
+            _ => (),
+        }
+    }
+    server.send_msg(luid, &Message::new(8, b"done".to_vec())).unwrap();
+    assert_eq!(ops.recv_msg().unwrap(), Message::new(8, b"done".to_vec()));
+    drop(viewer);
+
+    // A connection still authenticating takes up a place.
+    let mut auth = AuthConfig::new();
+    auth.add_key("ops", b"control secret", Permission::Control);
+    let mut cfg = ServerConfig::new();
+    cfg.max_clients = Option::Some(1);
+    cfg.auth = Option::Some(auth);
+    let server = Server::new_with_config("127.0.0.1:0", cfg).unwrap();
+    let addr = server.local_addr();
+    let silent = TcpStream::connect(&addr).unwrap();
+    let creds = Credentials::new("ops", b"control secret");
+    assert!(Connection::connect_with_key(&addr, &creds).is_err());
+    drop(silent);
+    let mut tries = 0;
+    while Connection::connect_with_key(&addr, &creds).is_err() {
+        tries += 1;
+        assert!(tries < 50);
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn test_auth_permission() {
+    use super::{Server, ServerConfig, Connection};
+    use std::net::TcpListener;
+    use std::thread;
+
+    let mut auth = AuthConfig::new();
+    auth.add_key("viewer", b"listen secret", Permission::Listen);
+    let mut cfg = ServerConfig::new();
+    cfg.auth = Option::Some(auth);
+    let server = Server::new_with_config("127.0.0.1:0", cfg).unwrap();
+    let addr = server.local_addr();
+
+    // Sits between the two and turns the permission granted into Control.
+    let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+    let paddr = proxy.local_addr().unwrap();
+    thread::spawn(move || {
+        let client = proxy.accept().unwrap().0;
+        let upstream = TcpStream::connect(&addr).unwrap();
+        let mut up_w = upstream.try_clone().unwrap();
+        let mut client_r = client.try_clone().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            loop {
+                match client_r.read(&mut buf) {
+                    Result::Ok(0) | Result::Err(_) => return,
+                    Result::Ok(n) => if up_w.write_all(&buf[0..n]).is_err() { return },
+                }
+            }
+        });
+        let mut up_r = upstream;
+        let mut client_w = client;
+        let mut frames = FrameReader::new();
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = match up_r.read(&mut buf) {
+                Result::Ok(0) | Result::Err(_) => return,
+                Result::Ok(n) => n,
+            };
+            frames.push(&buf[0..n]);
+            while let Result::Ok(Option::Some(mut msg)) = frames.next() {
+                if msg.kind == KIND_ACCEPT {
+                    msg.payload[0] = Permission::Control.to_u8();
+                }
+                if client_w.write_all(&msg.encode()).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    match Connection::connect_with_key(&paddr, &Credentials::new("viewer", b"listen secret")) {
+        Result::Err(FrameError::Denied) => (),
+        _ => panic!("expected the changed permission to be refused"),
+    }
+    // Without the change the same key gets in.
+    let viewer = Connection::connect_with_key(&addr, &Credentials::new("viewer", b"listen secret")).unwrap();
+    assert_eq!(viewer.permission(), Permission::Listen);
+}
+
+#[test]
+fn test_seal() {
+    let challenge = [1u8; NONCE_LEN + 1];
+    let (mut send, _) = seals(b"secret", &challenge, &[2u8; NONCE_LEN]);
+    let (mut recv, _) = seals(b"secret", &challenge, &[2u8; NONCE_LEN]);
+    let tune = Message::new(7, b"tune 146520000".to_vec());
+
+    let first = send.seal(&tune).unwrap();
+    assert!(first.payload.windows(4).all(|w| w != b"tune"));
+    let mut flipped = first.clone();
+    flipped.payload[12] ^= 1;
+    assert!(recv.open(&flipped).is_err());
+    assert_eq!(recv.open(&first).unwrap(), tune);
+    // A replay is refused, a gap left by a dropped message is not.
+    assert!(recv.open(&first).is_err());
+    send.seal(&tune).unwrap();
+    assert_eq!(recv.open(&send.seal(&tune).unwrap()).unwrap(), tune);
+    // Neither is anything sent without a seal or under other keys.
+    assert!(recv.open(&tune).is_err());
+    let (mut other, _) = seals(b"guess", &challenge, &[2u8; NONCE_LEN]);
+    assert!(recv.open(&other.seal(&tune).unwrap()).is_err());
+}
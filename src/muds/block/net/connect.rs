@@ -0,0 +1,112 @@
+///! The client end of a net connection speaking framed messages.
+
+use super::frame::{Message, FrameReader, FrameError, check_hello, hello_flags, HELLO_AUTH};
+use super::auth::{Credentials, Permission, Session, Seal, client_handshake};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::io::{Read, Write};
+
+pub struct Connection {
+    stream:     TcpStream,
+    frames:     FrameReader,
+    version:    u16,
+    permission: Permission,
+    ///! Set when the session is authenticated.
+    send:       Option<Seal>,
+    recv:       Option<Seal>,
+}
+
+impl Connection {
+    ///! Connect to a `Server` and exchange hellos. Fails with
+    ///! `FrameError::Denied` if the server wants clients to authenticate.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Connection, FrameError> {
+        Connection::connect_with(addr, Option::None)
+    }
+
+    ///! Connect to a `Server` that wants clients to authenticate. Fails with
+    ///! `FrameError::Denied` if the server does not, since it may not be the
+    ///! server it claims to be.
+    pub fn connect_with_key<A: ToSocketAddrs>(addr: A, creds: &Credentials) -> Result<Connection, FrameError> {
+        Connection::connect_with(addr, Option::Some(creds))
+    }
+
+    fn connect_with<A: ToSocketAddrs>(addr: A, creds: Option<&Credentials>) -> Result<Connection, FrameError> {
+        let stream = try!(TcpStream::connect(addr));
+        let mut conn = Connection {
+            stream:     stream,
+            frames:     FrameReader::new(),
+            version:    0,
+            permission: Permission::Control,
+            send:       Option::None,
+            recv:       Option::None,
+        };
+        try!(conn.send_msg(&Message::hello()));
+        let hello = try!(conn.recv_msg());
+        conn.version = try!(check_hello(&hello));
+
+        let session = match (hello_flags(&hello) & HELLO_AUTH != 0, creds) {
+            (true, Option::Some(creds)) => try!(client_handshake(&mut conn.stream, &mut conn.frames, creds)),
+            (false, Option::None) => Session::open(),
+            _ => return Result::Err(FrameError::Denied),
+        };
+        conn.permission = session.permission;
+        conn.send = session.send;
+        conn.recv = session.recv;
+        Result::Ok(conn)
+    }
+
+    ///! The protocol version of the server.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    ///! What the server lets this connection do.
+    pub fn permission(&self) -> Permission {
+        self.permission
+    }
+
+    pub fn send_msg(&mut self, msg: &Message) -> Result<(), FrameError> {
+        let buf = match self.send {
+            Option::Some(ref mut seal) => try!(seal.seal(msg)).encode(),
+            Option::None => msg.encode(),
+        };
+        try!(self.stream.write_all(&buf));
+        Result::Ok(())
+    }
+
+    ///! Block until the next message arrives.
+    pub fn recv_msg(&mut self) -> Result<Message, FrameError> {
+        let mut buf = [0u8; 2048];
+        loop {
+            match try!(self.frames.next()) {
+                Option::Some(msg) => match self.recv {
+                    Option::Some(ref mut seal) => return seal.open(&msg),
+                    Option::None => return Result::Ok(msg),
+                },
+                Option::None => (),
+            }
+            let n = try!(self.stream.read(&mut buf));
+            if n == 0 {
+                return Result::Err(FrameError::Closed);
+            }
+            self.frames.push(&buf[0..n]);
+        }
+    }
+
+    ///! Return a handle to the same connection, for instance to read on one
+    ///! thread while writing on another. Messages partly read are not shared.
+    ///! Authenticated connections can not be cloned since both handles would
+    ///! reuse the same sequence numbers.
+    pub fn try_clone(&self) -> Result<Connection, FrameError> {
+        if self.send.is_some() {
+            return Result::Err(FrameError::Io(::std::io::Error::new(::std::io::ErrorKind::Other, "authenticated connections can not be cloned")));
+        }
+        Result::Ok(Connection {
+            stream:     try!(self.stream.try_clone()),
+            frames:     FrameReader::new(),
+            version:    self.version,
+            permission: self.permission,
+            send:       Option::None,
+            recv:       Option::None,
+        })
+    }
+}
@@ -0,0 +1,241 @@
+///! The primitives behind authenticated sessions: SHA-256, HMAC-SHA-256
+///! and the ChaCha20 stream cipher, written out here so the crate needs no
+///! further dependencies.
+
+use std::fs::File;
+use std::io::Read;
+use std;
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+    ];
+
+    let mut msg = data.to_vec();
+    let bits = (data.len() as u64) * 8;
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    for x in 0..8 {
+        msg.push((bits >> (56 - x * 8)) as u8);
+    }
+
+    let mut w = [0u32; 64];
+    for block in msg.chunks(64) {
+        for t in 0..16 {
+            w[t] = (block[t * 4] as u32) << 24 | (block[t * 4 + 1] as u32) << 16 |
+                   (block[t * 4 + 2] as u32) << 8 | block[t * 4 + 3] as u32;
+        }
+        for t in 16..64 {
+            let s0 = w[t - 15].rotate_right(7) ^ w[t - 15].rotate_right(18) ^ (w[t - 15] >> 3);
+            let s1 = w[t - 2].rotate_right(17) ^ w[t - 2].rotate_right(19) ^ (w[t - 2] >> 10);
+            w[t] = w[t - 16].wrapping_add(s0).wrapping_add(w[t - 7]).wrapping_add(s1);
+        }
+
+        let mut v = h;
+        for t in 0..64 {
+            let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+            let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+            let t1 = v[7].wrapping_add(s1).wrapping_add(ch).wrapping_add(K[t]).wrapping_add(w[t]);
+            let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+            let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+            let t2 = s0.wrapping_add(maj);
+            v[7] = v[6];
+            v[6] = v[5];
+            v[5] = v[4];
+            v[4] = v[3].wrapping_add(t1);
+            v[3] = v[2];
+            v[2] = v[1];
+            v[1] = v[0];
+            v[0] = t1.wrapping_add(t2);
+        }
+        for x in 0..8 {
+            h[x] = h[x].wrapping_add(v[x]);
+        }
+    }
+
+    let mut out = [0u8; 32];
+    for x in 0..8 {
+        out[x * 4] = (h[x] >> 24) as u8;
+        out[x * 4 + 1] = (h[x] >> 16) as u8;
+        out[x * 4 + 2] = (h[x] >> 8) as u8;
+        out[x * 4 + 3] = h[x] as u8;
+    }
+    out
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; 64];
+    if key.len() > 64 {
+        let digest = sha256(key);
+        for x in 0..32 {
+            block[x] = digest[x];
+        }
+    } else {
+        for x in 0..key.len() {
+            block[x] = key[x];
+        }
+    }
+
+    let mut inner: Vec<u8> = block.iter().map(|b| b ^ 0x36).collect();
+    inner.extend(data.iter().cloned());
+    let mut outer: Vec<u8> = block.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend(sha256(&inner).iter().cloned());
+    sha256(&outer)
+}
+
+///! Compare without stopping at the first difference so the time taken
+///! says nothing about where a guess went wrong.
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut diff = 0u8;
+    for x in 0..a.len() {
+        diff |= a[x] ^ b[x];
+    }
+    diff == 0
+}
+
+///! Return `n` bytes from the system random source.
+pub fn random_bytes(n: usize) -> std::io::Result<Vec<u8>> {
+    let mut fp = try!(File::open("/dev/urandom"));
+    let mut out = vec![0u8; n];
+    let mut got = 0;
+    while got < n {
+        let r = try!(fp.read(&mut out[got..]));
+        if r == 0 {
+            return Result::Err(std::io::Error::new(std::io::ErrorKind::Other, "random source ran dry"));
+        }
+        got += r;
+    }
+    Result::Ok(out)
+}
+
+fn quarter(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]); s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]); s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]); s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]); s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+fn le32(b: &[u8]) -> u32 {
+    b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24
+}
+
+///! The ChaCha20 stream cipher of RFC 7539. Encrypting and decrypting are
+///! the same operation. The key stream ends once the 32 bit block counter
+///! would wrap, rather than starting over.
+pub struct ChaCha20 {
+    state:      [u32; 16],
+    block:      [u8; 64],
+    ///! The bytes of `block` already used.
+    used:       usize,
+    ///! Set once the block for the last counter value has been made.
+    exhausted:  bool,
+}
+
+impl ChaCha20 {
+    pub fn new(key: &[u8; 32], nonce: &[u8; 12], counter: u32) -> ChaCha20 {
+        let mut state = [0u32; 16];
+        state[0] = 0x61707865;
+        state[1] = 0x3320646e;
+        state[2] = 0x79622d32;
+        state[3] = 0x6b206574;
+        for x in 0..8 {
+            state[4 + x] = le32(&key[x * 4..]);
+        }
+        state[12] = counter;
+        for x in 0..3 {
+            state[13 + x] = le32(&nonce[x * 4..]);
+        }
+        ChaCha20 { state: state, block: [0u8; 64], used: 64, exhausted: false }
+    }
+
+    fn refill(&mut self) {
+        let mut s = self.state;
+        for _ in 0..10 {
+            quarter(&mut s, 0, 4, 8, 12);
+            quarter(&mut s, 1, 5, 9, 13);
+            quarter(&mut s, 2, 6, 10, 14);
+            quarter(&mut s, 3, 7, 11, 15);
+            quarter(&mut s, 0, 5, 10, 15);
+            quarter(&mut s, 1, 6, 11, 12);
+            quarter(&mut s, 2, 7, 8, 13);
+            quarter(&mut s, 3, 4, 9, 14);
+        }
+        for x in 0..16 {
+            let v = s[x].wrapping_add(self.state[x]);
+            self.block[x * 4] = v as u8;
+            self.block[x * 4 + 1] = (v >> 8) as u8;
+            self.block[x * 4 + 2] = (v >> 16) as u8;
+            self.block[x * 4 + 3] = (v >> 24) as u8;
+        }
+        if self.state[12] == std::u32::MAX {
+            self.exhausted = true;
+        } else {
+            self.state[12] += 1;
+        }
+        self.used = 0;
+    }
+
+    ///! XOR the key stream into `buf`. Returns false, leaving the rest of
+    ///! `buf` as it was, if the key stream ran out on the way.
+    pub fn apply(&mut self, buf: &mut [u8]) -> bool {
+        for b in buf.iter_mut() {
+            if self.used == 64 {
+                if self.exhausted {
+                    return false;
+                }
+                self.refill();
+            }
+            *b ^= self.block[self.used];
+            self.used += 1;
+        }
+        true
+    }
+}
+
+#[test]
+fn test_crypto() {
+    fn hex(b: &[u8]) -> String {
+        b.iter().map(|v| format!("{:02x}", v)).collect()
+    }
+
+    assert_eq!(hex(&sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    // RFC 4231, test case 2.
+    assert_eq!(
+        hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+        "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+    );
+
+    // RFC 7539, section 2.4.2.
+    let mut key = [0u8; 32];
+    for x in 0..32 {
+        key[x] = x as u8;
+    }
+    let nonce = [0, 0, 0, 0, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+    let mut buf = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.".to_vec();
+    assert!(ChaCha20::new(&key, &nonce, 1).apply(&mut buf));
+    assert_eq!(hex(&buf[0..16]), "6e2e359a2568f98041ba0728dd0d6981");
+    assert_eq!(hex(&buf[buf.len() - 4..]), "5e42874d");
+    assert!(ChaCha20::new(&key, &nonce, 1).apply(&mut buf));
+    assert_eq!(&buf[0..6], b"Ladies");
+
+    // The last block may be used but not the one after it.
+    let mut last = ChaCha20::new(&key, &nonce, std::u32::MAX);
+    assert!(last.apply(&mut [0u8; 64]));
+    assert!(!last.apply(&mut [0u8; 1]));
+}
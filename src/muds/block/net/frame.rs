@@ -0,0 +1,269 @@
+///! Length prefixed messages carried over a net connection.
+///!
+///! Each message is a little endian `u32` length, covering the kind and the
+///! payload, followed by a little endian `u16` kind and the payload. Both
+///! ends open with a `KIND_HELLO` message naming the protocol version so a
+///! mismatch is caught before anything else is exchanged.
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+use std::fmt;
+use std::error::Error;
+use std;
+
+///! The protocol version sent in the hello.
+pub const VERSION: u16 = 1;
+
+///! The kind of the hello message. Other protocols use kinds above this.
+pub const KIND_HELLO: u16 = 0;
+
+///! Messages larger than this are refused rather than buffered.
+pub const MAX_MESSAGE: usize = 1024 * 1024 * 16;
+
+///! Set in the hello of a server that wants clients to authenticate.
+pub const HELLO_AUTH: u8 = 1;
+
+const MAGIC: &'static [u8] = b"MUDS";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub kind:       u16,
+    pub payload:    Vec<u8>,
+}
+
+impl Message {
+    pub fn new(kind: u16, payload: Vec<u8>) -> Message {
+        Message { kind: kind, payload: payload }
+    }
+
+    ///! The hello sent first by both ends.
+    pub fn hello() -> Message {
+        Message::hello_with_flags(0)
+    }
+
+    ///! A hello that also carries `HELLO_AUTH` or other flags.
+    pub fn hello_with_flags(flags: u8) -> Message {
+        let mut payload = MAGIC.to_vec();
+        payload.write_u16::<LittleEndian>(VERSION).unwrap();
+        payload.push(flags);
+        Message::new(KIND_HELLO, payload)
+    }
+
+    ///! Return the message as it is sent.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out: Vec<u8> = Vec::with_capacity(6 + self.payload.len());
+        out.write_u32::<LittleEndian>(self.payload.len() as u32 + 2).unwrap();
+        out.write_u16::<LittleEndian>(self.kind).unwrap();
+        out.extend(self.payload.iter().cloned());
+        out
+    }
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    Io(std::io::Error),
+    ///! A message announced a length above `MAX_MESSAGE` or below the
+    ///! size of its kind.
+    BadLength(usize),
+    ///! The first message was not a hello.
+    BadHello,
+    ///! The other end speaks a different protocol version.
+    Version(u16),
+    ///! The connection was closed.
+    Closed,
+    ///! Authentication failed or was needed but not offered.
+    Denied,
+    ///! A message of an authenticated session was altered, replayed or
+    ///! sent without its seal.
+    Tampered,
+    ///! The session sent so much that its keys must not be used further.
+    Exhausted,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FrameError::Io(ref err) => write!(f, "{}: {}", self.description(), err),
+            FrameError::BadLength(n) => write!(f, "{}: {}", self.description(), n),
+            FrameError::Version(v) => write!(f, "{}: {} but {} expected", self.description(), v, VERSION),
+            _ => write!(f, "{}", self.description()),
+        }
+    }
+}
+
+impl Error for FrameError {
+    fn description(&self) -> &str {
+        match *self {
+            FrameError::Io(_) => "I/O error",
+            FrameError::BadLength(_) => "bad message length",
+            FrameError::BadHello => "bad hello",
+            FrameError::Version(_) => "unsupported protocol version",
+            FrameError::Closed => "connection closed",
+            FrameError::Denied => "authentication failed",
+            FrameError::Tampered => "message failed authentication",
+            FrameError::Exhausted => "session keys exhausted",
+        }
+    }
+}
+
+impl From<std::io::Error> for FrameError {
+    fn from(err: std::io::Error) -> FrameError {
+        FrameError::Io(err)
+    }
+}
+
+///! Check that `msg` is a hello of our version and return its version.
+pub fn check_hello(msg: &Message) -> Result<u16, FrameError> {
+    if msg.kind != KIND_HELLO || msg.payload.len() < MAGIC.len() + 2 || &msg.payload[0..MAGIC.len()] != MAGIC {
+        return Result::Err(FrameError::BadHello);
+    }
+    let version = (&msg.payload[MAGIC.len()..]).read_u16::<LittleEndian>().unwrap();
+    if version != VERSION {
+        return Result::Err(FrameError::Version(version));
+    }
+    Result::Ok(version)
+}
+
+///! Return the flags of a hello checked by `check_hello`.
+pub fn hello_flags(msg: &Message) -> u8 {
+    match msg.payload.get(MAGIC.len() + 2) {
+        Option::Some(flags) => *flags,
+        Option::None => 0,
+    }
+}
+
+///! Collects bytes as they arrive and splits them into messages.
+pub struct FrameReader {
+    buf:        Vec<u8>,
+    ///! Where the first message not yet taken starts in `buf`.
+    pos:        usize,
+}
+
+impl FrameReader {
+    pub fn new() -> FrameReader {
+        FrameReader { buf: Vec::new(), pos: 0 }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        // Move what is left to the front once most of the buffer has been
+        // taken, so each byte is only moved a few times.
+        if self.pos > 0 && self.pos >= self.buf.len() / 2 {
+            let rest = self.buf[self.pos..].to_vec();
+            self.buf = rest;
+            self.pos = 0;
+        }
+        self.buf.extend(data.iter().cloned());
+    }
+
+    ///! Return the next complete message if one has arrived.
+    pub fn next(&mut self) -> Result<Option<Message>, FrameError> {
+        let buf = &self.buf[self.pos..];
+        if buf.len() < 6 {
+            return Result::Ok(Option::None);
+        }
+        let len = (&buf[0..4]).read_u32::<LittleEndian>().unwrap() as usize;
+        if len < 2 || len > MAX_MESSAGE {
+            return Result::Err(FrameError::BadLength(len));
+        }
+        if buf.len() < 4 + len {
+            return Result::Ok(Option::None);
+        }
+        let kind = (&buf[4..6]).read_u16::<LittleEndian>().unwrap();
+        let payload = buf[6..4 + len].to_vec();
+        self.pos += 4 + len;
+        Result::Ok(Option::Some(Message::new(kind, payload)))
+    }
+
+    ///! Remove and return the bytes not yet taken as messages.
+    pub fn take(&mut self) -> Vec<u8> {
+        let rest = self.buf[self.pos..].to_vec();
+        self.buf = Vec::new();
+        self.pos = 0;
+        rest
+    }
+}
+
+///! Append `data` to `out` preceded by its length as a little endian `u16`.
+pub fn put_bytes(out: &mut Vec<u8>, data: &[u8]) {
+    out.write_u16::<LittleEndian>(data.len() as u16).unwrap();
+    out.extend(data.iter().cloned());
+}
+
+///! Take bytes written by `put_bytes` from the front of `buf`.
+pub fn get_bytes(buf: &mut &[u8]) -> Option<Vec<u8>> {
+    let len = match buf.read_u16::<LittleEndian>() {
+        Result::Ok(len) => len as usize,
+        Result::Err(_) => return Option::None,
+    };
+    if buf.len() < len {
+        return Option::None;
+    }
+    let out = buf[0..len].to_vec();
+    *buf = &buf[len..];
+    Option::Some(out)
+}
+
+#[test]
+fn test_frame_roundtrip() {
+    use super::{Server, ControlInfo, Connection};
+    use std::thread;
+
+    let server = Server::new("127.0.0.1:0").unwrap();
+    let addr = server.local_addr();
+
+    let th = thread::spawn(move || {
+        let mut conn = Connection::connect(&addr).unwrap();
+        conn.send_msg(&Message::new(7, b"ping".to_vec())).unwrap();
+        conn.send_msg(&Message::new(8, vec![0u8; 5000])).unwrap();
+        conn.recv_msg().unwrap()
+    });
+
+    let mut got: Vec<Message> = Vec::new();
+    while got.len() < 2 {
+        match server.read().unwrap() {
+            ControlInfo::ClientData { luid, client } => {
+                loop {
+                    match client.lock().unwrap().recv_msg().unwrap() {
+                        Option::Some(msg) => got.push(msg),
+                        Option::None => break,
+                    }
+                }
+                if got.len() == 2 {
+                    server.send_msg(luid, &Message::new(9, b"pong".to_vec())).unwrap();
+                }
+            },
+            _ => (),
+        }
+    }
+
+    assert_eq!(got[0], Message::new(7, b"ping".to_vec()));
+    assert_eq!(got[1].kind, 8);
+    assert_eq!(got[1].payload.len(), 5000);
+    assert_eq!(th.join().unwrap(), Message::new(9, b"pong".to_vec()));
+}
+
+#[test]
+fn test_frame_reader() {
+    let mut stream: Vec<u8> = Vec::new();
+    for x in 0..1000 {
+        stream.extend(Message::new(x as u16, vec![x as u8; x % 7]).encode().into_iter());
+    }
+    let mut reader = FrameReader::new();
+    let mut got = 0;
+    // Arriving in pieces that split messages.
+    for piece in stream.chunks(5) {
+        reader.push(piece);
+        loop {
+            let msg = match reader.next().unwrap() {
+                Option::Some(msg) => msg,
+                Option::None => break,
+            };
+            assert_eq!(msg.kind, got as u16);
+            assert_eq!(msg.payload, vec![got as u8; got % 7]);
+            got += 1;
+        }
+    }
+    assert_eq!(got, 1000);
+    assert_eq!(reader.take().len(), 0);
+    // The buffer is compacted as messages are taken.
+    assert!(reader.buf.len() < 64);
+}
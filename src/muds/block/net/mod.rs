@@ -0,0 +1,543 @@
+mod frame;
+mod connect;
+mod crypto;
+mod auth;
+mod reactor;
+mod queue;
+
+pub use self::frame::{Message, FrameReader, FrameError, VERSION, KIND_HELLO, HELLO_AUTH, MAX_MESSAGE, put_bytes, get_bytes};
+pub use self::connect::Connection;
+pub use self::auth::{AuthConfig, Credentials, Permission};
+pub use self::queue::Queue;
+
+use self::auth::{Session, Seal};
+use self::reactor::{Reactor, Waker, Wake};
+use std::os::unix::io::AsRawFd;
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::net::{TcpListener, TcpStream, SocketAddr, Shutdown};
+use std::thread;      
+use std::time::Duration;
+use std::collections::{VecDeque, HashMap};
+use std::sync::mpsc::{Sender, Receiver, channel, RecvError};
+use std;
+
+use std::io::{Read, Write};
+
+pub struct Client {
+    stream:      TcpStream,
+    luid:        u64,
+    /// What has been read, each chunk marked if it is a whole message that
+    /// may be dropped.
+    buffer:      VecDeque<(Vec<u8>, bool)>,
+    ctrltx:      Sender<ControlInfo>,
+    waitsz:      usize,
+    waitlimit:   usize,
+    policy:      Backpressure,
+    /// The bytes dropped because the buffer was full.
+    dropped:     u64,
+    /// Tells the reactor to read again once there is room.
+    waker:       Arc<Waker>,
+    frames:      FrameReader,
+    /// Set once the hello of the client has been read.
+    version:     Option<u16>,
+    permission:  Permission,
+    writer:      Arc<ClientWriter>,
+    /// Set when the session is authenticated.
+    open:        Option<Seal>,
+}
+
+/// The sending half of a client.
+///
+/// The reactor locks the client to hand over what it read, so a thread
+/// that holds the client locked while a peer that stopped reading blocks
+/// its write would hold up every client. Threads that may write for long
+/// should take the writer with `Client::writer` and let go of the client.
+pub struct ClientWriter {
+    /// The stream, and the seal if the session is authenticated.
+    inner:       Mutex<(TcpStream, Option<Seal>)>,
+}
+
+impl ClientWriter {
+    /// Write raw bytes. Authenticated sessions only take whole messages,
+    /// through `send_msg`.
+    pub fn write(&self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.1.is_some() {
+            return Result::Err(std::io::Error::new(std::io::ErrorKind::Other, "raw writes are not sealed"));
+        }
+        inner.0.write(buf)
+    }
+    
+    pub fn send_msg(&self, msg: &Message) -> std::io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let buf = match inner.1 {
+            Option::Some(ref mut seal) => match seal.seal(msg) {
+                Result::Ok(sealed) => sealed.encode(),
+                Result::Err(err) => return Result::Err(std::io::Error::new(std::io::ErrorKind::Other, err)),
+            },
+            Option::None => msg.encode(),
+        };
+        inner.0.write_all(&buf)
+    }
+}
+
+impl Client {
+    /// Write raw bytes, see `ClientWriter::write`.
+    pub fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.writer.write(buf)
+    }
+    /// Return raw bytes. For authenticated sessions these are the sealed
+    /// messages, which `recv_msg` opens.
+    pub fn read(&mut self) -> Option<Vec<u8>> {
+        let mut mustnotify = false;
+        if self.waitsz >= self.waitlimit {
+            mustnotify = true;
+        }
+        match self.buffer.pop_front() {
+            Option::Some((v, _)) => {
+                self.waitsz -= v.len();
+                if mustnotify {
+                    // Obviously, the wait is happening now or is 
+                    // about to happen.
+                    self.wake();
+                }
+                Option::Some(v)
+            },
+            Option::None => Option::None,
+        }
+    }
+    pub fn get_luid(&self) -> u64 {
+        self.luid
+    }
+    pub fn can_read(&self) -> bool {
+        self.buffer.len() > 0
+    }
+    /// The bytes sent by the client that were dropped for lack of room.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+    pub fn policy(&self) -> Backpressure {
+        self.policy
+    }
+    /// Change what happens once the buffer of this client is full.
+    pub fn set_policy(&mut self, policy: Backpressure) {
+        self.policy = policy;
+        // Reading may have stopped under the old policy.
+        self.wake();
+    }
+    /// What the client was allowed to do when it authenticated. Clients of
+    /// servers without authentication may do anything.
+    pub fn permission(&self) -> Permission {
+        self.permission
+    }
+    
+    /// Let the reactor read from this client again if it stopped for lack
+    /// of room.
+    fn wake(&self) {
+        self.waker.send(Wake::Resume(self.luid));
+    }
+    
+    /// Close the connection. The reactor notices, removes the client from
+    /// the server and reports `ClientBye`.
+    pub fn close(&mut self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+    
+    /// Make writes to this client fail after `secs` seconds instead of
+    /// waiting for a client that stopped reading. `None` waits forever.
+    pub fn set_write_timeout(&mut self, secs: Option<f64>) -> std::io::Result<()> {
+        self.stream.set_write_timeout(secs.map(|s| Duration::from_millis((s * 1000.0) as u64)))
+    }
+    
+    /// Send a message with the client locked. See `writer` for a way that
+    /// does not hold up the reactor.
+    pub fn send_msg(&mut self, msg: &Message) -> std::io::Result<()> {
+        self.writer.send_msg(msg)
+    }
+    
+    /// Return the sending half, to write without the client locked.
+    pub fn writer(&self) -> Arc<ClientWriter> {
+        self.writer.clone()
+    }
+    
+    /// Return the next complete message from the client. The hello the
+    /// client opens with is checked and not returned.
+    pub fn recv_msg(&mut self) -> Result<Option<Message>, FrameError> {
+        loop {
+            match self.read() {
+                Option::Some(chunk) => self.frames.push(&chunk),
+                Option::None => break,
+            }
+        }
+        loop {
+            let msg = match try!(self.frames.next()) {
+                Option::Some(msg) => msg,
+                Option::None => return Result::Ok(Option::None),
+            };
+            match self.version {
+                Option::Some(_) => (),
+                Option::None => {
+                    self.version = Option::Some(try!(frame::check_hello(&msg)));
+                    continue;
+                },
+            }
+            return match self.open {
+                Option::Some(ref mut seal) => seal.open(&msg).map(Option::Some),
+                Option::None => Result::Ok(Option::Some(msg)),
+            };
+        }
+    }
+}
+
+pub enum ControlInfo {
+    ClientHello { luid: u64, client: Arc<Mutex<Client>> },
+    ClientBye { luid: u64, client: Arc<Mutex<Client>> },
+    ClientData { luid: u64, client: Arc<Mutex<Client>> },
+    ClientFull { luid: u64, client: Arc<Mutex<Client>> },
+}
+
+/// What to do once a client has `waitlimit` bytes waiting to be read.
+///
+/// The dropping policies drop whole messages so that what is left still
+/// parses. They need the client to send framed messages, and fall back to
+/// blocking if it does not.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backpressure {
+    /// Stop reading from the client until there is room, which stalls the
+    /// sender. Right for control data.
+    Block,
+    /// Drop the oldest messages waiting to make room for new ones. Right
+    /// for live audio or IQ.
+    DropOldest,
+    /// Drop new messages until there is room.
+    DropNewest,
+    /// Block, but disconnect the client if there is still no room after
+    /// this many seconds.
+    DisconnectAfter(f64),
+}
+
+impl Backpressure {
+    /// True for the policies that drop messages.
+    pub fn drops(&self) -> bool {
+        match *self {
+            Backpressure::DropOldest | Backpressure::DropNewest => true,
+            _ => false,
+        }
+    }
+}
+
+/// Optional settings for the server.
+pub struct ServerConfig {
+    /// If set then connections beyond this many are closed at once.
+    pub max_clients:    Option<usize>,
+    /// The bytes buffered for a client before `policy` applies.
+    pub waitlimit:      usize,
+    /// The policy new clients start with.
+    pub policy:         Backpressure,
+    /// If set then clients must authenticate with one of its keys before
+    /// they are reported with `ClientHello`.
+    pub auth:           Option<AuthConfig>,
+}
+
+impl ServerConfig {
+    pub fn new() -> ServerConfig {
+        ServerConfig {
+            max_clients:    Option::None,
+            waitlimit:      1024 * 1024 * 16,
+            policy:         Backpressure::Block,
+            auth:           Option::None,
+        }
+    }
+}
+
+type Clients = Arc<Mutex<HashMap<u64, Arc<Mutex<Client>>>>>;
+
+/// Shuts down the server it was taken from with `Server::stop`. Once shut
+/// down `Server::read` returns an error after the last `ClientBye`.
+#[derive(Clone)]
+pub struct ServerStop {
+    strms:      Clients,
+    stopped:    Arc<AtomicBool>,
+    waker:      Arc<Waker>,
+}
+
+impl ServerStop {
+    /// Stop accepting connections and close every client.
+    pub fn shutdown(&self) {
+        if self.stopped.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        self.waker.send(Wake::Stop);
+        let clients: Vec<Arc<Mutex<Client>>> = self.strms.lock().unwrap().values().cloned().collect();
+        for client in clients {
+            client.lock().unwrap().close();
+        }
+    }
+}
+
+pub struct Server {
+    strms:      Clients,
+    ctrlrx:     Receiver<ControlInfo>,
+    addr:       SocketAddr,
+    stopped:    Arc<AtomicBool>,
+    waker:      Arc<Waker>,
+}
+
+impl Server {
+    pub fn write(&self, luid: u64, buf: &[u8]) -> std::io::Result<usize> {
+        match self.writer(luid) {
+            Option::Some(writer) => writer.write(buf),
+            Option::None => Result::Err(std::io::Error::new(std::io::ErrorKind::NotFound, "no such client")),
+        }
+    }
+    
+    pub fn read(&self) -> Result<ControlInfo, RecvError> {
+        self.ctrlrx.recv()
+    }        
+    
+    /// Return the client with `luid` if it is connected.
+    pub fn client(&self, luid: u64) -> Option<Arc<Mutex<Client>>> {
+        self.strms.lock().unwrap().get(&luid).map(|c| c.clone())
+    }
+    
+    /// Return the bytes dropped from the client `luid`.
+    pub fn dropped(&self, luid: u64) -> Option<u64> {
+        self.client(luid).map(|c| c.lock().unwrap().dropped())
+    }
+    
+    /// Return the luids of the connected clients.
+    pub fn clients(&self) -> Vec<u64> {
+        self.strms.lock().unwrap().keys().cloned().collect()
+    }
+    
+    pub fn send_msg(&self, luid: u64, msg: &Message) -> std::io::Result<()> {
+        match self.writer(luid) {
+            Option::Some(writer) => writer.send_msg(msg),
+            Option::None => Result::Err(std::io::Error::new(std::io::ErrorKind::NotFound, "no such client")),
+        }
+    }
+    
+    /// Return the sending half of the client `luid`, taken without holding
+    /// the client locked while writing.
+    pub fn writer(&self, luid: u64) -> Option<Arc<ClientWriter>> {
+        self.client(luid).map(|c| c.lock().unwrap().writer())
+    }
+    
+    /// Return the next complete message from the client `luid`.
+    pub fn recv_msg(&self, luid: u64) -> Result<Option<Message>, FrameError> {
+        match self.client(luid) {
+            Option::Some(client) => client.lock().unwrap().recv_msg(),
+            Option::None => Result::Err(FrameError::Closed),
+        }
+    }
+    
+    /// Return the address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+    
+    /// Close the connection of the client `luid`. Returns false if there
+    /// is no such client. A `ClientBye` follows once it is gone.
+    pub fn disconnect(&self, luid: u64) -> bool {
+        match self.client(luid) {
+            Option::Some(client) => {
+                client.lock().unwrap().close();
+                true
+            },
+            Option::None => false,
+        }
+    }
+    
+    /// Stop accepting connections and close every client.
+    pub fn shutdown(&self) {
+        self.stop().shutdown();
+    }
+
+    /// Return a handle that shuts the server down from another thread than
+    /// the one reading it.
+    pub fn stop(&self) -> ServerStop {
+        ServerStop {
+            strms:      self.strms.clone(),
+            stopped:    self.stopped.clone(),
+            waker:      self.waker.clone(),
+        }
+    }
+
+    pub fn new(addr: &str) -> Option<Server>  {
+        Server::new_with_config(addr, ServerConfig::new())
+    }
+    
+    /// Like `new` but with the optional settings in `cfg`.
+    pub fn new_with_config(addr: &str, mut cfg: ServerConfig) -> Option<Server>  {                    
+        let srv = match TcpListener::bind(addr) {
+            Result::Ok(srv) => srv,
+            Result::Err(_) => return Option::None,
+        };
+        let addr = match srv.local_addr() {
+            Result::Ok(addr) => addr,
+            Result::Err(_) => return Option::None,
+        };
+        let auth = cfg.auth.take().map(Arc::new);
+        let strms: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let (ctrltx, ctrlrx) = channel::<ControlInfo>();
+        let reactor = match Reactor::new(srv, strms.clone(), ctrltx, cfg.max_clients, cfg.waitlimit, cfg.policy, auth) {
+            Result::Ok(reactor) => reactor,
+            Result::Err(err) => {
+                println!("[net] unable to start the reactor: {}", err);
+                return Option::None;
+            },
+        };
+        let waker = reactor.waker();
+        thread::spawn(move || reactor.run());
+        Option::Some(Server {
+            strms:      strms,
+            ctrlrx:     ctrlrx,
+            addr:       addr,
+            stopped:    Arc::new(AtomicBool::new(false)),
+            waker:      waker,
+        })
+    } 
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Greet a new connection and, once it has authenticated if need be,
+/// register it.
+fn accept(stream: TcpStream, luid: u64, strms: &Clients, ctrltx: &Sender<ControlInfo>, waitlimit: usize, policy: Backpressure,
+          auth: &Option<Arc<AuthConfig>>, waker: &Arc<Waker>, pending: &Arc<AtomicUsize>) {
+    stream.set_read_timeout(Option::None);
+    stream.set_write_timeout(Option::None);
+    let mut stream = stream;
+    let flags = if auth.is_some() { HELLO_AUTH } else { 0 };
+    if stream.write_all(&Message::hello_with_flags(flags).encode()).is_err() {
+        return;
+    }
+    let auth = match *auth {
+        Option::Some(ref auth) => auth.clone(),
+        Option::None => {
+            register(stream, luid, strms, ctrltx, waitlimit, policy, waker, Session::open(), FrameReader::new(), Option::None);
+            return;
+        },
+    };
+
+    // The handshake gets its own thread so a slow client does not hold up
+    // the others.
+    let strms = strms.clone();
+    let ctrltx = ctrltx.clone();
+    let waker = waker.clone();
+    let pending = Pending::new(pending);
+    thread::spawn(move || {
+        // Held until the client is registered or gone.
+        let _pending = pending;
+        let mut frames = FrameReader::new();
+        stream.set_read_timeout(Option::Some(Duration::from_secs(10)));
+        let version = match auth::read_msg(&mut stream, &mut frames).and_then(|hello| frame::check_hello(&hello)) {
+            Result::Ok(version) => version,
+            Result::Err(_) => {
+                let _ = stream.shutdown(Shutdown::Both);
+                return;
+            },
+        };
+        let session = match auth::server_handshake(&mut stream, &mut frames, &auth) {
+            Result::Ok(session) => session,
+            Result::Err(err) => {
+                println!("[net] client {} refused: {}", luid, err);
+                let _ = stream.shutdown(Shutdown::Both);
+                return;
+            },
+        };
+        stream.set_read_timeout(Option::None);
+        register(stream, luid, &strms, &ctrltx, waitlimit, policy, &waker, session, frames, Option::Some(version));
+    });
+}
+
+/// Counts a connection as authenticating for as long as it lives.
+struct Pending(Arc<AtomicUsize>);
+
+impl Pending {
+    fn new(count: &Arc<AtomicUsize>) -> Pending {
+        count.fetch_add(1, Ordering::SeqCst);
+        Pending(count.clone())
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Register a new connection and hand it to the reactor.
+fn register(stream: TcpStream, luid: u64, strms: &Clients, ctrltx: &Sender<ControlInfo>, waitlimit: usize, policy: Backpressure,
+            waker: &Arc<Waker>, session: Session, frames: FrameReader, version: Option<u16>) {
+    let fd = stream.as_raw_fd();
+    let writer = match stream.try_clone() {
+        Result::Ok(clone) => Arc::new(ClientWriter { inner: Mutex::new((clone, session.send)) }),
+        Result::Err(_) => {
+            let _ = stream.shutdown(Shutdown::Both);
+            return;
+        },
+    };
+    let client = Arc::new(Mutex::new(Client {
+        stream:     stream,
+        luid:       luid,
+        buffer:     VecDeque::new(),
+        ctrltx:     ctrltx.clone(),   
+        waitsz:     0,
+        waitlimit:  waitlimit,
+        policy:     policy,
+        dropped:    0,
+        waker:      waker.clone(),
+        frames:     frames,
+        version:    version,
+        permission: session.permission,
+        writer:     writer,
+        open:       session.recv,
+    }));
+    strms.lock().unwrap().insert(luid, client.clone());
+    let _ = ctrltx.send(ControlInfo::ClientHello { luid: luid, client: client.clone() });
+    waker.send(Wake::Watch { luid: luid, fd: fd, client: client });
+}
+
+#[test]
+fn test_disconnect() {
+    let server = Server::new_with_config("127.0.0.1:0", {
+        let mut cfg = ServerConfig::new();
+        cfg.max_clients = Option::Some(1);
+        cfg
+    }).unwrap();
+    let addr = server.local_addr();
+
+    let mut first = Connection::connect(&addr).unwrap();
+    let luid = match server.read().unwrap() {
+        ControlInfo::ClientHello { luid, .. } => luid,
+        _ => panic!("expected a hello"),
+    };
+
+    // Over the limit so closed before any hello is sent.
+    assert!(Connection::connect(&addr).is_err());
+
+    assert!(server.write(luid + 1000, b"x").is_err());
+    assert!(server.disconnect(luid));
+    // The hello of the client may be reported as data first.
+    loop {
+        match server.read().unwrap() {
+            ControlInfo::ClientBye { luid: bye, .. } => {
+                assert_eq!(bye, luid);
+                break;
+            },
+            ControlInfo::ClientData { .. } => (),
+            _ => panic!("expected a bye"),
+        }
+    }
+    assert!(server.clients().is_empty());
+    assert!(first.recv_msg().is_err());
+
+    server.shutdown();
+}
@@ -0,0 +1,89 @@
+///! Messages waiting to be sent to one client.
+///!
+///! Streams such as audio or events are of no use late, so once a queue is
+///! full the oldest messages make way for new ones rather than holding up
+///! whoever produces them. A thread of its own pops and sends them.
+
+use super::Message;
+use std::collections::VecDeque;
+use std::sync::{Mutex, Condvar};
+
+pub struct Queue {
+    ///! The messages, and whether the client has gone.
+    msgs:       Mutex<(VecDeque<Message>, bool)>,
+    ready:      Condvar,
+    len:        usize,
+}
+
+impl Queue {
+    ///! Create holding up to `len` messages.
+    pub fn new(len: usize) -> Queue {
+        Queue { msgs: Mutex::new((VecDeque::new(), false)), ready: Condvar::new(), len: len }
+    }
+
+    ///! Add `msg`, returning false if an older one was dropped for it.
+    pub fn push(&self, msg: Message) -> bool {
+        let mut msgs = self.msgs.lock().unwrap();
+        let full = msgs.0.len() >= self.len;
+        if full {
+            msgs.0.pop_front();
+        }
+        msgs.0.push_back(msg);
+        self.ready.notify_one();
+        !full
+    }
+
+    ///! Wait for the next message, or return `None` once closed.
+    pub fn pop(&self) -> Option<Message> {
+        let mut msgs = self.msgs.lock().unwrap();
+        loop {
+            if msgs.1 {
+                return Option::None;
+            }
+            match msgs.0.pop_front() {
+                Option::Some(msg) => return Option::Some(msg),
+                Option::None => (),
+            }
+            msgs = self.ready.wait(msgs).unwrap();
+        }
+    }
+
+    pub fn close(&self) {
+        self.msgs.lock().unwrap().1 = true;
+        self.ready.notify_one();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.msgs.lock().unwrap().1
+    }
+}
+
+#[test]
+fn test_queue() {
+    use std::sync::Arc;
+    use std::thread;
+
+    let queue = Arc::new(Queue::new(64));
+    for x in 0..64 {
+        assert!(queue.push(Message::new(1, vec![x as u8])));
+    }
+    // The oldest messages make way.
+    assert!(!queue.push(Message::new(1, vec![200])));
+    assert!(!queue.push(Message::new(1, vec![201])));
+    assert_eq!(queue.pop().unwrap().payload, vec![2]);
+
+    let q = queue.clone();
+    let th = thread::spawn(move || {
+        let mut got = 0;
+        while q.pop().is_some() {
+            got += 1;
+        }
+        got
+    });
+    thread::sleep_ms(50);
+    queue.close();
+    // Whatever was left is of no use once the client is gone.
+    assert!(th.join().unwrap() <= 64);
+    assert!(queue.pop().is_none());
+    assert!(queue.is_closed());
+}
@@ -0,0 +1,662 @@
+///! Watches the listener and every client with epoll on a single thread.
+///!
+///! Sockets stay blocking so other threads can write to clients as before;
+///! the reactor only reads with `MSG_DONTWAIT` once epoll says there is
+///! something to read. A client whose buffer reaches the wait limit is no
+///! longer watched for input, so its TCP window closes, until reading from
+///! it on the other side wakes the reactor through a pipe. That is unless
+///! its `Backpressure` policy says to drop messages instead, for which the
+///! reactor follows the message boundaries in what each client sends.
+
+use super::{Client, ControlInfo, Clients, AuthConfig, Backpressure, MAX_MESSAGE, accept};
+use std::net::TcpListener;
+use std::os::unix::io::AsRawFd;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use ::libc::{c_int, c_void, size_t, epoll_event, epoll_create1, epoll_ctl, epoll_wait, fcntl, pipe, read, write, recv, close};
+use ::libc::{EPOLLIN, EPOLLERR, EPOLLHUP, EPOLL_CTL_ADD, EPOLL_CTL_DEL, EPOLL_CTL_MOD, F_GETFL, F_SETFL, O_NONBLOCK, MSG_DONTWAIT, EINTR, EAGAIN};
+use std;
+
+///! The connections allowed to authenticate at once.
+const MAX_PENDING: usize = 64;
+
+///! The epoll tokens of the listener and the wake pipe. Clients use their
+///! luid which starts above these.
+const TOKEN_LISTENER: u64 = 0;
+const TOKEN_WAKER: u64 = 1;
+
+fn last_errno() -> i32 {
+    std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
+fn set_nonblocking(fd: c_int) -> std::io::Result<()> {
+    unsafe {
+        let flags = fcntl(fd, F_GETFL);
+        if flags < 0 || fcntl(fd, F_SETFL, flags | O_NONBLOCK) < 0 {
+            return Result::Err(std::io::Error::last_os_error());
+        }
+    }
+    Result::Ok(())
+}
+
+///! Asks the reactor to do something from another thread.
+pub enum Wake {
+    ///! Start reading from a registered client.
+    Watch { luid: u64, fd: c_int, client: Arc<Mutex<Client>> },
+    ///! Read from a client again once it has room.
+    Resume(u64),
+    Stop,
+}
+
+pub struct Waker {
+    fd:         c_int,
+    queue:      Mutex<Vec<Wake>>,
+}
+
+impl Waker {
+    pub fn send(&self, wake: Wake) {
+        self.queue.lock().unwrap().push(wake);
+        let b = 1u8;
+        // If the pipe is full the reactor is already due to wake.
+        unsafe {
+            write(self.fd, &b as *const u8 as *const c_void, 1);
+        }
+    }
+}
+
+impl Drop for Waker {
+    fn drop(&mut self) {
+        unsafe {
+            close(self.fd);
+        }
+    }
+}
+
+struct Conn {
+    fd:         c_int,
+    client:     Arc<Mutex<Client>>,
+    ///! Set while the client is over its wait limit.
+    paused:     bool,
+    ///! When a paused client with `Backpressure::DisconnectAfter` is cut off.
+    deadline:   Option<f64>,
+    ///! Set while messages are being dropped, so `ClientFull` is only sent
+    ///! once each time it starts.
+    dropping:   bool,
+    ///! The bytes of the length of the next message seen so far.
+    head:       Vec<u8>,
+    ///! The bytes of the current message still to come.
+    left:       usize,
+    ///! Set if a length made no sense, after which nothing is dropped.
+    lost:       bool,
+    ///! Set if the current message is held back in `partial` until it is
+    ///! complete, so it can be dropped as a whole.
+    held:       bool,
+    partial:    Vec<u8>,
+    ///! Set until the first message is complete. It is the hello and never
+    ///! dropped.
+    first:      bool,
+}
+
+impl Conn {
+    fn new(fd: c_int, client: Arc<Mutex<Client>>) -> Conn {
+        Conn {
+            fd:         fd,
+            client:     client,
+            paused:     false,
+            deadline:   Option::None,
+            dropping:   false,
+            head:       Vec::new(),
+            left:       0,
+            lost:       false,
+            held:       false,
+            partial:    Vec::new(),
+            first:      true,
+        }
+    }
+
+    ///! Follow `data` through the messages it belongs to. Bytes of messages
+    ///! started while `hold` is set are returned in `msgs` once complete,
+    ///! each marked if it may be dropped, and the rest go into `raw`.
+    fn split(&mut self, data: &[u8], hold: bool, raw: &mut Vec<u8>, msgs: &mut Vec<(Vec<u8>, bool)>) {
+        let mut rest = data;
+        while rest.len() > 0 {
+            if self.lost {
+                raw.extend(rest.iter().cloned());
+                return;
+            }
+            if self.left == 0 && self.head.len() == 0 {
+                self.held = hold;
+            }
+            let n = if self.left == 0 {
+                let n = std::cmp::min(4 - self.head.len(), rest.len());
+                self.head.extend(rest[0..n].iter().cloned());
+                n
+            } else {
+                std::cmp::min(self.left, rest.len())
+            };
+            if self.held {
+                self.partial.extend(rest[0..n].iter().cloned());
+            } else {
+                raw.extend(rest[0..n].iter().cloned());
+            }
+            rest = &rest[n..];
+
+            if self.left == 0 {
+                if self.head.len() == 4 {
+                    let len = self.head[0] as usize | (self.head[1] as usize) << 8 |
+                              (self.head[2] as usize) << 16 | (self.head[3] as usize) << 24;
+                    self.head.clear();
+                    if len < 2 || len > MAX_MESSAGE {
+                        self.lost = true;
+                        self.held = false;
+                        let partial = std::mem::replace(&mut self.partial, Vec::new());
+                        raw.extend(partial.into_iter());
+                    } else {
+                        self.left = len;
+                    }
+                }
+            } else {
+                self.left -= n;
+                if self.left == 0 {
+                    if self.held {
+                        msgs.push((std::mem::replace(&mut self.partial, Vec::new()), !self.first));
+                    }
+                    self.first = false;
+                }
+            }
+        }
+    }
+}
+
+pub struct Reactor {
+    epfd:       c_int,
+    wakefd:     c_int,
+    waker:      Arc<Waker>,
+    listener:   TcpListener,
+    conns:      HashMap<u64, Conn>,
+    strms:      Clients,
+    ctrltx:     Sender<ControlInfo>,
+    max_clients: Option<usize>,
+    waitlimit:  usize,
+    policy:     Backpressure,
+    auth:       Option<Arc<AuthConfig>>,
+    ///! The connections still authenticating.
+    pending:    Arc<AtomicUsize>,
+    luid:       u64,
+}
+
+impl Reactor {
+    pub fn new(listener: TcpListener, strms: Clients, ctrltx: Sender<ControlInfo>, max_clients: Option<usize>,
+               waitlimit: usize, policy: Backpressure, auth: Option<Arc<AuthConfig>>) -> std::io::Result<Reactor> {
+        let mut fds: [c_int; 2] = [0, 0];
+        let epfd = unsafe { epoll_create1(0) };
+        if epfd < 0 {
+            return Result::Err(std::io::Error::last_os_error());
+        }
+        if unsafe { pipe(fds.as_mut_ptr()) } < 0 {
+            let err = std::io::Error::last_os_error();
+            unsafe { close(epfd); }
+            return Result::Err(err);
+        }
+        let reactor = Reactor {
+            epfd:       epfd,
+            wakefd:     fds[0],
+            waker:      Arc::new(Waker { fd: fds[1], queue: Mutex::new(Vec::new()) }),
+            listener:   listener,
+            conns:      HashMap::new(),
+            strms:      strms,
+            ctrltx:     ctrltx,
+            max_clients: max_clients,
+            waitlimit:  waitlimit,
+            policy:     policy,
+            auth:       auth,
+            pending:    Arc::new(AtomicUsize::new(0)),
+            luid:       100,
+        };
+        try!(set_nonblocking(fds[0]));
+        try!(set_nonblocking(fds[1]));
+        try!(set_nonblocking(reactor.listener.as_raw_fd()));
+        try!(reactor.ctl(EPOLL_CTL_ADD, reactor.listener.as_raw_fd(), EPOLLIN, TOKEN_LISTENER));
+        try!(reactor.ctl(EPOLL_CTL_ADD, fds[0], EPOLLIN, TOKEN_WAKER));
+        Result::Ok(reactor)
+    }
+
+    pub fn waker(&self) -> Arc<Waker> {
+        self.waker.clone()
+    }
+
+    fn ctl(&self, op: c_int, fd: c_int, events: c_int, token: u64) -> std::io::Result<()> {
+        let mut ev = epoll_event { events: events as u32, u64: token };
+        if unsafe { epoll_ctl(self.epfd, op, fd, &mut ev) } < 0 {
+            return Result::Err(std::io::Error::last_os_error());
+        }
+        Result::Ok(())
+    }
+
+    ///! Run until told to stop.
+    pub fn run(mut self) {
+        let mut events = [epoll_event { events: 0, u64: 0 }; 64];
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let timeout = self.timeout();
+            let n = unsafe { epoll_wait(self.epfd, events.as_mut_ptr(), events.len() as c_int, timeout) };
+            self.expire();
+            if n < 0 {
+                if last_errno() == EINTR {
+                    continue;
+                }
+                println!("[net] epoll failed: {}", std::io::Error::last_os_error());
+                return;
+            }
+            for x in 0..n as usize {
+                let token = events[x].u64;
+                let flags = events[x].events;
+                match token {
+                    TOKEN_LISTENER => self.accept_all(),
+                    TOKEN_WAKER => if !self.wake() {
+                        return;
+                    },
+                    luid => self.ready(luid, flags, &mut buf),
+                }
+            }
+        }
+    }
+
+    ///! Return the milliseconds until the next deadline, or -1 for none.
+    fn timeout(&self) -> c_int {
+        let now = ::time::precise_time_s();
+        let next = self.conns.values().filter_map(|conn| conn.deadline).fold(Option::None, |next: Option<f64>, d| {
+            match next {
+                Option::Some(next) if next < d => Option::Some(next),
+                _ => Option::Some(d),
+            }
+        });
+        match next {
+            Option::Some(next) if next <= now => 0,
+            Option::Some(next) => ((next - now) * 1000.0).ceil() as c_int,
+            Option::None => -1,
+        }
+    }
+
+    ///! Disconnect the clients that stayed full past their deadline.
+    fn expire(&mut self) {
+        let now = ::time::precise_time_s();
+        let expired: Vec<u64> = self.conns.iter().filter(|&(_, conn)| match conn.deadline {
+            Option::Some(d) => d <= now,
+            Option::None => false,
+        }).map(|(luid, _)| *luid).collect();
+        for luid in expired {
+            let policy = self.conns[&luid].client.lock().unwrap().policy;
+            match policy {
+                Backpressure::DisconnectAfter(_) => {
+                    println!("[net] client {} full for too long", luid);
+                    self.conns[&luid].client.lock().unwrap().close();
+                    self.remove(luid, Option::None);
+                },
+                _ => self.conns.get_mut(&luid).unwrap().deadline = Option::None,
+            }
+        }
+    }
+
+    fn accept_all(&mut self) {
+        loop {
+            let stream = match self.listener.accept() {
+                Result::Ok((stream, _)) => stream,
+                // Nothing more waiting, or the connection went away first.
+                Result::Err(_) => return,
+            };
+            // Connections still authenticating count too, or anyone could
+            // tie up a thread each for as long as the handshake may take.
+            let pending = self.pending.load(Ordering::SeqCst);
+            let full = match self.max_clients {
+                Option::Some(max) => self.strms.lock().unwrap().len() + pending >= max,
+                Option::None => false,
+            };
+            if full || pending >= MAX_PENDING {
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+                continue;
+            }
+            self.luid += 1;
+            accept(stream, self.luid, &self.strms, &self.ctrltx, self.waitlimit, self.policy, &self.auth, &self.waker, &self.pending);
+        }
+    }
+
+    ///! Act on what other threads asked for. Returns false once told to stop.
+    fn wake(&mut self) -> bool {
+        let mut b = [0u8; 256];
+        while unsafe { read(self.wakefd, b.as_mut_ptr() as *mut c_void, b.len() as size_t) } > 0 {
+        }
+        let queue = std::mem::replace(&mut *self.waker.queue.lock().unwrap(), Vec::new());
+        for wake in queue {
+            match wake {
+                Wake::Watch { luid, fd, client } => {
+                    if self.ctl(EPOLL_CTL_ADD, fd, EPOLLIN, luid).is_err() {
+                        self.remove(luid, Option::Some(client));
+                        continue;
+                    }
+                    self.conns.insert(luid, Conn::new(fd, client));
+                },
+                Wake::Resume(luid) => {
+                    let fd = match self.conns.get(&luid) {
+                        Option::Some(conn) if conn.paused => conn.fd,
+                        _ => continue,
+                    };
+                    let full = {
+                        let client = self.conns[&luid].client.lock().unwrap();
+                        client.waitsz >= client.waitlimit && !client.policy.drops()
+                    };
+                    if !full && self.ctl(EPOLL_CTL_MOD, fd, EPOLLIN, luid).is_ok() {
+                        let conn = self.conns.get_mut(&luid).unwrap();
+                        conn.paused = false;
+                        conn.deadline = Option::None;
+                    }
+                },
+                Wake::Stop => {
+                    let luids: Vec<u64> = self.conns.keys().cloned().collect();
+                    for luid in luids {
+                        self.conns[&luid].client.lock().unwrap().close();
+                        self.remove(luid, Option::None);
+                    }
+                    return false;
+                },
+            }
+        }
+        true
+    }
+
+    ///! Read what a client sent.
+    fn ready(&mut self, luid: u64, flags: u32, buf: &mut Vec<u8>) {
+        let (fd, paused) = match self.conns.get(&luid) {
+            Option::Some(conn) => (conn.fd, conn.paused),
+            Option::None => return,
+        };
+        if paused {
+            // Only a hang up is reported while paused, and the data
+            // already buffered is still there to be read.
+            if flags & (EPOLLHUP | EPOLLERR) as u32 != 0 {
+                self.remove(luid, Option::None);
+            }
+            return;
+        }
+
+        let rsz = unsafe { recv(fd, buf.as_mut_ptr() as *mut c_void, buf.len() as size_t, MSG_DONTWAIT) };
+        if rsz < 0 && (last_errno() == EAGAIN || last_errno() == EINTR) {
+            return;
+        }
+        if rsz < 1 {
+            // The other end closed the connection or it failed.
+            self.remove(luid, Option::None);
+            return;
+        }
+
+        let data = buf[0..rsz as usize].to_vec();
+        let conn = self.conns.get_mut(&luid).unwrap();
+
+        let policy = conn.client.lock().unwrap().policy;
+        let mut raw: Vec<u8> = Vec::new();
+        let mut msgs: Vec<(Vec<u8>, bool)> = Vec::new();
+        conn.split(&data, policy.drops(), &mut raw, &mut msgs);
+
+        let client_clone = conn.client.clone();
+        let mut lck = conn.client.lock().unwrap();
+        let was_empty = lck.buffer.len() == 0;
+        let mut dropped = false;
+        if raw.len() > 0 {
+            lck.waitsz += raw.len();
+            lck.buffer.push_back((raw, false));
+        }
+        for (msg, droppable) in msgs {
+            if droppable && lck.waitsz + msg.len() > lck.waitlimit {
+                match policy {
+                    Backpressure::DropNewest => {
+                        lck.dropped += msg.len() as u64;
+                        dropped = true;
+                        continue;
+                    },
+                    Backpressure::DropOldest => {
+                        while lck.waitsz + msg.len() > lck.waitlimit {
+                            let pos = lck.buffer.iter().position(|&(_, droppable)| droppable);
+                            let old = match pos {
+                                Option::Some(x) => lck.buffer.remove(x).unwrap().0,
+                                Option::None => break,
+                            };
+                            lck.waitsz -= old.len();
+                            lck.dropped += old.len() as u64;
+                            dropped = true;
+                        }
+                    },
+                    _ => (),
+                }
+            }
+            lck.waitsz += msg.len();
+            lck.buffer.push_back((msg, droppable));
+        }
+        if was_empty && lck.buffer.len() > 0 {
+            let _ = lck.ctrltx.send(ControlInfo::ClientData { luid: luid, client: client_clone.clone() });
+        }
+
+        if policy.drops() && !conn.lost {
+            if dropped && !conn.dropping {
+                let _ = lck.ctrltx.send(ControlInfo::ClientFull { luid: luid, client: client_clone });
+            }
+            conn.dropping = dropped || (conn.dropping && lck.waitsz >= lck.waitlimit);
+        } else if lck.waitsz >= lck.waitlimit {
+            // Stop reading so the TCP window closes instead of the buffer
+            // growing without bound.
+            let _ = lck.ctrltx.send(ControlInfo::ClientFull { luid: luid, client: client_clone });
+            let mut ev = epoll_event { events: 0, u64: luid };
+            unsafe {
+                epoll_ctl(self.epfd, EPOLL_CTL_MOD, fd, &mut ev);
+            }
+            conn.paused = true;
+            match policy {
+                Backpressure::DisconnectAfter(secs) => conn.deadline = Option::Some(::time::precise_time_s() + secs),
+                _ => (),
+            }
+        }
+    }
+
+    ///! Forget a client that has gone and report it.
+    fn remove(&mut self, luid: u64, client: Option<Arc<Mutex<Client>>>) {
+        let client = match self.conns.remove(&luid) {
+            Option::Some(conn) => {
+                let _ = self.ctl(EPOLL_CTL_DEL, conn.fd, 0, luid);
+                conn.client
+            },
+            Option::None => match client {
+                Option::Some(client) => client,
+                Option::None => return,
+            },
+        };
+        self.strms.lock().unwrap().remove(&luid);
+        let _ = self.ctrltx.send(ControlInfo::ClientBye { luid: luid, client: client });
+    }
+}
+
+impl Drop for Reactor {
+    fn drop(&mut self) {
+        unsafe {
+            close(self.wakefd);
+            close(self.epfd);
+        }
+    }
+}
+
+#[test]
+fn test_reactor_backpressure() {
+    use super::{Server, ServerConfig, Connection, Message};
+
+    let server = Server::new_with_config("127.0.0.1:0", {
+        let mut cfg = ServerConfig::new();
+        cfg.waitlimit = 4096;
+        cfg
+    }).unwrap();
+    let addr = server.local_addr();
+
+    // Sent from threads since the writes block once reading stops.
+    let senders: Vec<_> = (0..20).map(|x| std::thread::spawn(move || {
+        let mut conn = Connection::connect(&addr).unwrap();
+        conn.send_msg(&Message::new(7, vec![x as u8; 100000])).unwrap();
+        conn
+    })).collect();
+
+    // Each message is far over the wait limit so reading from every client
+    // must stop and start again many times.
+    let mut got: HashMap<u64, Message> = HashMap::new();
+    let mut full = 0;
+    while got.len() < 20 {
+        let (luid, client) = match server.read().unwrap() {
+            ControlInfo::ClientData { luid, client } => (luid, client),
+            ControlInfo::ClientFull { luid, client } => {
+                full += 1;
+                (luid, client)
+            },
+            _ => continue,
+        };
+        let msg = client.lock().unwrap().recv_msg().unwrap();
+        match msg {
+            Option::Some(msg) => {
+                got.insert(luid, msg);
+            },
+            Option::None => (),
+        }
+    }
+    for sender in senders {
+        sender.join().unwrap();
+    }
+    assert!(full >= 20);
+    let mut seen: Vec<u8> = got.values().map(|msg| {
+        assert_eq!(msg.payload.len(), 100000);
+        msg.payload[0]
+    }).collect();
+    seen.sort();
+    assert_eq!(seen, (0..20).collect::<Vec<u8>>());
+}
+
+#[test]
+fn test_reactor_policies() {
+    use super::{Server, ServerConfig, Connection, Message};
+    use std::sync::mpsc::channel;
+
+    // Sends 20 messages of 206 bytes, of which four fit behind the hello
+    // in 1000 bytes, and returns the payloads the server kept.
+    fn flood(policy: Backpressure) -> (u64, Vec<u8>) {
+        let server = Server::new_with_config("127.0.0.1:0", {
+            let mut cfg = ServerConfig::new();
+            cfg.waitlimit = 1000;
+            cfg.policy = policy;
+            cfg
+        }).unwrap();
+        let mut conn = Connection::connect(&server.local_addr()).unwrap();
+        let (luid, client) = match server.read().unwrap() {
+            ControlInfo::ClientHello { luid, client } => (luid, client),
+            _ => panic!("expected a hello"),
+        };
+        for x in 0..20 {
+            conn.send_msg(&Message::new(7, vec![x as u8; 200])).unwrap();
+        }
+        for _ in 0..500 {
+            if server.dropped(luid) == Option::Some(16 * 206) {
+                break;
+            }
+            std::thread::sleep_ms(10);
+        }
+        let mut kept: Vec<u8> = Vec::new();
+        loop {
+            let msg = client.lock().unwrap().recv_msg().unwrap();
+            match msg {
+                Option::Some(msg) => kept.push(msg.payload[0]),
+                Option::None => break,
+            }
+        }
+        (server.dropped(luid).unwrap(), kept)
+    }
+
+    assert_eq!(flood(Backpressure::DropOldest), (16 * 206, vec![16, 17, 18, 19]));
+    assert_eq!(flood(Backpressure::DropNewest), (16 * 206, vec![0, 1, 2, 3]));
+
+    // A client left full is cut off once the policy is changed to say so.
+    let server = Server::new_with_config("127.0.0.1:0", {
+        let mut cfg = ServerConfig::new();
+        cfg.waitlimit = 1000;
+        cfg
+    }).unwrap();
+    let addr = server.local_addr();
+    let (go_tx, go_rx) = channel::<()>();
+    let sender = std::thread::spawn(move || {
+        let mut conn = Connection::connect(&addr).unwrap();
+        go_rx.recv().unwrap();
+        conn.send_msg(&Message::new(7, vec![0u8; 5000])).unwrap();
+        conn
+    });
+    let luid = match server.read().unwrap() {
+        ControlInfo::ClientHello { luid, client } => {
+            client.lock().unwrap().set_policy(Backpressure::DisconnectAfter(0.1));
+            luid
+        },
+        _ => panic!("expected a hello"),
+    };
+    go_tx.send(()).unwrap();
+    let mut full = false;
+    loop {
+        match server.read().unwrap() {
+            ControlInfo::ClientFull { .. } => full = true,
+            ControlInfo::ClientBye { luid: bye, .. } => {
+                assert_eq!(bye, luid);
+                break;
+            },
+            _ => (),
+        }
+    }
+    assert!(full);
+    sender.join().unwrap();
+}
+
+#[test]
+fn test_reactor_slow_peer() {
+    use super::{Server, Connection, Message};
+
+    let server = Server::new("127.0.0.1:0").unwrap();
+    let addr = server.local_addr();
+    let mut slow = Connection::connect(&addr).unwrap();
+    let luid = match server.read().unwrap() {
+        ControlInfo::ClientHello { luid, .. } => luid,
+        _ => panic!("expected a hello"),
+    };
+
+    // Fill the socket of a peer that does not read until the writer blocks.
+    let writer = server.writer(luid).unwrap();
+    let th = std::thread::spawn(move || {
+        while writer.send_msg(&Message::new(7, vec![0u8; 65536])).is_ok() {
+        }
+    });
+    std::thread::sleep_ms(200);
+
+    // What the slow peer and the others send is still read.
+    slow.send_msg(&Message::new(8, b"still here".to_vec())).unwrap();
+    let mut fast = Connection::connect(&addr).unwrap();
+    fast.send_msg(&Message::new(9, b"hello".to_vec())).unwrap();
+    let mut got: Vec<u16> = Vec::new();
+    while got.len() < 2 {
+        match server.read().unwrap() {
+            ControlInfo::ClientData { client, .. } => {
+                loop {
+                    let msg = client.lock().unwrap().recv_msg().unwrap();
+                    match msg {
+                        Option::Some(msg) => got.push(msg.kind),
+                        Option::None => break,
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+    got.sort();
+    assert_eq!(got, vec![8, 9]);
+
+    server.disconnect(luid);
+    drop(slow);
+    th.join().unwrap();
+}
@@ -0,0 +1,197 @@
+///! The rtl_tcp protocol, as spoken by GQRX, SDR# and others to stream from
+///! a remote RTL-SDR dongle.
+///!
+///! The server opens with a 12 byte header naming the tuner and the number
+///! of gains it supports, then sends unsigned 8 bit I and Q pairs for as
+///! long as the client stays. The client sends 5 byte commands, a command
+///! byte followed by a big endian `u32` parameter, which are never answered.
+
+use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+use dsp::Complex;
+use std;
+
+mod server;
+mod source;
+
+pub use self::server::{RtlTcpServer, RtlTcpConfig};
+pub use self::source::RtlTcpSource;
+
+pub const MAGIC: &'static [u8] = b"RTL0";
+pub const HEADER_LEN: usize = 12;
+pub const COMMAND_LEN: usize = 5;
+
+///! The tuner named in the header. Clients use it to decide which gains to
+///! offer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TunerType {
+    Unknown,
+    E4000,
+    FC0012,
+    FC0013,
+    FC2580,
+    R820T,
+    R828D,
+}
+
+impl TunerType {
+    pub fn from_u32(v: u32) -> TunerType {
+        match v {
+            1 => TunerType::E4000,
+            2 => TunerType::FC0012,
+            3 => TunerType::FC0013,
+            4 => TunerType::FC2580,
+            5 => TunerType::R820T,
+            6 => TunerType::R828D,
+            _ => TunerType::Unknown,
+        }
+    }
+
+    pub fn to_u32(&self) -> u32 {
+        match *self {
+            TunerType::Unknown => 0,
+            TunerType::E4000 => 1,
+            TunerType::FC0012 => 2,
+            TunerType::FC0013 => 3,
+            TunerType::FC2580 => 4,
+            TunerType::R820T => 5,
+            TunerType::R828D => 6,
+        }
+    }
+
+    ///! Return the gains in dB the tuner offers, as librtlsdr lists them.
+    ///! Tuners whose gains are not known give none.
+    pub fn gains(&self) -> Vec<f64> {
+        let tenths: &[i32] = match *self {
+            TunerType::E4000 => &[-10, 15, 40, 65, 90, 115, 140, 165, 190, 215, 240, 290, 340, 420],
+            TunerType::R820T | TunerType::R828D => &[
+                0, 9, 14, 27, 37, 77, 87, 125, 144, 157, 166, 197, 207, 229, 254,
+                280, 297, 328, 338, 364, 372, 386, 402, 421, 434, 439, 445, 480, 496,
+            ],
+            _ => &[],
+        };
+        tenths.iter().map(|g| *g as f64 / 10.0).collect()
+    }
+}
+
+///! The header sent by the server when a client connects.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DongleInfo {
+    pub tuner:      TunerType,
+    pub gain_count: u32,
+}
+
+impl DongleInfo {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out: Vec<u8> = Vec::with_capacity(HEADER_LEN);
+        out.extend(MAGIC.iter().cloned());
+        out.write_u32::<BigEndian>(self.tuner.to_u32()).unwrap();
+        out.write_u32::<BigEndian>(self.gain_count).unwrap();
+        out
+    }
+
+    ///! Return `None` if `buf` is not a header.
+    pub fn decode(buf: &[u8]) -> Option<DongleInfo> {
+        if buf.len() < HEADER_LEN || &buf[0..4] != MAGIC {
+            return Option::None;
+        }
+        let mut rest = &buf[4..HEADER_LEN];
+        let tuner = rest.read_u32::<BigEndian>().unwrap();
+        let gain_count = rest.read_u32::<BigEndian>().unwrap();
+        Option::Some(DongleInfo {
+            tuner:      TunerType::from_u32(tuner),
+            gain_count: gain_count,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    ///! Tune to this many Hz.
+    SetFreq(u32),
+    SetSampleRate(u32),
+    ///! Zero for automatic gain, otherwise manual.
+    SetGainMode(u32),
+    ///! The gain in tenths of a dB.
+    SetGain(i32),
+    SetPpm(i32),
+    ///! Use the gain at this index of those the header counted.
+    SetGainIndex(u32),
+    ///! Any command not understood, kept as sent.
+    Other(u8, u32),
+}
+
+impl Command {
+    pub fn encode(&self) -> Vec<u8> {
+        let (cmd, param) = match *self {
+            Command::SetFreq(v) => (0x01, v),
+            Command::SetSampleRate(v) => (0x02, v),
+            Command::SetGainMode(v) => (0x03, v),
+            Command::SetGain(v) => (0x04, v as u32),
+            Command::SetPpm(v) => (0x05, v as u32),
+            Command::SetGainIndex(v) => (0x0d, v),
+            Command::Other(cmd, v) => (cmd, v),
+        };
+        let mut out: Vec<u8> = Vec::with_capacity(COMMAND_LEN);
+        out.push(cmd);
+        out.write_u32::<BigEndian>(param).unwrap();
+        out
+    }
+
+    pub fn decode(cmd: u8, param: u32) -> Command {
+        match cmd {
+            0x01 => Command::SetFreq(param),
+            0x02 => Command::SetSampleRate(param),
+            0x03 => Command::SetGainMode(param),
+            0x04 => Command::SetGain(param as i32),
+            0x05 => Command::SetPpm(param as i32),
+            0x0d => Command::SetGainIndex(param),
+            _ => Command::Other(cmd, param),
+        }
+    }
+
+    ///! Read the next command, returning `None` once the stream ends.
+    pub fn read<R: std::io::Read>(rd: &mut R) -> Option<Command> {
+        let cmd = match rd.read_u8() {
+            Result::Ok(v) => v,
+            Result::Err(_) => return Option::None,
+        };
+        let param = match rd.read_u32::<BigEndian>() {
+            Result::Ok(v) => v,
+            Result::Err(_) => return Option::None,
+        };
+        Option::Some(Command::decode(cmd, param))
+    }
+}
+
+///! Append `buf` to `out` as unsigned 8 bit pairs, clipping anything
+///! outside of -1 to 1.
+pub fn to_cu8(buf: &[Complex<f32>], out: &mut Vec<u8>) {
+    fn conv(v: f32) -> u8 {
+        let v = (v * 127.5 + 128.0).floor();
+        if v < 0.0 {
+            0
+        } else if v > 255.0 {
+            255
+        } else {
+            v as u8
+        }
+    }
+    for s in buf.iter() {
+        out.push(conv(s.i));
+        out.push(conv(s.q));
+    }
+}
+
+///! Append the unsigned 8 bit pairs in `buf` to `out`. A trailing odd byte
+///! is ignored.
+pub fn from_cu8(buf: &[u8], out: &mut Vec<Complex<f32>>) {
+    for pair in buf.chunks(2) {
+        if pair.len() < 2 {
+            break;
+        }
+        out.push(Complex {
+            i: (pair[0] as f32 - 127.5) / 127.5,
+            q: (pair[1] as f32 - 127.5) / 127.5,
+        });
+    }
+}
@@ -0,0 +1,216 @@
+///! Serves the samples of any `Source` to rtl_tcp clients.
+
+use super::{DongleInfo, TunerType, Command, to_cu8};
+use dsp::{Source, Complex};
+use std::net::{TcpListener, TcpStream, SocketAddr, Shutdown};
+use std::io::Write;
+use std::sync::mpsc::channel;
+use std::thread;
+use std;
+
+pub struct RtlTcpConfig {
+    ///! The tuner claimed in the header.
+    pub tuner:      TunerType,
+}
+
+impl RtlTcpConfig {
+    pub fn new() -> RtlTcpConfig {
+        RtlTcpConfig {
+            tuner:      TunerType::R820T,
+        }
+    }
+}
+
+///! Serves one client at a time, as rtl_tcp does.
+pub struct RtlTcpServer {
+    listener:   TcpListener,
+    cfg:        RtlTcpConfig,
+}
+
+impl RtlTcpServer {
+    pub fn new(addr: &str) -> std::io::Result<RtlTcpServer> {
+        RtlTcpServer::new_with_config(addr, RtlTcpConfig::new())
+    }
+
+    pub fn new_with_config(addr: &str, cfg: RtlTcpConfig) -> std::io::Result<RtlTcpServer> {
+        Result::Ok(RtlTcpServer {
+            listener:   try!(TcpListener::bind(addr)),
+            cfg:        cfg,
+        })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    ///! Serve clients one after another until the source runs dry.
+    pub fn serve<S: Source>(&self, source: &mut S) -> std::io::Result<()> {
+        while try!(self.serve_client(source)) {
+        }
+        Result::Ok(())
+    }
+
+    ///! Wait for a client and stream to it until it leaves. Return false if
+    ///! the stream ended because the source ran dry.
+    pub fn serve_client<S: Source>(&self, source: &mut S) -> std::io::Result<bool> {
+        let (mut stream, addr) = try!(self.listener.accept());
+        println!("[rtl-tcp] client {} connected", addr);
+
+        let gains = source.gains();
+        let info = DongleInfo { tuner: self.cfg.tuner, gain_count: gains.len() as u32 };
+        try!(stream.write_all(&info.encode()));
+
+        // Commands are read on their own thread so a quiet client never
+        // holds up the samples.
+        let (tx, rx) = channel();
+        let mut reader = try!(stream.try_clone());
+        thread::spawn(move || {
+            loop {
+                match Command::read(&mut reader) {
+                    Option::Some(cmd) => {
+                        if tx.send(cmd).is_err() {
+                            return;
+                        }
+                    },
+                    Option::None => return,
+                }
+            }
+        });
+
+        let mut out: Vec<u8> = Vec::new();
+        let mut more = true;
+        loop {
+            while let Result::Ok(cmd) = rx.try_recv() {
+                apply(source, &gains, cmd);
+            }
+
+            let buf = match source.recv_block() {
+                Result::Ok(buf) => buf,
+                Result::Err(err) => {
+                    let _ = stream.shutdown(Shutdown::Both);
+                    return Result::Err(std::io::Error::new(std::io::ErrorKind::Other, format!("{}", err)));
+                },
+            };
+            if buf.len() == 0 {
+                more = false;
+                break;
+            }
+
+            out.clear();
+            to_cu8(&buf, &mut out);
+            source.recycle_block(buf);
+            if stream.write_all(&out).is_err() {
+                break;
+            }
+        }
+
+        println!("[rtl-tcp] client {} gone", addr);
+        let _ = stream.shutdown(Shutdown::Both);
+        Result::Ok(more)
+    }
+}
+
+///! Carry out a command. The client is never told of a failure, so it is
+///! only logged.
+fn apply<S: Source>(source: &mut S, gains: &Vec<f64>, cmd: Command) {
+    let result = match cmd {
+        Command::SetFreq(freq) => source.set_freq(freq as f64),
+        Command::SetSampleRate(rate) => source.set_rate(rate as f64),
+        Command::SetGainMode(0) => source.set_gain(Option::None),
+        // The gain that follows makes it manual.
+        Command::SetGainMode(_) => Result::Ok(()),
+        Command::SetGain(gain) => source.set_gain(Option::Some(gain as f64 / 10.0)),
+        Command::SetPpm(ppm) => source.set_ppm(ppm),
+        Command::SetGainIndex(index) => match gains.get(index as usize) {
+            Option::Some(gain) => source.set_gain(Option::Some(*gain)),
+            Option::None => {
+                println!("[rtl-tcp] no gain at index {}", index);
+                Result::Ok(())
+            },
+        },
+        Command::Other(cmd, param) => {
+            println!("[rtl-tcp] ignoring command {:#x} ({})", cmd, param);
+            Result::Ok(())
+        },
+    };
+    match result {
+        Result::Ok(_) => (),
+        Result::Err(err) => println!("[rtl-tcp] unable to apply {:?}: {}", cmd, err),
+    }
+}
+
+#[test]
+fn test_rtl_tcp_server() {
+    use std::io::Read;
+    use super::{HEADER_LEN, MAGIC};
+
+    ///! Repeats a full scale sample until it is tuned.
+    struct TestSource {
+        freq:   Option<f64>,
+        gain:   Option<f64>,
+    }
+
+    impl Source for TestSource {
+        type Error = String;
+
+        fn recv_block(&mut self) -> Result<Vec<Complex<f32>>, String> {
+            match self.freq {
+                Option::Some(_) => Result::Ok(Vec::new()),
+                Option::None => Result::Ok(vec![Complex { i: 1.0, q: -1.0 }; 64]),
+            }
+        }
+
+        fn rate(&self) -> f64 {
+            1000000.0
+        }
+
+        fn set_freq(&mut self, freq: f64) -> Result<(), String> {
+            self.freq = Option::Some(freq);
+            Result::Ok(())
+        }
+
+        fn set_gain(&mut self, gain: Option<f64>) -> Result<(), String> {
+            self.gain = gain;
+            Result::Ok(())
+        }
+
+        fn gains(&self) -> Vec<f64> {
+            vec![0.0, 10.0, 20.0]
+        }
+    }
+
+    let server = RtlTcpServer::new("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+
+    let client = thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut header = [0u8; HEADER_LEN];
+        let mut got = 0;
+        while got < HEADER_LEN {
+            got += stream.read(&mut header[got..]).unwrap();
+        }
+        assert_eq!(&header[0..4], MAGIC);
+        let info = DongleInfo::decode(&header).unwrap();
+        assert_eq!(info.tuner, TunerType::R820T);
+        assert_eq!(info.gain_count, 3);
+
+        // The gain goes first since the source stops once tuned.
+        stream.write_all(&Command::SetGainIndex(2).encode()).unwrap();
+        stream.write_all(&Command::SetFreq(146520000).encode()).unwrap();
+
+        let mut data: Vec<u8> = Vec::new();
+        stream.read_to_end(&mut data).unwrap();
+        data
+    });
+
+    let mut source = TestSource { freq: Option::None, gain: Option::None };
+    assert_eq!(server.serve_client(&mut source).unwrap(), false);
+    assert_eq!(source.freq, Option::Some(146520000.0));
+    assert_eq!(source.gain, Option::Some(20.0));
+
+    let data = client.join().unwrap();
+    assert!(data.len() > 0 && data.len() % 2 == 0);
+    for pair in data.chunks(2) {
+        assert_eq!(pair, &[255u8, 0][..]);
+    }
+}
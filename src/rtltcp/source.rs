@@ -0,0 +1,173 @@
+///! A `Source` reading from an rtl_tcp server.
+
+use super::{DongleInfo, Command, HEADER_LEN, from_cu8};
+use dsp::{Source, Complex};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::io::{Read, Write};
+use std;
+
+///! The rate rtl_tcp starts at until told otherwise.
+const DEFAULT_RATE: f64 = 2048000.0;
+
+pub struct RtlTcpSource {
+    stream:     TcpStream,
+    info:       DongleInfo,
+    rate:       f64,
+    ///! Room for the bytes of one block.
+    chunk:      Vec<u8>,
+    ///! The I half of a pair split across reads.
+    odd:        Option<u8>,
+}
+
+impl RtlTcpSource {
+    ///! Connect and read the header. The rate is taken to be the rtl_tcp
+    ///! default until `set_rate` is used.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> std::io::Result<RtlTcpSource> {
+        let mut stream = try!(TcpStream::connect(addr));
+        let mut header = [0u8; HEADER_LEN];
+        let mut got = 0;
+        while got < HEADER_LEN {
+            let n = try!(stream.read(&mut header[got..]));
+            if n == 0 {
+                return Result::Err(std::io::Error::new(std::io::ErrorKind::Other, "connection closed before the header"));
+            }
+            got += n;
+        }
+        let info = match DongleInfo::decode(&header) {
+            Option::Some(info) => info,
+            Option::None => return Result::Err(std::io::Error::new(std::io::ErrorKind::Other, "not an rtl_tcp server")),
+        };
+        Result::Ok(RtlTcpSource {
+            stream:     stream,
+            info:       info,
+            rate:       DEFAULT_RATE,
+            chunk:      vec![0u8; 32768],
+            odd:        Option::None,
+        })
+    }
+
+    ///! The header sent by the server.
+    pub fn info(&self) -> DongleInfo {
+        self.info
+    }
+
+    pub fn send(&mut self, cmd: Command) -> std::io::Result<()> {
+        self.stream.write_all(&cmd.encode())
+    }
+}
+
+impl Source for RtlTcpSource {
+    type Error = std::io::Error;
+
+    fn recv_block(&mut self) -> std::io::Result<Vec<Complex<f32>>> {
+        let mut out: Vec<Complex<f32>> = Vec::new();
+        while out.len() == 0 {
+            let n = try!(self.stream.read(&mut self.chunk));
+            if n == 0 {
+                return Result::Ok(out);
+            }
+            let mut bytes = &self.chunk[0..n];
+            match self.odd.take() {
+                Option::Some(i) => {
+                    from_cu8(&[i, bytes[0]], &mut out);
+                    bytes = &bytes[1..];
+                },
+                Option::None => (),
+            }
+            if bytes.len() % 2 == 1 {
+                self.odd = Option::Some(bytes[bytes.len() - 1]);
+                bytes = &bytes[0..bytes.len() - 1];
+            }
+            from_cu8(bytes, &mut out);
+        }
+        Result::Ok(out)
+    }
+
+    fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    fn set_freq(&mut self, freq: f64) -> std::io::Result<()> {
+        self.send(Command::SetFreq(freq as u32))
+    }
+
+    fn set_rate(&mut self, rate: f64) -> std::io::Result<()> {
+        try!(self.send(Command::SetSampleRate(rate as u32)));
+        self.rate = rate;
+        Result::Ok(())
+    }
+
+    fn set_gain(&mut self, gain: Option<f64>) -> std::io::Result<()> {
+        match gain {
+            Option::Some(gain) => {
+                try!(self.send(Command::SetGainMode(1)));
+                self.send(Command::SetGain((gain * 10.0).round() as i32))
+            },
+            Option::None => self.send(Command::SetGainMode(0)),
+        }
+    }
+
+    fn set_ppm(&mut self, ppm: i32) -> std::io::Result<()> {
+        self.send(Command::SetPpm(ppm))
+    }
+
+    fn gains(&self) -> Vec<f64> {
+        self.info.tuner.gains()
+    }
+}
+
+#[test]
+fn test_rtl_tcp_source() {
+    use dsp::{FileSink, FileSource, Sink, SendFlags};
+    use super::{RtlTcpServer, TunerType, to_cu8};
+    use std::thread;
+
+    let path = std::env::temp_dir().join("ham-test-rtl-tcp-source");
+    let path = String::from(path.to_str().unwrap());
+    let mut samples: Vec<Complex<f32>> = Vec::new();
+    for x in 0..20000 {
+        let v = (x % 200) as f32 / 100.0 - 1.0;
+        samples.push(Complex { i: v, q: -v });
+    }
+    {
+        let mut sink = FileSink::new(path.clone(), 1000.0).unwrap();
+        sink.send(&samples, SendFlags::continuous()).unwrap();
+    }
+
+    // Stands in for a remote dongle by replaying the file.
+    let server = RtlTcpServer::new("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let replay = path.clone();
+    let handle = thread::spawn(move || {
+        let mut source = FileSource::new(replay);
+        server.serve_client(&mut source).unwrap()
+    });
+
+    let mut source = RtlTcpSource::connect(addr).unwrap();
+    assert_eq!(source.info().tuner, TunerType::R820T);
+    assert_eq!(source.gains().len(), 29);
+    source.set_rate(1000000.0).unwrap();
+    source.set_freq(146520000.0).unwrap();
+    assert_eq!(source.rate(), 1000000.0);
+
+    let mut got: Vec<Complex<f32>> = Vec::new();
+    loop {
+        let buf = source.recv_block().unwrap();
+        if buf.len() == 0 {
+            break;
+        }
+        got.extend(buf.into_iter());
+    }
+    assert_eq!(handle.join().unwrap(), false);
+
+    let mut expect: Vec<u8> = Vec::new();
+    to_cu8(&samples, &mut expect);
+    let mut want: Vec<Complex<f32>> = Vec::new();
+    from_cu8(&expect, &mut want);
+    assert_eq!(got.len(), want.len());
+    for (a, b) in got.iter().zip(want.iter()) {
+        assert_eq!(a.i, b.i);
+        assert_eq!(a.q, b.q);
+    }
+    std::fs::remove_file(path).unwrap();
+}
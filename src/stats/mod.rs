@@ -0,0 +1,246 @@
+///! Activity statistics for monitored channels.
+///!
+///! The router registers every monitor when it starts and records each
+///! transmission here, published or not, so that the occupancy of each
+///! channel can be queried or exported. Channels which stay idle are still
+///! listed.
+///! All times are host times in seconds since the UNIX epoch.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std;
+
+const HOUR: f64 = 3600.0;
+const DAY: f64 = 86400.0;
+
+pub struct ChannelStats {
+    pub id:         usize,
+    pub freq:       f64,
+    ///! The time at which tracking of this channel started.
+    pub since:      f64,
+    ///! The time at which its monitor went away, if it has.
+    pub until:      Option<f64>,
+    ///! The number of transmissions.
+    pub count:      u64,
+    ///! The transmissions long enough to be published.
+    pub published:  u64,
+    ///! The total keyed time in seconds.
+    pub keyed:      f64,
+    ///! The length of the longest transmission in seconds.
+    pub longest:    f64,
+    ///! The keyed seconds for each hour of the day (UTC).
+    pub hourly:     [f64; 24],
+    ///! The transmissions started in each hour of the day (UTC).
+    pub hourly_count: [u64; 24],
+    ///! The `(start, duration)` of the transmissions within the last day.
+    history:        VecDeque<(f64, f64)>,
+}
+
+impl ChannelStats {
+    pub fn new(id: usize, freq: f64, since: f64) -> ChannelStats {
+        ChannelStats {
+            id:             id,
+            freq:           freq,
+            since:          since,
+            until:          Option::None,
+            count:          0,
+            published:      0,
+            keyed:          0.0,
+            longest:        0.0,
+            hourly:         [0.0; 24],
+            hourly_count:   [0; 24],
+            history:        VecDeque::new(),
+        }
+    }
+
+    pub fn record(&mut self, start: f64, duration: f64, published: bool) {
+        self.count += 1;
+        if published {
+            self.published += 1;
+        }
+        self.keyed += duration;
+        if duration > self.longest {
+            self.longest = duration;
+        }
+
+        self.hourly_count[((start / HOUR).floor() as u64 % 24) as usize] += 1;
+
+        // Spread the keyed time over the hours it covers.
+        let end = start + duration;
+        let mut t = start;
+        while t < end {
+            let next = ((t / HOUR).floor() + 1.0) * HOUR;
+            let seg = if next < end { next - t } else { end - t };
+            self.hourly[((t / HOUR).floor() as u64 % 24) as usize] += seg;
+            t = next;
+        }
+
+        self.history.push_back((start, duration));
+        while self.history.len() > 0 && self.history[0].0 + self.history[0].1 < end - DAY {
+            self.history.pop_front();
+        }
+    }
+
+    ///! The fraction of the `window` seconds up to `now` that the channel
+    ///! was keyed. The window may not exceed one day.
+    pub fn duty_cycle(&self, window: f64, now: f64) -> f64 {
+        let from = now - window;
+        let mut keyed = 0f64;
+        for &(s, d) in self.history.iter() {
+            let a = if s > from { s } else { from };
+            let b = if s + d < now { s + d } else { now };
+            if b > a {
+                keyed += b - a;
+            }
+        }
+        keyed / window
+    }
+
+    pub fn duty_hour(&self, now: f64) -> f64 {
+        self.duty_cycle(HOUR, now)
+    }
+
+    pub fn duty_day(&self, now: f64) -> f64 {
+        self.duty_cycle(DAY, now)
+    }
+}
+
+pub struct Stats {
+    channels:       BTreeMap<usize, ChannelStats>,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats { channels: BTreeMap::new() }
+    }
+
+    ///! Start tracking the monitor `id` on `freq` from `since`, unless it
+    ///! already is.
+    pub fn register(&mut self, id: usize, freq: f64, since: f64) {
+        self.channels.entry(id).or_insert_with(|| ChannelStats::new(id, freq, since));
+    }
+
+    ///! Note that the monitor `id` went away at `at`. What was recorded for
+    ///! it is kept.
+    pub fn retire(&mut self, id: usize, at: f64) {
+        match self.channels.get_mut(&id) {
+            Option::Some(ch) => ch.until = Option::Some(at),
+            Option::None => (),
+        }
+    }
+
+    ///! Record a transmission for the monitor `id` on `freq`.
+    pub fn record(&mut self, id: usize, freq: f64, start: f64, duration: f64, published: bool) {
+        let ch = self.channels.entry(id).or_insert_with(|| ChannelStats::new(id, freq, start));
+        ch.record(start, duration, published);
+    }
+
+    pub fn get(&self, id: usize) -> Option<&ChannelStats> {
+        self.channels.get(&id)
+    }
+
+    ///! Return the statistics of every channel ordered by id.
+    pub fn channels(&self) -> Vec<&ChannelStats> {
+        self.channels.values().collect()
+    }
+
+    ///! Export one line per channel with a header line.
+    pub fn to_csv(&self, now: f64) -> String {
+        let mut out = String::new();
+        out.push_str("id,freq,since,until,count,published,keyed,longest,duty_hour,duty_day");
+        for h in 0..24 {
+            write!(out, ",h{:02}", h).unwrap();
+        }
+        out.push_str("\n");
+        for ch in self.channels.values() {
+            write!(out, "{},{},{},{},{},{},{},{},{},{}",
+                ch.id, ch.freq, ch.since, ch.until.map(|t| t.to_string()).unwrap_or(String::new()), ch.count, ch.published, ch.keyed, ch.longest,
+                ch.duty_hour(now), ch.duty_day(now)
+            ).unwrap();
+            for h in 0..24 {
+                write!(out, ",{}", ch.hourly[h]).unwrap();
+            }
+            out.push_str("\n");
+        }
+        out
+    }
+
+    pub fn to_json(&self, now: f64) -> String {
+        let mut out = String::new();
+        out.push_str("[");
+        let mut first = true;
+        for ch in self.channels.values() {
+            if !first {
+                out.push_str(",");
+            }
+            first = false;
+            write!(out, "{{\"id\":{},\"freq\":{},\"since\":{},\"until\":{},\"count\":{},\"published\":{},\"keyed\":{},\"longest\":{},\"duty_hour\":{},\"duty_day\":{},\"hourly\":[",
+                ch.id, ch.freq, ch.since, ch.until.map(|t| t.to_string()).unwrap_or(String::from("null")),
+                ch.count, ch.published, ch.keyed, ch.longest, ch.duty_hour(now), ch.duty_day(now)
+            ).unwrap();
+            for h in 0..24 {
+                write!(out, "{}{}", if h > 0 { "," } else { "" }, ch.hourly[h]).unwrap();
+            }
+            out.push_str("],\"hourly_count\":[");
+            for h in 0..24 {
+                write!(out, "{}{}", if h > 0 { "," } else { "" }, ch.hourly_count[h]).unwrap();
+            }
+            out.push_str("]}");
+        }
+        out.push_str("]");
+        out
+    }
+}
+
+#[test]
+fn test_duty_cycle() {
+    let mut stats = Stats::new();
+    // Two transmissions of 90 seconds with the second crossing an hour.
+    stats.record(0, 146520000.0, 7200.0 * 3.0, 90.0, true);
+    stats.record(0, 146520000.0, 7200.0 * 4.0 - 45.0, 90.0, true);
+
+    let ch = stats.get(0).unwrap();
+    assert_eq!(ch.count, 2);
+    assert_eq!(ch.published, 2);
+    assert_eq!(ch.keyed, 180.0);
+    assert_eq!(ch.longest, 90.0);
+    assert_eq!(ch.hourly[6], 90.0);
+    assert_eq!(ch.hourly[7], 45.0);
+    assert_eq!(ch.hourly[8], 45.0);
+
+    let now = 7200.0 * 4.0 + 45.0;
+    assert!((ch.duty_hour(now) - 90.0 / 3600.0).abs() < 1e-9);
+    assert!((ch.duty_day(now) - 180.0 / 86400.0).abs() < 1e-9);
+}
+
+#[test]
+fn test_idle_channel() {
+    let mut stats = Stats::new();
+    stats.register(0, 146520000.0, 3600.0);
+    stats.register(1, 146940000.0, 3600.0);
+    // Too short to publish but the channel was still keyed.
+    stats.record(1, 146940000.0, 3700.0, 0.5, false);
+    // Registering again keeps what was recorded.
+    stats.register(1, 146940000.0, 3800.0);
+
+    let idle = stats.get(0).unwrap();
+    assert_eq!(idle.count, 0);
+    assert_eq!(idle.since, 3600.0);
+    assert_eq!(idle.duty_hour(7200.0), 0.0);
+
+    let busy = stats.get(1).unwrap();
+    assert_eq!(busy.count, 1);
+    assert_eq!(busy.published, 0);
+    assert_eq!(busy.keyed, 0.5);
+    assert_eq!(busy.since, 3600.0);
+
+    assert_eq!(stats.channels().len(), 2);
+    assert_eq!(stats.to_csv(7200.0).lines().count(), 3);
+    assert!(stats.to_json(7200.0).starts_with("[{\"id\":0,\"freq\":146520000,\"since\":3600,\"until\":null,\"count\":0,"));
+
+    // A monitor that went away stays listed.
+    stats.retire(1, 5400.0);
+    assert_eq!(stats.get(1).unwrap().until, Option::Some(5400.0));
+    assert_eq!(stats.get(1).unwrap().count, 1);
+    assert!(stats.to_csv(7200.0).lines().nth(2).unwrap().starts_with("1,146940000,3600,5400,1,0,"));
+}
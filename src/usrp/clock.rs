@@ -0,0 +1,224 @@
+///! Reference clock and device time configuration.
+///!
+///! The device counts time from whenever it was powered up unless told
+///! otherwise. `align_time` sets it to UTC, either roughly from the host
+///! clock or exactly on a PPS edge, so that the timestamps of received
+///! samples can be compared between stations.
+
+use super::sys;
+use super::{USRPSource, UhdError, TimeSpec, StreamMode, SensorScope, ALL_MBOARDS};
+use super::error::check_usrp;
+use ::time;
+use std::ffi::CString;
+use std;
+
+///! How the device time is set at startup.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeAlign {
+    ///! Leave the device time alone.
+    None,
+    ///! Set the time from the host clock. This is only as good as the host
+    ///! clock and the latency of the call, a few milliseconds at best.
+    HostUtc,
+    ///! Set the time on the next PPS edge. The seconds are taken from the
+    ///! GPS receiver if the device has one and otherwise from the host
+    ///! clock, which must then be within half a second of UTC.
+    Pps,
+}
+
+#[derive(Clone, Debug)]
+pub struct TimeConfig {
+    ///! The reference clock such as `internal`, `external` or `gpsdo`.
+    pub clock_source:   Option<String>,
+    ///! The PPS source such as `internal`, `external` or `gpsdo`.
+    pub time_source:    Option<String>,
+    pub align:          TimeAlign,
+}
+
+impl TimeConfig {
+    pub fn new() -> TimeConfig {
+        TimeConfig {
+            clock_source:   Option::None,
+            time_source:    Option::None,
+            align:          TimeAlign::None,
+        }
+    }
+
+    ///! Take the clock and PPS from a GPS disciplined oscillator.
+    pub fn gpsdo() -> TimeConfig {
+        TimeConfig {
+            clock_source:   Option::Some(String::from("gpsdo")),
+            time_source:    Option::Some(String::from("gpsdo")),
+            align:          TimeAlign::Pps,
+        }
+    }
+}
+
+///! Seconds allowed for the reference clock to lock after it is changed.
+const REF_LOCK_TIMEOUT: f64 = 5.0;
+
+fn host_time() -> TimeSpec {
+    let t = time::get_time();
+    TimeSpec::new(t.sec, t.nsec as f64 / 1e9)
+}
+
+impl USRPSource {
+    pub fn set_clock_source(&mut self, source: &str) -> Result<(), UhdError> {
+        unsafe {
+            check_usrp(self.usrp_handle, sys::uhd_usrp_set_clock_source(
+                self.usrp_handle, CString::new(source).unwrap().as_ptr(), ALL_MBOARDS
+            ))
+        }
+    }
+
+    pub fn set_time_source(&mut self, source: &str) -> Result<(), UhdError> {
+        unsafe {
+            check_usrp(self.usrp_handle, sys::uhd_usrp_set_time_source(
+                self.usrp_handle, CString::new(source).unwrap().as_ptr(), ALL_MBOARDS
+            ))
+        }
+    }
+
+    pub fn set_time_now(&mut self, t: TimeSpec) -> Result<(), UhdError> {
+        unsafe {
+            check_usrp(self.usrp_handle, sys::uhd_usrp_set_time_now(
+                self.usrp_handle, t.full_secs as sys::time_t, t.frac_secs, ALL_MBOARDS
+            ))
+        }
+    }
+
+    ///! Set the device time to `t` on the next PPS edge.
+    pub fn set_time_next_pps(&mut self, t: TimeSpec) -> Result<(), UhdError> {
+        unsafe {
+            check_usrp(self.usrp_handle, sys::uhd_usrp_set_time_next_pps(
+                self.usrp_handle, t.full_secs as sys::time_t, t.frac_secs, ALL_MBOARDS
+            ))
+        }
+    }
+
+    ///! Wait for a PPS edge and set the device time to `t` on the one after.
+    ///! This blocks for up to two seconds.
+    pub fn set_time_unknown_pps(&mut self, t: TimeSpec) -> Result<(), UhdError> {
+        unsafe {
+            check_usrp(self.usrp_handle, sys::uhd_usrp_set_time_unknown_pps(
+                self.usrp_handle, t.full_secs as sys::time_t, t.frac_secs
+            ))
+        }
+    }
+
+    ///! Return the device time latched on the last PPS edge.
+    pub fn time_last_pps(&self) -> Result<TimeSpec, UhdError> {
+        let mut full: sys::time_t = 0;
+        let mut frac: f64 = 0.0;
+        unsafe {
+            try!(check_usrp(self.usrp_handle, sys::uhd_usrp_get_time_last_pps(self.usrp_handle, 0, &mut full, &mut frac)));
+        }
+        Result::Ok(TimeSpec::new(full as i64, frac))
+    }
+
+    ///! Return true if the time of every motherboard is the same.
+    pub fn time_synchronized(&self) -> Result<bool, UhdError> {
+        let mut out: u8 = 0;
+        unsafe {
+            try!(check_usrp(self.usrp_handle, sys::uhd_usrp_get_time_synchronized(self.usrp_handle, &mut out)));
+        }
+        Result::Ok(out != 0)
+    }
+
+    ///! Wait until the PPS edge after the current one has been seen so a
+    ///! time set for the next PPS has most of a second to get there.
+    fn wait_pps_edge(&self) -> Result<(), UhdError> {
+        let last = try!(self.time_last_pps());
+        for _ in 0..150 {
+            if try!(self.time_last_pps()) != last {
+                return Result::Ok(());
+            }
+            std::thread::sleep_ms(10);
+        }
+        Result::Err(UhdError::NoPps(String::from("no PPS edge seen within 1.5 seconds")))
+    }
+
+    ///! Wait up to `timeout` seconds for the reference clock to lock. A
+    ///! device without a `ref_locked` sensor is taken to be locked.
+    fn wait_ref_locked(&self, timeout: f64) -> Result<(), UhdError> {
+        let polls = (timeout * 100.0).ceil() as u32;
+        for _ in 0..polls {
+            match try!(self.ref_locked()) {
+                Option::Some(false) => std::thread::sleep_ms(10),
+                _ => return Result::Ok(()),
+            }
+        }
+        Result::Err(UhdError::NoRefLock(format!("reference clock not locked within {} seconds", timeout)))
+    }
+
+    ///! Apply the clock and time sources of `cfg` and set the device time.
+    ///! Streaming is stopped while the time changes and started again at a
+    ///! device time so all channels stay aligned, also when aligning fails.
+    ///! Returns the device time once done.
+    pub fn align_time(&mut self, cfg: &TimeConfig) -> Result<TimeSpec, UhdError> {
+        try!(self.issue_stream_cmd(StreamMode::StopContinuous, 0, Option::None));
+
+        let aligned = self.set_times(cfg);
+
+        // The timestamps may have jumped so gaps measured across them mean
+        // nothing.
+        self.next_time = Option::None;
+
+        let start = match self.time_now() {
+            Result::Ok(now) => Option::Some(TimeSpec::from_secs(now.as_secs() + 0.1)),
+            Result::Err(_) => Option::None,
+        };
+        let started = self.issue_stream_cmd(StreamMode::StartContinuous, 0, start);
+
+        let now = try!(aligned);
+        try!(started);
+        Result::Ok(now)
+    }
+
+    ///! The part of `align_time` done while streaming is stopped.
+    fn set_times(&mut self, cfg: &TimeConfig) -> Result<TimeSpec, UhdError> {
+        match cfg.clock_source {
+            Option::Some(ref s) => {
+                try!(self.set_clock_source(s));
+                // Timestamps taken before the lock would drift.
+                try!(self.wait_ref_locked(REF_LOCK_TIMEOUT));
+            },
+            Option::None => (),
+        }
+        match cfg.time_source {
+            Option::Some(ref s) => try!(self.set_time_source(s)),
+            Option::None => (),
+        }
+
+        match cfg.align {
+            TimeAlign::None => (),
+            TimeAlign::HostUtc => {
+                try!(self.set_time_now(host_time()));
+            },
+            TimeAlign::Pps => {
+                try!(self.wait_pps_edge());
+                // Right after an edge, so the next edge is the next second.
+                let gps = try!(self.sensor_names(SensorScope::Mboard(0))).iter().any(|n| n == "gps_time");
+                let secs = if gps {
+                    match try!(self.sensor(SensorScope::Mboard(0), "gps_time")).as_real() {
+                        Option::Some(v) => v as i64,
+                        Option::None => host_time().as_secs().round() as i64,
+                    }
+                } else {
+                    host_time().as_secs().round() as i64
+                };
+                try!(self.set_time_next_pps(TimeSpec::new(secs + 1, 0.0)));
+                // Let the edge pass before anything relies on the time.
+                std::thread::sleep_ms(1100);
+            },
+        }
+
+        if !try!(self.time_synchronized()) {
+            println!("[usrp] device time is not synchronized across motherboards");
+        }
+
+        let now = try!(self.time_now());
+        println!("[usrp] device time {} host time {}", now.as_secs(), host_time().as_secs());
+        Result::Ok(now)
+    }
+}
@@ -0,0 +1,231 @@
+///! Discovery and selection of USRP devices.
+///!
+///! UHD reports the devices it can see as `key=value` strings. These are
+///! parsed into `DeviceInfo` and matched against `DeviceArgs` to decide
+///! which device is opened.
+
+use super::sys;
+use super::read_string_vector;
+use std::ffi::CString;
+use std;
+
+///! A device as reported by `uhd_usrp_find`.
+#[derive(Clone)]
+pub struct DeviceInfo {
+    pub serial:     String,
+    ///! The device type such as `b200` or `usrp2`.
+    pub kind:       String,
+    ///! The network address if the device has one.
+    pub addr:       String,
+    pub name:       String,
+    ///! Every key and value reported for the device.
+    pub pairs:      Vec<(String, String)>,
+}
+
+impl DeviceInfo {
+    ///! Parse a `key=value,key=value` device string.
+    pub fn parse(s: &str) -> DeviceInfo {
+        let mut pairs: Vec<(String, String)> = Vec::new();
+        for part in s.split(',') {
+            let mut kv = part.splitn(2, '=');
+            let k = kv.next().unwrap_or("").trim();
+            let v = kv.next().unwrap_or("").trim();
+            if k.len() > 0 {
+                pairs.push((String::from(k), String::from(v)));
+            }
+        }
+
+        let mut info = DeviceInfo {
+            serial:     String::new(),
+            kind:       String::new(),
+            addr:       String::new(),
+            name:       String::new(),
+            pairs:      Vec::new(),
+        };
+
+        for &(ref k, ref v) in pairs.iter() {
+            match &k[..] {
+                "serial" => info.serial = v.clone(),
+                "type" => info.kind = v.clone(),
+                "addr" => info.addr = v.clone(),
+                "name" => info.name = v.clone(),
+                _ => (),
+            }
+        }
+
+        info.pairs = pairs;
+        info
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        for &(ref k, ref v) in self.pairs.iter() {
+            if k == key {
+                return Option::Some(&v[..]);
+            }
+        }
+        Option::None
+    }
+}
+
+///! Which device to open and how. Fields left as `None` match any device.
+#[derive(Clone)]
+pub struct DeviceArgs {
+    pub serial:             Option<String>,
+    pub addr:               Option<String>,
+    pub kind:               Option<String>,
+    pub master_clock_rate:  Option<f64>,
+}
+
+impl DeviceArgs {
+    pub fn new() -> DeviceArgs {
+        DeviceArgs {
+            serial:             Option::None,
+            addr:               Option::None,
+            kind:               Option::None,
+            master_clock_rate:  Option::None,
+        }
+    }
+
+    pub fn serial(serial: &str) -> DeviceArgs {
+        let mut args = DeviceArgs::new();
+        args.serial = Option::Some(String::from(serial));
+        args
+    }
+
+    pub fn addr(addr: &str) -> DeviceArgs {
+        let mut args = DeviceArgs::new();
+        args.addr = Option::Some(String::from(addr));
+        args
+    }
+
+    ///! Format as the argument string understood by UHD.
+    pub fn to_args(&self) -> String {
+        let mut parts: Vec<String> = Vec::new();
+        match self.serial {
+            Option::Some(ref v) => parts.push(format!("serial={}", v)),
+            Option::None => (),
+        }
+        match self.addr {
+            Option::Some(ref v) => parts.push(format!("addr={}", v)),
+            Option::None => (),
+        }
+        match self.kind {
+            Option::Some(ref v) => parts.push(format!("type={}", v)),
+            Option::None => (),
+        }
+        match self.master_clock_rate {
+            Option::Some(v) => parts.push(format!("master_clock_rate={}", v)),
+            Option::None => (),
+        }
+        parts.join(",")
+    }
+
+    pub fn matches(&self, dev: &DeviceInfo) -> bool {
+        let checks = [
+            (&self.serial, &dev.serial),
+            (&self.addr, &dev.addr),
+            (&self.kind, &dev.kind),
+        ];
+        for &(want, have) in checks.iter() {
+            match *want {
+                Option::Some(ref v) if v != have => return false,
+                _ => (),
+            }
+        }
+        true
+    }
+}
+
+///! The calls used to find devices. This allows the selection logic to be
+///! exercised without UHD or any hardware.
+pub trait Backend {
+    ///! Return the device strings of the devices matching `args`.
+    fn find(&self, args: &str) -> Vec<String>;
+}
+
+///! Finds devices using UHD.
+pub struct UhdBackend;
+
+impl Backend for UhdBackend {
+    fn find(&self, args: &str) -> Vec<String> {
+        unsafe {
+            let mut h: sys::uhd_string_vector_handle = std::mem::zeroed();
+            sys::uhd_string_vector_make(&mut h);
+            sys::uhd_usrp_find(CString::new(args).unwrap().as_ptr(), &mut h);
+            let out = read_string_vector(h);
+            sys::uhd_string_vector_free(&mut h);
+            out
+        }
+    }
+}
+
+///! Return the devices visible through `backend` that match `args`.
+pub fn find_with(backend: &Backend, args: &DeviceArgs) -> Vec<DeviceInfo> {
+    let mut out: Vec<DeviceInfo> = Vec::new();
+    for s in backend.find(&args.to_args()).iter() {
+        let dev = DeviceInfo::parse(s);
+        if args.matches(&dev) {
+            out.push(dev);
+        }
+    }
+    out
+}
+
+///! Return the devices visible to UHD that match `args`.
+pub fn find(args: &DeviceArgs) -> Vec<DeviceInfo> {
+    find_with(&UhdBackend, args)
+}
+
+///! Pick the device to open for `args`. With no explicit selection the
+///! first device found is used.
+pub fn select_with(backend: &Backend, args: &DeviceArgs) -> Option<DeviceInfo> {
+    let mut devs = find_with(backend, args);
+    if devs.len() > 1 {
+        println!("[usrp] {} devices match, using the first one", devs.len());
+    }
+    if devs.len() > 0 {
+        Option::Some(devs.remove(0))
+    } else {
+        Option::None
+    }
+}
+
+///! Return the arguments for `uhd_usrp_make` that open exactly `dev`.
+pub fn make_args(dev: &DeviceInfo, args: &DeviceArgs) -> String {
+    let mut exact = args.clone();
+    if dev.serial.len() > 0 {
+        exact.serial = Option::Some(dev.serial.clone());
+    } else if dev.addr.len() > 0 {
+        exact.addr = Option::Some(dev.addr.clone());
+    }
+    exact.to_args()
+}
+
+#[test]
+fn test_select() {
+    struct Stub;
+
+    impl Backend for Stub {
+        fn find(&self, args: &str) -> Vec<String> {
+            vec![
+                String::from("type=b200,name=,serial=30AD2C5,product=B210"),
+                String::from("addr=192.168.10.2,type=usrp2,name=roof,serial=F4A0A1"),
+            ]
+        }
+    }
+
+    let dev = select_with(&Stub, &DeviceArgs::new()).unwrap();
+    assert_eq!(dev.serial, "30AD2C5");
+    assert_eq!(dev.get("product"), Option::Some("B210"));
+
+    let dev = select_with(&Stub, &DeviceArgs::addr("192.168.10.2")).unwrap();
+    assert_eq!(dev.kind, "usrp2");
+    assert_eq!(dev.name, "roof");
+
+    let mut args = DeviceArgs::serial("F4A0A1");
+    args.master_clock_rate = Option::Some(32e6);
+    let dev = select_with(&Stub, &args).unwrap();
+    assert_eq!(make_args(&dev, &args), "serial=F4A0A1,master_clock_rate=32000000");
+
+    assert!(select_with(&Stub, &DeviceArgs::serial("nope")).is_none());
+}
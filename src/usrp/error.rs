@@ -0,0 +1,168 @@
+///! Errors reported by UHD.
+///!
+///! Each `UHD_ERROR_*` code is mapped onto a variant carrying the last
+///! error string UHD recorded for the failed call.
+
+use super::sys;
+use super::read_cstr;
+use ::libc;
+use std::fmt;
+use std::error::Error;
+use std;
+
+#[derive(Debug, Clone)]
+pub enum UhdError {
+    InvalidDevice(String),
+    Index(String),
+    Key(String),
+    NotImplemented(String),
+    Usb(String),
+    Io(String),
+    Os(String),
+    Assertion(String),
+    Lookup(String),
+    Type(String),
+    Value(String),
+    Runtime(String),
+    Environment(String),
+    System(String),
+    Except(String),
+    BoostExcept(String),
+    StdExcept(String),
+    Unknown(u32, String),
+    ///! No device matched the requested device args.
+    NoDevice(String),
+    ///! No PPS edge arrived while aligning the device time.
+    NoPps(String),
+    ///! The reference clock did not lock while aligning the device time.
+    NoRefLock(String),
+}
+
+impl UhdError {
+    ///! Map a UHD error code with its message.
+    pub fn from_code(code: sys::uhd_error, msg: String) -> UhdError {
+        match code {
+            sys::UHD_ERROR_INVALID_DEVICE => UhdError::InvalidDevice(msg),
+            sys::UHD_ERROR_INDEX => UhdError::Index(msg),
+            sys::UHD_ERROR_KEY => UhdError::Key(msg),
+            sys::UHD_ERROR_NOT_IMPLEMENTED => UhdError::NotImplemented(msg),
+            sys::UHD_ERROR_USB => UhdError::Usb(msg),
+            sys::UHD_ERROR_IO => UhdError::Io(msg),
+            sys::UHD_ERROR_OS => UhdError::Os(msg),
+            sys::UHD_ERROR_ASSERTION => UhdError::Assertion(msg),
+            sys::UHD_ERROR_LOOKUP => UhdError::Lookup(msg),
+            sys::UHD_ERROR_TYPE => UhdError::Type(msg),
+            sys::UHD_ERROR_VALUE => UhdError::Value(msg),
+            sys::UHD_ERROR_RUNTIME => UhdError::Runtime(msg),
+            sys::UHD_ERROR_ENVIRONMENT => UhdError::Environment(msg),
+            sys::UHD_ERROR_SYSTEM => UhdError::System(msg),
+            sys::UHD_ERROR_EXCEPT => UhdError::Except(msg),
+            sys::UHD_ERROR_BOOSTEXCEPT => UhdError::BoostExcept(msg),
+            sys::UHD_ERROR_STDEXCEPT => UhdError::StdExcept(msg),
+            _ => UhdError::Unknown(code as u32, msg),
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match *self {
+            UhdError::InvalidDevice(ref m) | UhdError::Index(ref m) |
+            UhdError::Key(ref m) | UhdError::NotImplemented(ref m) |
+            UhdError::Usb(ref m) | UhdError::Io(ref m) | UhdError::Os(ref m) |
+            UhdError::Assertion(ref m) | UhdError::Lookup(ref m) |
+            UhdError::Type(ref m) | UhdError::Value(ref m) |
+            UhdError::Runtime(ref m) | UhdError::Environment(ref m) |
+            UhdError::System(ref m) | UhdError::Except(ref m) |
+            UhdError::BoostExcept(ref m) | UhdError::StdExcept(ref m) |
+            UhdError::Unknown(_, ref m) | UhdError::NoDevice(ref m) |
+            UhdError::NoPps(ref m) | UhdError::NoRefLock(ref m) => &m[..],
+        }
+    }
+}
+
+impl fmt::Display for UhdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.description(), self.message())
+    }
+}
+
+impl Error for UhdError {
+    fn description(&self) -> &str {
+        match *self {
+            UhdError::InvalidDevice(_) => "invalid device",
+            UhdError::Index(_) => "index error",
+            UhdError::Key(_) => "key error",
+            UhdError::NotImplemented(_) => "not implemented",
+            UhdError::Usb(_) => "USB error",
+            UhdError::Io(_) => "I/O error",
+            UhdError::Os(_) => "OS error",
+            UhdError::Assertion(_) => "assertion failed",
+            UhdError::Lookup(_) => "lookup error",
+            UhdError::Type(_) => "type error",
+            UhdError::Value(_) => "value error",
+            UhdError::Runtime(_) => "runtime error",
+            UhdError::Environment(_) => "environment error",
+            UhdError::System(_) => "system error",
+            UhdError::Except(_) => "UHD exception",
+            UhdError::BoostExcept(_) => "boost exception",
+            UhdError::StdExcept(_) => "std exception",
+            UhdError::Unknown(_, _) => "unknown error",
+            UhdError::NoDevice(_) => "no device",
+            UhdError::NoPps(_) => "no PPS",
+            UhdError::NoRefLock(_) => "reference not locked",
+        }
+    }
+}
+
+///! Fetch a last error string through `f` which is given a buffer and its
+///! length.
+fn last_error<F>(f: F) -> String where F: FnOnce(*mut libc::c_char, sys::size_t) -> sys::uhd_error {
+    let mut buf = [0 as libc::c_char; 1024];
+    f(buf.as_mut_ptr(), buf.len() as sys::size_t);
+    read_cstr(&buf)
+}
+
+///! Turn the code returned by a UHD call into a `Result` using the global
+///! last error string.
+pub fn check(code: sys::uhd_error) -> Result<(), UhdError> {
+    if code == sys::UHD_ERROR_NONE {
+        return Result::Ok(());
+    }
+    let msg = last_error(|buf, len| unsafe { sys::uhd_get_last_error(buf, len) });
+    Result::Err(UhdError::from_code(code, msg))
+}
+
+///! Like `check` but prefers the last error recorded on the usrp handle.
+pub fn check_usrp(h: sys::uhd_usrp_handle, code: sys::uhd_error) -> Result<(), UhdError> {
+    if code == sys::UHD_ERROR_NONE {
+        return Result::Ok(());
+    }
+    let mut msg = last_error(|buf, len| unsafe { sys::uhd_usrp_last_error(h, buf, len) });
+    if msg.len() == 0 {
+        msg = last_error(|buf, len| unsafe { sys::uhd_get_last_error(buf, len) });
+    }
+    Result::Err(UhdError::from_code(code, msg))
+}
+
+///! Like `check` but prefers the last error recorded on the rx streamer.
+pub fn check_rx(h: sys::uhd_rx_streamer_handle, code: sys::uhd_error) -> Result<(), UhdError> {
+    if code == sys::UHD_ERROR_NONE {
+        return Result::Ok(());
+    }
+    let mut msg = last_error(|buf, len| unsafe { sys::uhd_rx_streamer_last_error(h, buf, len) });
+    if msg.len() == 0 {
+        msg = last_error(|buf, len| unsafe { sys::uhd_get_last_error(buf, len) });
+    }
+    Result::Err(UhdError::from_code(code, msg))
+}
+
+///! Like `check` but prefers the last error recorded on the tx streamer.
+pub fn check_tx(h: sys::uhd_tx_streamer_handle, code: sys::uhd_error) -> Result<(), UhdError> {
+    if code == sys::UHD_ERROR_NONE {
+        return Result::Ok(());
+    }
+    let mut msg = last_error(|buf, len| unsafe { sys::uhd_tx_streamer_last_error(h, buf, len) });
+    if msg.len() == 0 {
+        msg = last_error(|buf, len| unsafe { sys::uhd_get_last_error(buf, len) });
+    }
+    Result::Err(UhdError::from_code(code, msg))
+}
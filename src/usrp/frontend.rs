@@ -0,0 +1,159 @@
+///! Control of the analog front end of each receive channel.
+///!
+///! Every setting is checked against what the device reports it supports
+///! before it is applied, so a bad configuration fails with a clear
+///! `UhdError::Value` rather than being silently coerced by the driver.
+
+use super::sys;
+use super::{USRPSource, UhdError, read_cstr, read_string_vector};
+use super::error::{check, check_usrp};
+use ::libc;
+use std::ffi::CString;
+use std;
+
+///! Front end settings for one channel. Settings left as `None` are not
+///! changed from what the device starts with.
+#[derive(Clone, Debug)]
+pub struct FrontEnd {
+    pub antenna:        Option<String>,
+    ///! The analog bandwidth in Hz.
+    pub bandwidth:      Option<f64>,
+    ///! Automatic DC offset correction.
+    pub dc_offset:      Option<bool>,
+    ///! Automatic IQ imbalance correction.
+    pub iq_balance:     Option<bool>,
+}
+
+impl FrontEnd {
+    pub fn new() -> FrontEnd {
+        FrontEnd {
+            antenna:        Option::None,
+            bandwidth:      Option::None,
+            dc_offset:      Option::None,
+            iq_balance:     Option::None,
+        }
+    }
+}
+
+///! Read a `(start, stop, step)` range filled in by `f`.
+fn read_range<F>(f: F) -> Result<(f64, f64, f64), UhdError> where F: FnOnce(sys::uhd_meta_range_handle) -> Result<(), UhdError> {
+    unsafe {
+        let mut range: sys::uhd_meta_range_handle = std::mem::zeroed();
+        try!(check(sys::uhd_meta_range_make(&mut range)));
+        let mut out = (0f64, 0f64, 0f64);
+        let res = f(range)
+            .and_then(|_| check(sys::uhd_meta_range_start(range, &mut out.0)))
+            .and_then(|_| check(sys::uhd_meta_range_stop(range, &mut out.1)))
+            .and_then(|_| check(sys::uhd_meta_range_step(range, &mut out.2)));
+        sys::uhd_meta_range_free(&mut range);
+        try!(res);
+        Result::Ok(out)
+    }
+}
+
+impl USRPSource {
+    ///! Return the antenna ports of the channel at `index`.
+    pub fn rx_antennas(&self, index: usize) -> Result<Vec<String>, UhdError> {
+        unsafe {
+            let mut v: sys::uhd_string_vector_handle = std::mem::zeroed();
+            try!(check(sys::uhd_string_vector_make(&mut v)));
+            let res = check_usrp(self.usrp_handle, sys::uhd_usrp_get_rx_antennas(self.usrp_handle, self.channels[index], &mut v));
+            let out = read_string_vector(v);
+            sys::uhd_string_vector_free(&mut v);
+            try!(res);
+            Result::Ok(out)
+        }
+    }
+
+    pub fn rx_antenna(&self, index: usize) -> Result<String, UhdError> {
+        let mut buf = [0 as libc::c_char; 256];
+        unsafe {
+            try!(check_usrp(self.usrp_handle, sys::uhd_usrp_get_rx_antenna(
+                self.usrp_handle, self.channels[index], buf.as_mut_ptr(), buf.len() as sys::size_t
+            )));
+        }
+        Result::Ok(read_cstr(&buf))
+    }
+
+    pub fn set_rx_antenna(&mut self, index: usize, antenna: &str) -> Result<(), UhdError> {
+        let all = try!(self.rx_antennas(index));
+        if !all.iter().any(|a| a == antenna) {
+            return Result::Err(UhdError::Value(format!("antenna {} not one of {}", antenna, all.join(", "))));
+        }
+        unsafe {
+            check_usrp(self.usrp_handle, sys::uhd_usrp_set_rx_antenna(
+                self.usrp_handle, CString::new(antenna).unwrap().as_ptr(), self.channels[index]
+            ))
+        }
+    }
+
+    ///! Return the `(start, stop, step)` of the analog bandwidth in Hz.
+    pub fn rx_bandwidth_range(&self, index: usize) -> Result<(f64, f64, f64), UhdError> {
+        let h = self.usrp_handle;
+        let chan = self.channels[index];
+        read_range(|range| unsafe { check_usrp(h, sys::uhd_usrp_get_rx_bandwidth_range(h, chan, range)) })
+    }
+
+    pub fn rx_bandwidth(&self, index: usize) -> Result<f64, UhdError> {
+        let mut out: f64 = 0.0;
+        unsafe {
+            try!(check_usrp(self.usrp_handle, sys::uhd_usrp_get_rx_bandwidth(self.usrp_handle, self.channels[index], &mut out)));
+        }
+        Result::Ok(out)
+    }
+
+    pub fn set_rx_bandwidth(&mut self, index: usize, bandwidth: f64) -> Result<(), UhdError> {
+        let (start, stop, _) = try!(self.rx_bandwidth_range(index));
+        if bandwidth < start || bandwidth > stop {
+            return Result::Err(UhdError::Value(format!("bandwidth {} outside of {} to {}", bandwidth, start, stop)));
+        }
+        unsafe {
+            check_usrp(self.usrp_handle, sys::uhd_usrp_set_rx_bandwidth(self.usrp_handle, bandwidth, self.channels[index]))
+        }
+    }
+
+    ///! Return the `(start, stop, step)` of the frequencies the channel at
+    ///! `index` can tune to.
+    pub fn rx_freq_range(&self, index: usize) -> Result<(f64, f64, f64), UhdError> {
+        let h = self.usrp_handle;
+        let chan = self.channels[index];
+        read_range(|range| unsafe { check_usrp(h, sys::uhd_usrp_get_rx_freq_range(h, chan, range)) })
+    }
+
+    pub fn set_rx_dc_offset(&mut self, index: usize, enable: bool) -> Result<(), UhdError> {
+        unsafe {
+            check_usrp(self.usrp_handle, sys::uhd_usrp_set_rx_dc_offset_enabled(
+                self.usrp_handle, if enable { 1 } else { 0 }, self.channels[index]
+            ))
+        }
+    }
+
+    pub fn set_rx_iq_balance(&mut self, index: usize, enable: bool) -> Result<(), UhdError> {
+        unsafe {
+            check_usrp(self.usrp_handle, sys::uhd_usrp_set_rx_iq_balance_enabled(
+                self.usrp_handle, if enable { 1 } else { 0 }, self.channels[index]
+            ))
+        }
+    }
+
+    ///! Apply every setting of `fe` to the channel at `index`.
+    pub fn set_frontend(&mut self, index: usize, fe: &FrontEnd) -> Result<(), UhdError> {
+        match fe.antenna {
+            Option::Some(ref ant) => try!(self.set_rx_antenna(index, ant)),
+            Option::None => (),
+        }
+        match fe.bandwidth {
+            Option::Some(bw) => try!(self.set_rx_bandwidth(index, bw)),
+            Option::None => (),
+        }
+        match fe.dc_offset {
+            Option::Some(v) => try!(self.set_rx_dc_offset(index, v)),
+            Option::None => (),
+        }
+        match fe.iq_balance {
+            Option::Some(v) => try!(self.set_rx_iq_balance(index, v)),
+            Option::None => (),
+        }
+        Result::Ok(())
+    }
+}
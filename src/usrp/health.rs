@@ -0,0 +1,141 @@
+///! Periodic checking of the state of a receiver.
+///!
+///! A receiver whose LO or reference has lost lock keeps producing samples
+///! but nothing useful is in them. `HealthCheck` polls the sensors now and
+///! then, warns about anything wrong and re-tunes channels that lost lock.
+
+use super::{USRPSource, UhdError};
+
+///! The shortest interval between checks. A check reads several sensors,
+///! each a round trip to the device taking up to milliseconds, and is made
+///! from the receive loop, so it must stay rare next to the receives.
+pub const MIN_INTERVAL: f64 = 1.0;
+
+///! What the sensors reported. Sensors the device does not have are `None`.
+#[derive(Clone, Debug)]
+pub struct Health {
+    ///! The lock state of the LO of each channel.
+    pub lo_locked:      Vec<Option<bool>>,
+    pub ref_locked:     Option<bool>,
+    pub gps_locked:     Option<bool>,
+    ///! The board temperature in degrees Celsius.
+    pub temperature:    Option<f64>,
+}
+
+impl Health {
+    ///! True if no LO or reference reported being unlocked. The GPS is not
+    ///! considered since many stations run without one.
+    pub fn ok(&self) -> bool {
+        !self.lo_locked.iter().any(|l| *l == Option::Some(false)) &&
+        self.ref_locked != Option::Some(false)
+    }
+}
+
+pub struct HealthCheck {
+    ///! Seconds between checks, no less than `MIN_INTERVAL`.
+    pub interval:       f64,
+    ///! If set then channels whose LO lost lock are re-tuned.
+    pub retune:         bool,
+    ///! Warn when the temperature exceeds this many degrees Celsius.
+    pub max_temperature: f64,
+    ///! The number of times a channel was found unlocked.
+    pub unlocks:        u64,
+    last:               f64,
+}
+
+impl HealthCheck {
+    pub fn new(interval: f64) -> HealthCheck {
+        HealthCheck {
+            interval:           if interval > MIN_INTERVAL { interval } else { MIN_INTERVAL },
+            retune:             true,
+            max_temperature:    75.0,
+            unlocks:            0,
+            last:               0.0,
+        }
+    }
+
+    ///! True if `interval` seconds have passed since the last check at the
+    ///! host time `now`, which then counts as the time of the last check.
+    pub fn due(&mut self, now: f64) -> bool {
+        let interval = if self.interval > MIN_INTERVAL { self.interval } else { MIN_INTERVAL };
+        if now - self.last < interval {
+            return false;
+        }
+        self.last = now;
+        true
+    }
+
+    ///! Read the sensors of `usrp` if they are `due`. Between checks this
+    ///! only compares times, so it is cheap enough for every receive.
+    pub fn poll(&mut self, usrp: &mut USRPSource, now: f64) -> Option<Health> {
+        if !self.due(now) {
+            return Option::None;
+        }
+
+        match self.check(usrp) {
+            Result::Ok(health) => Option::Some(health),
+            Result::Err(err) => {
+                println!("[usrp] health check failed: {}", err);
+                Option::None
+            },
+        }
+    }
+
+    ///! Read the sensors of `usrp` now, warn about any problem and re-tune
+    ///! unlocked channels.
+    pub fn check(&mut self, usrp: &mut USRPSource) -> Result<Health, UhdError> {
+        let mut health = Health {
+            lo_locked:      Vec::new(),
+            ref_locked:     try!(usrp.ref_locked()),
+            gps_locked:     try!(usrp.gps_locked()),
+            temperature:    try!(usrp.temperature()),
+        };
+
+        for index in 0..usrp.num_channels() {
+            let locked = try!(usrp.lo_locked(index));
+            if locked == Option::Some(false) {
+                self.unlocks += 1;
+                println!("[usrp] LO of channel {} is not locked", index);
+                if self.retune {
+                    try!(usrp.retune(index));
+                }
+            }
+            health.lo_locked.push(locked);
+        }
+
+        if health.ref_locked == Option::Some(false) {
+            println!("[usrp] reference clock is not locked");
+        }
+
+        match health.temperature {
+            Option::Some(t) if t > self.max_temperature => {
+                println!("[usrp] temperature is {} C", t);
+            },
+            _ => (),
+        }
+
+        Result::Ok(health)
+    }
+}
+
+#[test]
+fn test_health_due() {
+    let mut hc = HealthCheck::new(10.0);
+    assert!(hc.due(1000.0));
+    assert!(!hc.due(1000.5));
+    assert!(!hc.due(1009.9));
+    assert!(hc.due(1010.0));
+
+    // Asking for checks on every receive still leaves most of them out.
+    let mut hc = HealthCheck::new(0.0);
+    assert_eq!(hc.interval, MIN_INTERVAL);
+    hc.interval = 0.001;
+    let mut checks = 0;
+    for x in 0..10000 {
+        // A receive every millisecond.
+        if hc.due(1000.0 + x as f64 * 0.001) {
+            checks += 1;
+        }
+    }
+    assert!(checks <= 10);
+}
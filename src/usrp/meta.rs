@@ -0,0 +1,230 @@
+///! Metadata describing received samples.
+
+use super::sys;
+use super::error::{UhdError, check};
+use std;
+
+///! A device time as whole and fractional seconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeSpec {
+    pub full_secs:  i64,
+    pub frac_secs:  f64,
+}
+
+impl TimeSpec {
+    pub fn new(full_secs: i64, frac_secs: f64) -> TimeSpec {
+        TimeSpec { full_secs: full_secs, frac_secs: frac_secs }
+    }
+
+    pub fn from_secs(secs: f64) -> TimeSpec {
+        let full = secs.floor();
+        TimeSpec { full_secs: full as i64, frac_secs: secs - full }
+    }
+
+    pub fn as_secs(&self) -> f64 {
+        self.full_secs as f64 + self.frac_secs
+    }
+}
+
+///! The error reported with a receive.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RxError {
+    None,
+    Timeout,
+    LateCommand,
+    BrokenChain,
+    ///! Samples were lost because the host did not keep up.
+    Overflow,
+    Alignment,
+    BadPacket,
+    Unknown(u32),
+}
+
+impl RxError {
+    pub fn from_code(code: sys::uhd_rx_metadata_error_code_t) -> RxError {
+        match code {
+            sys::UHD_RX_METADATA_ERROR_CODE_NONE => RxError::None,
+            sys::UHD_RX_METADATA_ERROR_CODE_TIMEOUT => RxError::Timeout,
+            sys::UHD_RX_METADATA_ERROR_CODE_LATE_COMMAND => RxError::LateCommand,
+            sys::UHD_RX_METADATA_ERROR_CODE_BROKEN_CHAIN => RxError::BrokenChain,
+            sys::UHD_RX_METADATA_ERROR_CODE_OVERFLOW => RxError::Overflow,
+            sys::UHD_RX_METADATA_ERROR_CODE_ALIGNMENT => RxError::Alignment,
+            sys::UHD_RX_METADATA_ERROR_CODE_BAD_PACKET => RxError::BadPacket,
+            _ => RxError::Unknown(code as u32),
+        }
+    }
+}
+
+///! What the device reported along with a block of samples.
+#[derive(Clone, Debug)]
+pub struct RecvInfo {
+    ///! The device time of the first sample if the device reported one.
+    pub time:               Option<TimeSpec>,
+    pub error:              RxError,
+    pub start_of_burst:     bool,
+    pub end_of_burst:       bool,
+    ///! True if the packet did not fit and the rest follows in the next
+    ///! receive.
+    pub more_fragments:     bool,
+    pub fragment_offset:    usize,
+    ///! True if packets were lost between the device and the host.
+    pub out_of_sequence:    bool,
+}
+
+impl RecvInfo {
+    ///! Read the metadata left behind by `uhd_rx_streamer_recv`.
+    pub fn read(md: sys::uhd_rx_metadata_handle) -> Result<RecvInfo, UhdError> {
+        unsafe {
+            let mut flag: u8 = 0;
+            let mut info = RecvInfo {
+                time:               Option::None,
+                error:              RxError::None,
+                start_of_burst:     false,
+                end_of_burst:       false,
+                more_fragments:     false,
+                fragment_offset:    0,
+                out_of_sequence:    false,
+            };
+
+            try!(check(sys::uhd_rx_metadata_has_time_spec(md, &mut flag)));
+            if flag != 0 {
+                let mut full: sys::time_t = 0;
+                let mut frac: f64 = 0.0;
+                try!(check(sys::uhd_rx_metadata_time_spec(md, &mut full, &mut frac)));
+                info.time = Option::Some(TimeSpec::new(full as i64, frac));
+            }
+
+            let mut code: sys::uhd_rx_metadata_error_code_t = 0;
+            try!(check(sys::uhd_rx_metadata_error_code(md, &mut code)));
+            info.error = RxError::from_code(code);
+
+            try!(check(sys::uhd_rx_metadata_start_of_burst(md, &mut flag)));
+            info.start_of_burst = flag != 0;
+            try!(check(sys::uhd_rx_metadata_end_of_burst(md, &mut flag)));
+            info.end_of_burst = flag != 0;
+            try!(check(sys::uhd_rx_metadata_more_fragments(md, &mut flag)));
+            info.more_fragments = flag != 0;
+            try!(check(sys::uhd_rx_metadata_out_of_sequence(md, &mut flag)));
+            info.out_of_sequence = flag != 0;
+
+            let mut offset: sys::size_t = 0;
+            try!(check(sys::uhd_rx_metadata_fragment_offset(md, &mut offset)));
+            info.fragment_offset = offset as usize;
+
+            Result::Ok(info)
+        }
+    }
+}
+
+///! Running totals of receive problems.
+#[derive(Clone, Copy, Debug)]
+pub struct RecvCounters {
+    pub overflows:          u64,
+    pub timeouts:           u64,
+    pub out_of_sequence:    u64,
+    ///! Other errors reported in the metadata.
+    pub errors:             u64,
+    ///! Samples missing according to the timestamps.
+    pub dropped:            u64,
+}
+
+impl RecvCounters {
+    pub fn new() -> RecvCounters {
+        RecvCounters {
+            overflows:          0,
+            timeouts:           0,
+            out_of_sequence:    0,
+            errors:             0,
+            dropped:            0,
+        }
+    }
+
+    ///! Account for `info` which came with `n` samples at `rate`. The
+    ///! expected time of the next sample is tracked in `next`.
+    pub fn update(&mut self, info: &RecvInfo, n: usize, rate: f64, next: &mut Option<f64>) {
+        match info.error {
+            RxError::None => (),
+            RxError::Overflow => self.overflows += 1,
+            RxError::Timeout => self.timeouts += 1,
+            _ => self.errors += 1,
+        }
+
+        if info.out_of_sequence {
+            self.out_of_sequence += 1;
+        }
+
+        match info.time {
+            Option::Some(t) => {
+                let t = t.as_secs();
+                match *next {
+                    Option::Some(expected) => {
+                        let gap = ((t - expected) * rate).round();
+                        if gap > 0.0 {
+                            self.dropped += gap as u64;
+                        }
+                    },
+                    Option::None => (),
+                }
+                *next = Option::Some(t + n as f64 / rate);
+            },
+            Option::None => (),
+        }
+    }
+}
+
+#[test]
+fn test_recv_counters() {
+    let rate = 1000000.0;
+    let mut info = RecvInfo {
+        time:               Option::Some(TimeSpec::new(100, 0.0)),
+        error:              RxError::None,
+        start_of_burst:     false,
+        end_of_burst:       false,
+        more_fragments:     false,
+        fragment_offset:    0,
+        out_of_sequence:    false,
+    };
+    let mut c = RecvCounters::new();
+    let mut next: Option<f64> = Option::None;
+
+    // The first block only sets where the next should start.
+    c.update(&info, 1000, rate, &mut next);
+    assert_eq!(c.dropped, 0);
+    assert_eq!(next, Option::Some(100.001));
+
+    // Contiguous blocks drop nothing.
+    info.time = Option::Some(TimeSpec::new(100, 0.001));
+    c.update(&info, 1000, rate, &mut next);
+    assert_eq!(c.dropped, 0);
+
+    // An overflow which lost 500 samples.
+    info.time = Option::Some(TimeSpec::new(100, 0.0025));
+    info.error = RxError::Overflow;
+    c.update(&info, 1000, rate, &mut next);
+    assert_eq!(c.overflows, 1);
+    assert_eq!(c.dropped, 500);
+
+    // Lost packets, with other errors counted apart.
+    info.time = Option::Some(TimeSpec::new(100, 0.0045));
+    info.error = RxError::BadPacket;
+    info.out_of_sequence = true;
+    c.update(&info, 1000, rate, &mut next);
+    assert_eq!(c.out_of_sequence, 1);
+    assert_eq!(c.errors, 1);
+    assert_eq!(c.dropped, 1500);
+
+    // A timeout without a time keeps the expected time.
+    info.time = Option::None;
+    info.error = RxError::Timeout;
+    info.out_of_sequence = false;
+    c.update(&info, 0, rate, &mut next);
+    assert_eq!(c.timeouts, 1);
+    assert_eq!(next, Option::Some(100.0055));
+
+    // Time running backwards is not counted as a loss.
+    info.time = Option::Some(TimeSpec::new(100, 0.0));
+    info.error = RxError::None;
+    c.update(&info, 1000, rate, &mut next);
+    assert_eq!(c.dropped, 1500);
+    assert_eq!(c.overflows, 1);
+}
@@ -1,53 +1,138 @@
 mod sys;
+mod device;
+mod error;
+mod meta;
+mod sink;
+mod sensor;
+mod health;
+mod timed;
+mod clock;
+mod pool;
+mod frontend;
 
 use ::libc;
-use std::ffi::CString;
+use std::ffi::{CString, CStr};
 use std::sync::{Arc, Mutex};
-use ::dsp::Complex;
+use ::dsp::{Complex, Source};
 use ::std;
-use ::alloc;
+use ::gain::GainDevice;
+
+pub use self::device::{DeviceInfo, DeviceArgs, Backend, UhdBackend, find, find_with, select_with};
+pub use self::error::UhdError;
+pub use self::meta::{TimeSpec, RxError, RecvInfo, RecvCounters};
+pub use self::sink::USRPSink;
+pub use self::sensor::{SensorValue, SensorData, SensorScope};
+pub use self::health::{Health, HealthCheck};
+pub use self::timed::{StreamMode, Retune};
+pub use self::clock::{TimeConfig, TimeAlign};
+pub use self::pool::BufferPool;
+pub use self::frontend::FrontEnd;
+
+use self::error::{check, check_usrp, check_rx};
+
+/// Return the string held in a buffer filled in by UHD.
+fn read_cstr(buf: &[libc::c_char]) -> String {
+    unsafe {
+        CStr::from_ptr(buf.as_ptr()).to_string_lossy().into_owned()
+    }
+}
+
+/// Return the strings held by a UHD string vector.
+fn read_string_vector(h: sys::uhd_string_vector_handle) -> Vec<String> {
+    let mut out: Vec<String> = Vec::new();
+    unsafe {
+        let mut n: sys::size_t = 0;
+        sys::uhd_string_vector_size(h, &mut n);
+        for x in 0..n {
+            let mut buf = [0 as libc::c_char; 1024];
+            sys::uhd_string_vector_at(h, x, buf.as_mut_ptr(), buf.len() as sys::size_t);
+            out.push(read_cstr(&buf));
+        }
+    }
+    out
+}
+
+/// The settings of one receive channel.
+#[derive(Clone)]
+pub struct ChannelConfig {
+    pub freq:       f64,
+    pub gain:       f64,
+    pub frontend:   FrontEnd,
+}
+
+impl ChannelConfig {
+    pub fn new(freq: f64, gain: f64) -> ChannelConfig {
+        ChannelConfig { freq: freq, gain: gain, frontend: FrontEnd::new() }
+    }
+}
+
+/// Used as the mboard argument to address every motherboard.
+const ALL_MBOARDS: sys::size_t = !0;
 
 pub struct USRPSource {
     usrp_handle:        sys::uhd_usrp_handle,
     streamer_handle:    sys::uhd_rx_streamer_handle,
     metadata_handle:    sys::uhd_rx_metadata_handle,
-    buff:               *mut Complex<f32>,
+    /// The buffers handed out by `recv` and `recv_channels`.
+    pool:               BufferPool,
+    /// Room for the channels not given a buffer by `recv_into`.
+    scratch:            Vec<Vec<Complex<f32>>>,
+    /// The buffer pointers passed to UHD, one for each channel.
+    ptrs:               Vec<*mut libc::c_void>,
     max_num_samps:      sys::size_t,
-    tunereq:            sys::uhd_tune_request_t,
-    streamargs:         sys::uhd_stream_args_t,
     streamcmd:          sys::uhd_stream_cmd_t,
-    channel:            u64,
-    buffs_ptr:          *mut *mut Complex<f32>,
+    /// The device channel numbers streamed.
+    channels:           Vec<u64>,
+    /// The frequency last asked for on each channel.
+    freqs:              Vec<f64>,
+    gain_range:         (f64, f64, f64),
+    rate:               f64,
+    counters:           RecvCounters,
+    next_time:          Option<f64>,
+    /// The number of samples received on each channel so far.
+    received:           u64,
+    /// Scheduled retunes waiting for the samples they apply to.
+    pending:            Vec<Retune>,
+    /// Retunes whose first sample has been received.
+    applied:            Vec<Retune>,
 }
 
 impl USRPSource {
-    pub fn new(sps: f64, center: f64, gain: f64) -> Arc<Mutex<USRPSource>> {
+    /// Open the first device found.
+    pub fn new(sps: f64, center: f64, gain: f64) -> Result<Arc<Mutex<USRPSource>>, UhdError> {
+        USRPSource::open(&DeviceArgs::new(), sps, center, gain)
+    }
+    
+    /// Open the device selected by `args`.
+    pub fn open(args: &DeviceArgs, sps: f64, center: f64, gain: f64) -> Result<Arc<Mutex<USRPSource>>, UhdError> {
+        USRPSource::open_channels(args, sps, Option::None, &[ChannelConfig::new(center, gain)])
+    }
+    
+    /// Open the device selected by `args` receiving on one channel for each
+    /// entry of `chans`. If `subdev` is set it is applied as the receive
+    /// subdevice specification, such as `A:A A:B`, before the channels are
+    /// configured. With more than one channel the streams are started at
+    /// the same device time so that the buffers returned are aligned.
+    pub fn open_channels(args: &DeviceArgs, sps: f64, subdev: Option<&str>, chans: &[ChannelConfig]) -> Result<Arc<Mutex<USRPSource>>, UhdError> {
+        let dev = match select_with(&UhdBackend, args) {
+            Option::Some(dev) => dev,
+            Option::None => return Result::Err(UhdError::NoDevice(args.to_args())),
+        };
+        
+        println!("[usrp] opening {} serial:{} addr:{}", dev.kind, dev.serial, dev.addr);
+        
+        let makeargs = device::make_args(&dev, args);
+        
         let mut ausrp;
         unsafe {  
-            let mut err: libc::c_uint = 0;
-            
             ausrp = Arc::new(Mutex::new(USRPSource {
                 usrp_handle:        std::mem::zeroed(),
                 streamer_handle:    std::mem::zeroed(),
                 metadata_handle:    std::mem::zeroed(),  
-                buff:               0 as *mut Complex<f32>,
-                buffs_ptr:          0 as *mut *mut Complex<f32>,
+                pool:               BufferPool::new(chans.len() * 4),
+                scratch:            (1..chans.len()).map(|_| Vec::new()).collect(),
+                ptrs:               Vec::with_capacity(chans.len()),
                 max_num_samps:      0,
-                tunereq:            sys::uhd_tune_request_t {
-                    target_freq:        center,
-                    rf_freq_policy:     sys::UHD_TUNE_REQUEST_POLICY_AUTO,
-                    rf_freq:            0.0,
-                    dsp_freq_policy:    sys::UHD_TUNE_REQUEST_POLICY_AUTO,
-                    dsp_freq:           0.0,
-                    args:               0 as *mut i8,
-                },
-                streamargs:         sys::uhd_stream_args_t {
-                    cpu_format:         CString::new("fc32").unwrap().into_raw(), 
-                    otw_format:         CString::new("sc16").unwrap().into_raw(),
-                    args:               CString::new("").unwrap().into_raw(),
-                    channel_list:       0 as *mut u64,
-                    n_channels:         1,  
-                },
                 streamcmd:          sys::uhd_stream_cmd_t {
                     //stream_mode:         sys::UHD_STREAM_MODE_NUM_SAMPS_AND_DONE,
                     stream_mode:         sys::UHD_STREAM_MODE_START_CONTINUOUS,
@@ -56,67 +141,116 @@ impl USRPSource {
                     time_spec_full_secs: 0,
                     time_spec_frac_secs: 0.0,
                 },
-                channel:            0,
+                channels:           (0..chans.len() as u64).collect(),
+                freqs:              chans.iter().map(|c| c.freq).collect(),
+                gain_range:         (0.0, 0.0, 0.0),
+                rate:               sps,
+                counters:           RecvCounters::new(),
+                next_time:          Option::None,
+                received:           0,
+                pending:            Vec::new(),
+                applied:            Vec::new(),
             }));
             
             let mut usrp = ausrp.lock().unwrap();
             
-            usrp.streamargs.channel_list = &mut usrp.channel as *mut u64;
+            try!(check(sys::uhd_usrp_make(&mut usrp.usrp_handle, CString::new(makeargs).unwrap().as_ptr())));
+            try!(check(sys::uhd_rx_streamer_make(&mut usrp.streamer_handle)));
+            try!(check(sys::uhd_rx_metadata_make(&mut usrp.metadata_handle)));
+            
+            match subdev {
+                Option::Some(markup) => {
+                    let mut spec: sys::uhd_subdev_spec_handle = std::mem::zeroed();
+                    try!(check(sys::uhd_subdev_spec_make(&mut spec, CString::new(markup).unwrap().as_ptr())));
+                    let res = check_usrp(usrp.usrp_handle, sys::uhd_usrp_set_rx_subdev_spec(usrp.usrp_handle, spec, ALL_MBOARDS));
+                    sys::uhd_subdev_spec_free(&mut spec);
+                    try!(res);
+                },
+                Option::None => (),
+            }
+            
+            let mut available: sys::size_t = 0;
+            try!(check_usrp(usrp.usrp_handle, sys::uhd_usrp_get_rx_num_channels(usrp.usrp_handle, &mut available)));
+            if (chans.len() as sys::size_t) > available {
+                return Result::Err(UhdError::Index(format!("{} channels requested but the device has {}", chans.len(), available)));
+            }
             
-            err += sys::uhd_usrp_make(&mut usrp.usrp_handle, CString::new("").unwrap().as_ptr());
-            err += sys::uhd_rx_streamer_make(&mut usrp.streamer_handle);
-            err += sys::uhd_rx_metadata_make(&mut usrp.metadata_handle);
-            err += sys::uhd_usrp_set_rx_rate(usrp.usrp_handle, sps, usrp.channel);
+            for x in 0..chans.len() {
+                let chan = usrp.channels[x];
+                try!(check_usrp(usrp.usrp_handle, sys::uhd_usrp_set_rx_rate(usrp.usrp_handle, sps, chan)));
+            }
             
             let mut actual_rx_rate: f64 = 0.0;
             
-            err += sys::uhd_usrp_get_rx_rate(usrp.usrp_handle, usrp.channel, &mut actual_rx_rate); 
-            err += sys::uhd_usrp_set_rx_gain(usrp.usrp_handle, gain, usrp.channel, CString::new("").unwrap().as_ptr());
+            let mut range: sys::uhd_meta_range_handle = std::mem::zeroed();
+            try!(check(sys::uhd_meta_range_make(&mut range)));
+            try!(check_usrp(usrp.usrp_handle, sys::uhd_usrp_get_rx_gain_range(usrp.usrp_handle, CString::new("").unwrap().as_ptr(), usrp.channels[0], range)));
+            try!(check(sys::uhd_meta_range_start(range, &mut usrp.gain_range.0)));
+            try!(check(sys::uhd_meta_range_stop(range, &mut usrp.gain_range.1)));
+            try!(check(sys::uhd_meta_range_step(range, &mut usrp.gain_range.2)));
+            try!(check(sys::uhd_meta_range_free(&mut range)));
             
-            //     pub fn uhd_usrp_set_rx_freq(h: uhd_usrp_handle,
-            //                        tune_request: *mut uhd_tune_request_t,
-            //                        chan: size_t,
-            //                        tune_result: *mut uhd_tune_result_t)
-            // -> uhd_error;
+            println!("gain range: {} to {} step {}", usrp.gain_range.0, usrp.gain_range.1, usrp.gain_range.2);
             
-            let mut tuneresult: sys::uhd_tune_result_t = std::mem::zeroed();
+            try!(check_usrp(usrp.usrp_handle, sys::uhd_usrp_get_rx_rate(usrp.usrp_handle, usrp.channels[0], &mut actual_rx_rate))); 
+            usrp.rate = actual_rx_rate;
             
-            err += sys::uhd_usrp_set_rx_freq(
-                usrp.usrp_handle, 
-                &mut usrp.tunereq as *mut sys::uhd_tune_request_t,
-                usrp.channel,
-                &mut tuneresult as *mut sys::uhd_tune_result_t
-            );
+            for x in 0..chans.len() {
+                try!(usrp.set_channel_gain(x, chans[x].gain));
+                try!(usrp.set_channel_freq(x, chans[x].freq));
+                try!(usrp.set_frontend(x, &chans[x].frontend));
+            }
             
             //     pub fn uhd_usrp_get_rx_stream(h: uhd_usrp_handle,
             //      stream_args: *mut uhd_stream_args_t,
             //      h_out: uhd_rx_streamer_handle) -> uhd_error;
             
-            err += sys::uhd_usrp_get_rx_stream(
+            // UHD copies what it needs from these during the call.
+            let cpu_format = CString::new("fc32").unwrap();
+            let otw_format = CString::new("sc16").unwrap();
+            let streamargs_args = CString::new("").unwrap();
+            let mut channel_list = usrp.channels.clone();
+            let mut streamargs = sys::uhd_stream_args_t {
+                cpu_format:         cpu_format.as_ptr() as *mut libc::c_char,
+                otw_format:         otw_format.as_ptr() as *mut libc::c_char,
+                args:               streamargs_args.as_ptr() as *mut libc::c_char,
+                channel_list:       channel_list.as_mut_ptr(),
+                n_channels:         channel_list.len() as libc::c_int,
+            };
+            
+            try!(check_usrp(usrp.usrp_handle, sys::uhd_usrp_get_rx_stream(
                 usrp.usrp_handle, 
-                &mut usrp.streamargs as &mut sys::uhd_stream_args_t,
+                &mut streamargs as &mut sys::uhd_stream_args_t,
                 usrp.streamer_handle
-            );
+            )));
             
             //     pub fn uhd_rx_streamer_max_num_samps(h: uhd_rx_streamer_handle,
             //              max_num_samps_out: *mut size_t)
             
-            err += sys::uhd_rx_streamer_max_num_samps(usrp.streamer_handle, &mut usrp.max_num_samps as *mut sys::size_t);
+            try!(check_rx(usrp.streamer_handle, sys::uhd_rx_streamer_max_num_samps(usrp.streamer_handle, &mut usrp.max_num_samps as *mut sys::size_t)));
 
             usrp.streamcmd.num_samps = usrp.max_num_samps;    
     
             println!("max_num_samps: {}", usrp.max_num_samps);        
             
+            // The channels only start together if they are told to start
+            // at a device time, so start shortly after the current one. The
+            // time itself is left alone since it may be aligned to PPS; it
+            // is only set through `align_time`.
+            if chans.len() > 1 {
+                let start = TimeSpec::from_secs(try!(usrp.time_now()).as_secs() + 0.1);
+                usrp.streamcmd.stream_now = 0;
+                usrp.streamcmd.time_spec_full_secs = start.full_secs as sys::time_t;
+                usrp.streamcmd.time_spec_frac_secs = start.frac_secs;
+            }
+            
             //     pub fn uhd_rx_streamer_issue_stream_cmd(h: uhd_rx_streamer_handle,
             //              stream_cmd:
             //              *const sys::uhd_stream_cmd_t)
             
-            err += sys::uhd_rx_streamer_issue_stream_cmd(
+            try!(check_rx(usrp.streamer_handle, sys::uhd_rx_streamer_issue_stream_cmd(
                 usrp.streamer_handle, &usrp.streamcmd as *const sys::uhd_stream_cmd_t
-            );
-            
-            usrp.buff = alloc::heap::allocate((usrp.max_num_samps * 2 * 4) as usize, 8) as *mut Complex<f32>;
-            usrp.buffs_ptr = &mut usrp.buff;
+            )));
             
             // pub fn uhd_rx_streamer_recv(h: uhd_rx_streamer_handle,
             //                            buffs: *mut *mut ::libc::c_void,
@@ -125,66 +259,471 @@ impl USRPSource {
             //                            timeout: ::libc::c_double, one_packet: u8,
             //                            items_recvd: *mut size_t) -> uhd_error;        
             //
-            
-            println!("returning error:{}", err);         
         }    
         
-        ausrp
+        Result::Ok(ausrp)
+    }
+    
+    /// Return the number of channels received.
+    pub fn num_channels(&self) -> usize {
+        self.channels.len()
+    }
+    
+    fn clamp_gain(&self, gain: f64) -> f64 {
+        // Keep within what the device reports it can do.
+        if gain < self.gain_range.0 {
+            self.gain_range.0
+        } else if gain > self.gain_range.1 {
+            self.gain_range.1
+        } else {
+            gain
+        }
+    }
+    
+    /// Set the gain of every channel.
+    pub fn set_rx_gain(&mut self, gain: f64) -> Result<(), UhdError> {
+        for x in 0..self.channels.len() {
+            try!(self.set_channel_gain(x, gain));
+        }
+        Result::Ok(())
     }
     
-    pub fn set_rx_gain(&mut self, gain: f64) {
+    /// Set the gain of the channel at `index`.
+    pub fn set_channel_gain(&mut self, index: usize, gain: f64) -> Result<(), UhdError> {
         //     pub fn uhd_usrp_set_rx_gain(h: uhd_usrp_handle, gain: ::libc::c_double,
         //                     chan: size_t,
         //                      gain_name: *const ::libc::c_char)
         // -> uhd_error;
         
-        // A safe-guard.
-        if gain > 70.0 {
-            return;
-        }
+        let gain = self.clamp_gain(gain);
         unsafe {
-            sys::uhd_usrp_set_rx_gain(self.usrp_handle, gain, self.channel, 0 as *const ::libc::c_char);
+            check_usrp(self.usrp_handle, sys::uhd_usrp_set_rx_gain(
+                self.usrp_handle, gain, self.channels[index], CString::new("").unwrap().as_ptr()
+            ))
         }
     }
     
-    pub fn recv(&mut self) -> Vec<Complex<f32>> {
+    /// Tune the channel at `index` and return the frequency actually tuned.
+    pub fn set_channel_freq(&mut self, index: usize, freq: f64) -> Result<f64, UhdError> {
+        //     pub fn uhd_usrp_set_rx_freq(h: uhd_usrp_handle,
+        //                        tune_request: *mut uhd_tune_request_t,
+        //                        chan: size_t,
+        //                        tune_result: *mut uhd_tune_result_t)
+        // -> uhd_error;
+        
+        let (start, stop, _) = try!(self.rx_freq_range(index));
+        if freq < start || freq > stop {
+            return Result::Err(UhdError::Value(format!("frequency {} outside of {} to {}", freq, start, stop)));
+        }
+        
         unsafe {
-            let mut err: libc::c_uint;
+            let mut tunereq = sys::uhd_tune_request_t {
+                target_freq:        freq,
+                rf_freq_policy:     sys::UHD_TUNE_REQUEST_POLICY_AUTO,
+                rf_freq:            0.0,
+                dsp_freq_policy:    sys::UHD_TUNE_REQUEST_POLICY_AUTO,
+                dsp_freq:           0.0,
+                args:               0 as *mut i8,
+            };
+            let mut tuneresult: sys::uhd_tune_result_t = std::mem::zeroed();
             
-            //println!("usrp trying recv");  
+            try!(check_usrp(self.usrp_handle, sys::uhd_usrp_set_rx_freq(
+                self.usrp_handle, 
+                &mut tunereq as *mut sys::uhd_tune_request_t,
+                self.channels[index],
+                &mut tuneresult as *mut sys::uhd_tune_result_t
+            )));
+            
+            self.freqs[index] = freq;
             
+            let mut actual: f64 = 0.0;
+            try!(check_usrp(self.usrp_handle, sys::uhd_usrp_get_rx_freq(self.usrp_handle, self.channels[index], &mut actual)));
+            Result::Ok(actual)
+        }
+    }
+    
+    /// Tune the channel at `index` again to the frequency last asked for.
+    pub fn retune(&mut self, index: usize) -> Result<f64, UhdError> {
+        let freq = self.freqs[index];
+        self.set_channel_freq(index, freq)
+    }
+    
+    /// Return the names of the sensors in `scope`.
+    pub fn sensor_names(&self, scope: SensorScope) -> Result<Vec<String>, UhdError> {
+        sensor::names(self.usrp_handle, scope)
+    }
+    
+    /// Read the sensor `name` in `scope`.
+    pub fn sensor(&self, scope: SensorScope, name: &str) -> Result<SensorValue, UhdError> {
+        sensor::get(self.usrp_handle, scope, name)
+    }
+    
+    fn sensor_bool(&self, scope: SensorScope, name: &str) -> Result<Option<bool>, UhdError> {
+        let v = try!(sensor::find(self.usrp_handle, scope, name));
+        Result::Ok(v.and_then(|v| v.as_bool()))
+    }
+    
+    /// Return whether the LO of the channel at `index` is locked.
+    pub fn lo_locked(&self, index: usize) -> Result<Option<bool>, UhdError> {
+        self.sensor_bool(SensorScope::Rx(self.channels[index] as usize), "lo_locked")
+    }
+    
+    /// Return whether the device is locked to its reference clock.
+    pub fn ref_locked(&self) -> Result<Option<bool>, UhdError> {
+        self.sensor_bool(SensorScope::Mboard(0), "ref_locked")
+    }
+    
+    pub fn gps_locked(&self) -> Result<Option<bool>, UhdError> {
+        self.sensor_bool(SensorScope::Mboard(0), "gps_locked")
+    }
+    
+    /// Return the temperature in degrees Celsius from the first sensor of
+    /// the board, or failing that of the first channel, whose name starts
+    /// with `temp`.
+    pub fn temperature(&self) -> Result<Option<f64>, UhdError> {
+        let scopes = [SensorScope::Mboard(0), SensorScope::Rx(self.channels[0] as usize)];
+        for scope in scopes.iter() {
+            for name in try!(self.sensor_names(*scope)).iter() {
+                if name.starts_with("temp") {
+                    let v = try!(self.sensor(*scope, name));
+                    return Result::Ok(v.as_real());
+                }
+            }
+        }
+        Result::Ok(Option::None)
+    }
+    
+    /// Return the `(start, stop, step)` of the receive gain range in dB.
+    pub fn get_rx_gain_range(&self) -> Result<(f64, f64, f64), UhdError> {
+        Result::Ok(self.gain_range)
+    }
+    
+    pub fn set_rx_agc(&mut self, enable: bool) -> Result<(), UhdError> {
+        for x in 0..self.channels.len() {
+            unsafe {
+                try!(check_usrp(self.usrp_handle, sys::uhd_usrp_set_rx_agc(
+                    self.usrp_handle, if enable { 1 } else { 0 }, self.channels[x]
+                )));
+            }
+        }
+        Result::Ok(())
+    }
+    
+    /// Return the current device time.
+    pub fn time_now(&self) -> Result<TimeSpec, UhdError> {
+        let mut full: sys::time_t = 0;
+        let mut frac: f64 = 0.0;
+        unsafe {
+            try!(check_usrp(self.usrp_handle, sys::uhd_usrp_get_time_now(self.usrp_handle, 0, &mut full, &mut frac)));
+        }
+        Result::Ok(TimeSpec::new(full as i64, frac))
+    }
+    
+    /// Make the commands that follow take effect at the device time `t`
+    /// until `clear_command_time` is called.
+    pub fn set_command_time(&mut self, t: TimeSpec) -> Result<(), UhdError> {
+        unsafe {
+            check_usrp(self.usrp_handle, sys::uhd_usrp_set_command_time(
+                self.usrp_handle, t.full_secs as sys::time_t, t.frac_secs, ALL_MBOARDS
+            ))
+        }
+    }
+    
+    pub fn clear_command_time(&mut self) -> Result<(), UhdError> {
+        unsafe {
+            check_usrp(self.usrp_handle, sys::uhd_usrp_clear_command_time(self.usrp_handle, ALL_MBOARDS))
+        }
+    }
+    
+    /// Issue a stream command to the receive streamer. If `at` is set the
+    /// command takes effect at that device time rather than immediately.
+    pub fn issue_stream_cmd(&mut self, mode: StreamMode, num_samps: usize, at: Option<TimeSpec>) -> Result<(), UhdError> {
+        let cmd = timed::stream_cmd(mode, num_samps, at);
+        unsafe {
+            check_rx(self.streamer_handle, sys::uhd_rx_streamer_issue_stream_cmd(self.streamer_handle, &cmd))
+        }
+    }
+    
+    /// Retune the first channel to `freq` at the device time `time`.
+    pub fn schedule_retune(&mut self, time: TimeSpec, freq: f64) -> Result<(), UhdError> {
+        self.schedule_channel_retune(0, time, freq)
+    }
+    
+    /// Retune the channel at `index` to `freq` at the device time `time`.
+    /// Once the samples from that time have been received the retune is
+    /// returned by `take_retunes` with the index of its first sample.
+    pub fn schedule_channel_retune(&mut self, index: usize, time: TimeSpec, freq: f64) -> Result<(), UhdError> {
+        try!(self.set_command_time(time));
+        let res = self.set_channel_freq(index, freq);
+        try!(self.clear_command_time());
+        try!(res);
+        
+        self.pending.push(Retune {
+            channel:    index,
+            freq:       freq,
+            time:       time,
+            sample:     Option::None,
+        });
+        Result::Ok(())
+    }
+    
+    /// Return the retunes applied since the last call.
+    pub fn take_retunes(&mut self) -> Vec<Retune> {
+        std::mem::replace(&mut self.applied, Vec::new())
+    }
+    
+    /// Return the number of samples received on each channel so far, which
+    /// is also the index of the first sample of the next receive.
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+    
+    /// Move the pending retunes that apply from within the `n` samples just
+    /// received, the first of which was taken at `first`.
+    fn apply_retunes(&mut self, first: TimeSpec, n: usize) {
+        let mut x = 0;
+        while x < self.pending.len() {
+            let when = self.pending[x].time;
+            let offset = if when.as_secs() < first.as_secs() {
+                // The samples it should have applied from were lost.
+                Option::Some(0)
+            } else {
+                timed::sample_offset(first, n, self.rate, when)
+            };
+            match offset {
+                Option::Some(offset) => {
+                    let mut r = self.pending.remove(x);
+                    r.sample = Option::Some(self.received + offset as u64);
+                    self.applied.push(r);
+                },
+                Option::None => x += 1,
+            }
+        }
+    }
+    
+    /// Return the totals of the problems seen while receiving.
+    pub fn counters(&self) -> RecvCounters {
+        self.counters
+    }
+    
+    /// Return the actual sample rate of the device.
+    pub fn get_rx_rate(&self) -> f64 {
+        self.rate
+    }
+    
+    /// Change the sample rate of every channel and return the rate the
+    /// device settled on.
+    pub fn set_rx_rate(&mut self, sps: f64) -> Result<f64, UhdError> {
+        for x in 0..self.channels.len() {
+            unsafe {
+                try!(check_usrp(self.usrp_handle, sys::uhd_usrp_set_rx_rate(self.usrp_handle, sps, self.channels[x])));
+            }
+        }
+        let mut actual: f64 = 0.0;
+        unsafe {
+            try!(check_usrp(self.usrp_handle, sys::uhd_usrp_get_rx_rate(self.usrp_handle, self.channels[0], &mut actual)));
+        }
+        self.rate = actual;
+        Result::Ok(actual)
+    }
+    
+    /// Receive the next block of samples of the first channel along with
+    /// what the device reported about them. Give the buffer back with
+    /// `recycle` once done with it to avoid an allocation.
+    pub fn recv(&mut self) -> Result<(Vec<Complex<f32>>, RecvInfo), UhdError> {
+        let (mut bufs, info) = try!(self.recv_channels());
+        let buf = bufs.swap_remove(0);
+        for other in bufs {
+            self.pool.put(other);
+        }
+        Result::Ok((buf, info))
+    }
+    
+    /// Receive the next block of samples of every channel. The buffers are
+    /// in the order the channels were opened, hold the same number of
+    /// samples and start at the same device time.
+    pub fn recv_channels(&mut self) -> Result<(Vec<Vec<Complex<f32>>>, RecvInfo), UhdError> {
+        let n = self.max_num_samps as usize;
+        let mut out: Vec<Vec<Complex<f32>>> = Vec::with_capacity(self.channels.len());
+        self.ptrs.clear();
+        for _ in 0..self.channels.len() {
+            let mut buf = self.pool.get(n);
+            self.ptrs.push(buf.as_mut_ptr() as *mut libc::c_void);
+            out.push(buf);
+        }
+        
+        let (got, info) = match self.recv_ptrs(n) {
+            Result::Ok(v) => v,
+            Result::Err(err) => {
+                for buf in out {
+                    self.pool.put(buf);
+                }
+                return Result::Err(err);
+            },
+        };
+        
+        for buf in out.iter_mut() {
+            buf.truncate(got);
+        }
+        Result::Ok((out, info))
+    }
+    
+    /// Give back a buffer returned by `recv` or `recv_channels`.
+    pub fn recycle(&mut self, buf: Vec<Complex<f32>>) {
+        self.pool.put(buf);
+    }
+    
+    /// Receive straight into `buf` from the first channel and return the
+    /// number of samples placed there. The samples of any other channels
+    /// are discarded.
+    pub fn recv_into(&mut self, buf: &mut [Complex<f32>]) -> Result<(usize, RecvInfo), UhdError> {
+        let n = buf.len();
+        self.ptrs.clear();
+        self.ptrs.push(buf.as_mut_ptr() as *mut libc::c_void);
+        for s in self.scratch.iter_mut() {
+            s.resize(n, Complex { i: 0.0, q: 0.0 });
+            self.ptrs.push(s.as_mut_ptr() as *mut libc::c_void);
+        }
+        self.recv_ptrs(n)
+    }
+    
+    /// Receive straight into one buffer for each channel. The buffers must
+    /// all be the same length.
+    pub fn recv_channels_into(&mut self, bufs: &mut [&mut [Complex<f32>]]) -> Result<(usize, RecvInfo), UhdError> {
+        if bufs.len() != self.channels.len() {
+            return Result::Err(UhdError::Value(format!("{} buffers given for {} channels", bufs.len(), self.channels.len())));
+        }
+        let n = bufs[0].len();
+        if bufs.iter().any(|b| b.len() != n) {
+            return Result::Err(UhdError::Value(String::from("buffers differ in length")));
+        }
+        self.ptrs.clear();
+        for buf in bufs.iter_mut() {
+            self.ptrs.push(buf.as_mut_ptr() as *mut libc::c_void);
+        }
+        self.recv_ptrs(n)
+    }
+    
+    /// Receive up to `n` samples per channel into the buffers in `ptrs`.
+    fn recv_ptrs(&mut self, n: usize) -> Result<(usize, RecvInfo), UhdError> {
+        unsafe {
             let mut num_rx_samps: sys::size_t = 0;
             
-            err = sys::uhd_rx_streamer_recv(
+            try!(check_rx(self.streamer_handle, sys::uhd_rx_streamer_recv(
                 self.streamer_handle,
-                self.buffs_ptr as *mut *mut libc::c_void,
-                self.max_num_samps,
+                self.ptrs.as_mut_ptr(),
+                n as sys::size_t,
                 &mut self.metadata_handle,
                 3.0,
                 0,
                 &mut num_rx_samps as *mut sys::size_t
-            );
-            
-            //println!("recv err {}", err);
-
-            // Treat our buffer as a vector of Complex<f32> items.
-            let sbuf: Vec<Complex<f32>> = Vec::from_raw_parts(self.buff, num_rx_samps as usize, num_rx_samps as usize);                  
+            )));
             
-            //println!("returning {} samples ... {} sizeof:{} alignof:{}", sbuf.len(), num_rx_samps, std::mem::size_of::<Complex<f32>>(),
-            //    std::mem::align_of::<Complex<f32>>()
-            //);
+            let info = try!(RecvInfo::read(self.metadata_handle));
+            self.counters.update(&info, num_rx_samps as usize, self.rate, &mut self.next_time);
             
-            // Copy the Vec (array).
-            let sbuf_clone = sbuf.clone();
+            match info.time {
+                Option::Some(first) if self.pending.len() > 0 => self.apply_retunes(first, num_rx_samps as usize),
+                _ => (),
+            }
+            self.received += num_rx_samps as u64;
             
-            // If we do not forget it then it appears that Vec decides
-            // to deallocate on its own accord, thus, we shall crash. This
-            // is the most safe manner in which we can operate for potential
-            // future functionality changes to Vec.
-            std::mem::forget(sbuf);
-            
-            // This is the new memory buffer that has been allocated.
-            sbuf_clone
+            Result::Ok((num_rx_samps as usize, info))
+        }
+    }
+}
+
+impl Drop for USRPSource {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.streamer_handle.is_null() {
+                // Stop the device sending samples nobody will read.
+                let cmd = timed::stream_cmd(StreamMode::StopContinuous, 0, Option::None);
+                sys::uhd_rx_streamer_issue_stream_cmd(self.streamer_handle, &cmd);
+                sys::uhd_rx_streamer_free(&mut self.streamer_handle);
+            }
+            if !self.metadata_handle.is_null() {
+                sys::uhd_rx_metadata_free(&mut self.metadata_handle);
+            }
+            if !self.usrp_handle.is_null() {
+                sys::uhd_usrp_free(&mut self.usrp_handle);
+            }
+        }
+    }
+}
+
+impl GainDevice for USRPSource {
+    fn set_rx_gain(&mut self, gain: f64) {
+        match USRPSource::set_rx_gain(self, gain) {
+            Result::Ok(_) => (),
+            Result::Err(err) => println!("[usrp] unable to set gain: {}", err),
+        }
+    }
+    
+    fn get_rx_gain_range(&mut self) -> (f64, f64, f64) {
+        self.gain_range
+    }
+    
+    fn set_rx_agc(&mut self, enable: bool) {
+        match USRPSource::set_rx_agc(self, enable) {
+            Result::Ok(_) => (),
+            Result::Err(err) => println!("[usrp] unable to set agc: {}", err),
+        }
+    }
+}
+
+/// Tuning applies to the first channel. There is no reference correction,
+/// so `set_ppm` is ignored.
+impl Source for USRPSource {
+    type Error = UhdError;
+    
+    fn recv_block(&mut self) -> Result<Vec<Complex<f32>>, UhdError> {
+        let (buf, _) = try!(self.recv());
+        Result::Ok(buf)
+    }
+    
+    fn recycle_block(&mut self, buf: Vec<Complex<f32>>) {
+        self.recycle(buf);
+    }
+    
+    fn rate(&self) -> f64 {
+        self.rate
+    }
+    
+    fn set_freq(&mut self, freq: f64) -> Result<(), UhdError> {
+        try!(self.set_channel_freq(0, freq));
+        Result::Ok(())
+    }
+    
+    fn set_rate(&mut self, rate: f64) -> Result<(), UhdError> {
+        try!(self.set_rx_rate(rate));
+        Result::Ok(())
+    }
+    
+    fn set_gain(&mut self, gain: Option<f64>) -> Result<(), UhdError> {
+        match gain {
+            Option::Some(gain) => {
+                // Front ends without an AGC refuse to disable it.
+                match USRPSource::set_rx_agc(self, false) {
+                    Result::Ok(_) => (),
+                    Result::Err(err) => println!("[usrp] unable to disable agc: {}", err),
+                }
+                let gain = self.clamp_gain(gain);
+                USRPSource::set_rx_gain(self, gain)
+            },
+            Option::None => self.set_rx_agc(true),
+        }
+    }
+    
+    fn gains(&self) -> Vec<f64> {
+        let (min, max, step) = self.gain_range;
+        let step = if step > 0.0 { step } else { 1.0 };
+        let mut out: Vec<f64> = Vec::new();
+        let mut gain = min;
+        while gain <= max {
+            out.push(gain);
+            gain += step;
         }
+        out
     }
 }
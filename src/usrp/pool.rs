@@ -0,0 +1,36 @@
+///! Sample buffers reused between receives.
+///!
+///! Handing out a fresh `Vec` for every receive means an allocation for
+///! each block of samples. Buffers given back with `put` are handed out
+///! again by `get` instead.
+
+use ::dsp::Complex;
+
+pub struct BufferPool {
+    free:       Vec<Vec<Complex<f32>>>,
+    ///! The most buffers kept for reuse.
+    limit:      usize,
+}
+
+impl BufferPool {
+    pub fn new(limit: usize) -> BufferPool {
+        BufferPool { free: Vec::new(), limit: limit }
+    }
+
+    ///! Return a buffer holding `len` samples.
+    pub fn get(&mut self, len: usize) -> Vec<Complex<f32>> {
+        let mut buf = match self.free.pop() {
+            Option::Some(buf) => buf,
+            Option::None => Vec::with_capacity(len),
+        };
+        buf.resize(len, Complex { i: 0.0, q: 0.0 });
+        buf
+    }
+
+    ///! Give back a buffer for reuse.
+    pub fn put(&mut self, buf: Vec<Complex<f32>>) {
+        if self.free.len() < self.limit {
+            self.free.push(buf);
+        }
+    }
+}
@@ -0,0 +1,133 @@
+///! Typed access to the sensors of the device.
+///!
+///! UHD reports each sensor as a name with a value of one of four types.
+///! The names differ between devices so the helpers on `USRPSource` return
+///! `None` when a device does not have the sensor asked for.
+
+use super::sys;
+use super::read_cstr;
+use super::read_string_vector;
+use super::error::{UhdError, check, check_usrp};
+use ::libc;
+use std::ffi::CString;
+use std;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SensorData {
+    Bool(bool),
+    Int(i64),
+    Real(f64),
+    Text(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct SensorValue {
+    pub name:       String,
+    pub data:       SensorData,
+    pub unit:       String,
+}
+
+impl SensorValue {
+    fn read(h: sys::uhd_sensor_value_handle) -> Result<SensorValue, UhdError> {
+        unsafe {
+            let mut buf = [0 as libc::c_char; 256];
+            try!(check(sys::uhd_sensor_value_name(h, buf.as_mut_ptr(), buf.len() as sys::size_t)));
+            let name = read_cstr(&buf);
+            try!(check(sys::uhd_sensor_value_unit(h, buf.as_mut_ptr(), buf.len() as sys::size_t)));
+            let unit = read_cstr(&buf);
+
+            let mut kind: sys::uhd_sensor_value_data_type_t = 0;
+            try!(check(sys::uhd_sensor_value_data_type(h, &mut kind)));
+            let data = match kind {
+                sys::UHD_SENSOR_VALUE_BOOLEAN => {
+                    let mut v: u8 = 0;
+                    try!(check(sys::uhd_sensor_value_to_bool(h, &mut v)));
+                    SensorData::Bool(v != 0)
+                },
+                sys::UHD_SENSOR_VALUE_INTEGER => {
+                    let mut v: libc::c_int = 0;
+                    try!(check(sys::uhd_sensor_value_to_int(h, &mut v)));
+                    SensorData::Int(v as i64)
+                },
+                sys::UHD_SENSOR_VALUE_REALNUM => {
+                    let mut v: f64 = 0.0;
+                    try!(check(sys::uhd_sensor_value_to_realnum(h, &mut v)));
+                    SensorData::Real(v)
+                },
+                _ => {
+                    try!(check(sys::uhd_sensor_value_value(h, buf.as_mut_ptr(), buf.len() as sys::size_t)));
+                    SensorData::Text(read_cstr(&buf))
+                },
+            };
+
+            Result::Ok(SensorValue { name: name, data: data, unit: unit })
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self.data {
+            SensorData::Bool(v) => Option::Some(v),
+            _ => Option::None,
+        }
+    }
+
+    ///! Return the value as a number if it is one.
+    pub fn as_real(&self) -> Option<f64> {
+        match self.data {
+            SensorData::Int(v) => Option::Some(v as f64),
+            SensorData::Real(v) => Option::Some(v),
+            _ => Option::None,
+        }
+    }
+}
+
+///! Which part of the device a sensor belongs to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SensorScope {
+    Mboard(usize),
+    Rx(usize),
+}
+
+pub fn names(h: sys::uhd_usrp_handle, scope: SensorScope) -> Result<Vec<String>, UhdError> {
+    unsafe {
+        let mut v: sys::uhd_string_vector_handle = std::mem::zeroed();
+        try!(check(sys::uhd_string_vector_make(&mut v)));
+        let res = match scope {
+            SensorScope::Mboard(n) => check_usrp(h, sys::uhd_usrp_get_mboard_sensor_names(h, n as sys::size_t, &mut v)),
+            SensorScope::Rx(n) => check_usrp(h, sys::uhd_usrp_get_rx_sensor_names(h, n as sys::size_t, &mut v)),
+        };
+        let out = read_string_vector(v);
+        sys::uhd_string_vector_free(&mut v);
+        try!(res);
+        Result::Ok(out)
+    }
+}
+
+pub fn get(h: sys::uhd_usrp_handle, scope: SensorScope, name: &str) -> Result<SensorValue, UhdError> {
+    unsafe {
+        // UHD fills in a value that already exists.
+        let empty = CString::new("").unwrap();
+        let mut v: sys::uhd_sensor_value_handle = std::mem::zeroed();
+        try!(check(sys::uhd_sensor_value_make_from_realnum(&mut v, empty.as_ptr(), 0.0, empty.as_ptr(), empty.as_ptr())));
+        let cname = CString::new(name).unwrap();
+        let res = match scope {
+            SensorScope::Mboard(n) => check_usrp(h, sys::uhd_usrp_get_mboard_sensor(h, cname.as_ptr(), n as sys::size_t, &mut v)),
+            SensorScope::Rx(n) => check_usrp(h, sys::uhd_usrp_get_rx_sensor(h, cname.as_ptr(), n as sys::size_t, &mut v)),
+        };
+        let out = match res {
+            Result::Ok(_) => SensorValue::read(v),
+            Result::Err(err) => Result::Err(err),
+        };
+        sys::uhd_sensor_value_free(&mut v);
+        out
+    }
+}
+
+///! Like `get` but returns `None` if the sensor does not exist.
+pub fn find(h: sys::uhd_usrp_handle, scope: SensorScope, name: &str) -> Result<Option<SensorValue>, UhdError> {
+    let all = try!(names(h, scope));
+    if !all.iter().any(|n| n == name) {
+        return Result::Ok(Option::None);
+    }
+    Result::Ok(Option::Some(try!(get(h, scope, name))))
+}
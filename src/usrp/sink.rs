@@ -0,0 +1,213 @@
+///! Transmission of samples through a USRP.
+///!
+///! `USRPSink` implements `dsp::Sink` so code written against the trait can
+///! be exercised offline with `dsp::FileSink`.
+
+use super::sys;
+use super::device;
+use super::error::{UhdError, check, check_usrp, check_tx};
+use super::meta::TimeSpec;
+use super::{DeviceArgs, UhdBackend, select_with};
+use ::dsp::{Complex, Sink, SendFlags, SinkEvent, SinkEventKind};
+use ::libc;
+use std::ffi::CString;
+use std;
+
+///! How long a send may block waiting for room on the device.
+const SEND_TIMEOUT: f64 = 3.0;
+
+pub struct USRPSink {
+    usrp_handle:        sys::uhd_usrp_handle,
+    streamer_handle:    sys::uhd_tx_streamer_handle,
+    async_handle:       sys::uhd_async_metadata_handle,
+    max_num_samps:      usize,
+    rate:               f64,
+    channel:            u64,
+}
+
+impl USRPSink {
+    ///! Open the first device found.
+    pub fn new(sps: f64, center: f64, gain: f64) -> Result<USRPSink, UhdError> {
+        USRPSink::open(&DeviceArgs::new(), sps, center, gain)
+    }
+
+    ///! Open the device selected by `args` for transmission on `center`.
+    pub fn open(args: &DeviceArgs, sps: f64, center: f64, gain: f64) -> Result<USRPSink, UhdError> {
+        let dev = match select_with(&UhdBackend, args) {
+            Option::Some(dev) => dev,
+            Option::None => return Result::Err(UhdError::NoDevice(args.to_args())),
+        };
+
+        println!("[usrp] opening {} serial:{} addr:{} for transmit", dev.kind, dev.serial, dev.addr);
+
+        let makeargs = device::make_args(&dev, args);
+
+        unsafe {
+            // Built first so that `Drop` releases whatever was made should a
+            // later call fail.
+            let mut sink = USRPSink {
+                usrp_handle:        std::ptr::null_mut(),
+                streamer_handle:    std::ptr::null_mut(),
+                async_handle:       std::ptr::null_mut(),
+                max_num_samps:      0,
+                rate:               sps,
+                channel:            0,
+            };
+
+            try!(check(sys::uhd_usrp_make(&mut sink.usrp_handle, CString::new(makeargs).unwrap().as_ptr())));
+            try!(check(sys::uhd_tx_streamer_make(&mut sink.streamer_handle)));
+            try!(check(sys::uhd_async_metadata_make(&mut sink.async_handle)));
+
+            let h = sink.usrp_handle;
+            try!(check_usrp(h, sys::uhd_usrp_set_tx_rate(h, sps, sink.channel)));
+            try!(check_usrp(h, sys::uhd_usrp_get_tx_rate(h, sink.channel, &mut sink.rate)));
+            try!(check_usrp(h, sys::uhd_usrp_set_tx_gain(h, gain, sink.channel, CString::new("").unwrap().as_ptr())));
+
+            let mut tunereq = sys::uhd_tune_request_t {
+                target_freq:        center,
+                rf_freq_policy:     sys::UHD_TUNE_REQUEST_POLICY_AUTO,
+                rf_freq:            0.0,
+                dsp_freq_policy:    sys::UHD_TUNE_REQUEST_POLICY_AUTO,
+                dsp_freq:           0.0,
+                args:               0 as *mut i8,
+            };
+            let mut tuneresult: sys::uhd_tune_result_t = std::mem::zeroed();
+            try!(check_usrp(h, sys::uhd_usrp_set_tx_freq(h, &mut tunereq, sink.channel, &mut tuneresult)));
+
+            let cpu_format = CString::new("fc32").unwrap();
+            let otw_format = CString::new("sc16").unwrap();
+            let streamargs_args = CString::new("").unwrap();
+            let mut channel = sink.channel;
+            let mut streamargs = sys::uhd_stream_args_t {
+                cpu_format:         cpu_format.as_ptr() as *mut libc::c_char,
+                otw_format:         otw_format.as_ptr() as *mut libc::c_char,
+                args:               streamargs_args.as_ptr() as *mut libc::c_char,
+                channel_list:       &mut channel,
+                n_channels:         1,
+            };
+            try!(check_usrp(h, sys::uhd_usrp_get_tx_stream(h, &mut streamargs, sink.streamer_handle)));
+
+            let mut max: sys::size_t = 0;
+            try!(check_tx(sink.streamer_handle, sys::uhd_tx_streamer_max_num_samps(sink.streamer_handle, &mut max)));
+            sink.max_num_samps = max as usize;
+
+            println!("[usrp] tx rate: {} max_num_samps: {}", sink.rate, sink.max_num_samps);
+
+            Result::Ok(sink)
+        }
+    }
+
+    ///! The rate the device actually runs at.
+    pub fn get_tx_rate(&self) -> f64 {
+        self.rate
+    }
+
+    ///! The most samples carried by one packet.
+    pub fn max_num_samps(&self) -> usize {
+        self.max_num_samps
+    }
+}
+
+fn event_kind(code: sys::uhd_async_metadata_event_code_t) -> SinkEventKind {
+    match code {
+        sys::UHD_ASYNC_METADATA_EVENT_CODE_BURST_ACK => SinkEventKind::BurstAck,
+        sys::UHD_ASYNC_METADATA_EVENT_CODE_UNDERFLOW => SinkEventKind::Underflow,
+        sys::UHD_ASYNC_METADATA_EVENT_CODE_SEQ_ERROR => SinkEventKind::SeqError,
+        sys::UHD_ASYNC_METADATA_EVENT_CODE_TIME_ERROR => SinkEventKind::TimeError,
+        sys::UHD_ASYNC_METADATA_EVENT_CODE_UNDERFLOW_IN_PACKET => SinkEventKind::UnderflowInPacket,
+        sys::UHD_ASYNC_METADATA_EVENT_CODE_SEQ_ERROR_IN_BURST => SinkEventKind::SeqErrorInBurst,
+        _ => SinkEventKind::UserPayload,
+    }
+}
+
+impl Sink for USRPSink {
+    type Error = UhdError;
+
+    fn send(&mut self, buf: &[Complex<f32>], flags: SendFlags) -> Result<usize, UhdError> {
+        unsafe {
+            let time = match flags.time {
+                Option::Some(t) => Option::Some(TimeSpec::from_secs(t)),
+                Option::None => Option::None,
+            };
+            let (full, frac) = match time {
+                Option::Some(t) => (t.full_secs, t.frac_secs),
+                Option::None => (0, 0.0),
+            };
+
+            let mut md: sys::uhd_tx_metadata_handle = std::ptr::null_mut();
+            try!(check(sys::uhd_tx_metadata_make(
+                &mut md,
+                time.is_some() as u8,
+                full as sys::time_t,
+                frac,
+                flags.start_of_burst as u8,
+                flags.end_of_burst as u8
+            )));
+
+            let mut ptr = buf.as_ptr() as *const libc::c_void;
+            let mut sent: sys::size_t = 0;
+            let code = sys::uhd_tx_streamer_send(
+                self.streamer_handle,
+                &mut ptr,
+                buf.len() as sys::size_t,
+                &mut md,
+                SEND_TIMEOUT,
+                &mut sent
+            );
+            sys::uhd_tx_metadata_free(&mut md);
+            try!(check_tx(self.streamer_handle, code));
+
+            Result::Ok(sent as usize)
+        }
+    }
+
+    fn recv_event(&mut self, timeout: f64) -> Result<Option<SinkEvent>, UhdError> {
+        unsafe {
+            let mut valid: u8 = 0;
+            try!(check_tx(self.streamer_handle, sys::uhd_tx_streamer_recv_async_msg(
+                self.streamer_handle, &mut self.async_handle, timeout, &mut valid
+            )));
+            if valid == 0 {
+                return Result::Ok(Option::None);
+            }
+
+            let md = self.async_handle;
+            let mut code: sys::uhd_async_metadata_event_code_t = 0;
+            try!(check(sys::uhd_async_metadata_event_code(md, &mut code)));
+            let mut channel: sys::size_t = 0;
+            try!(check(sys::uhd_async_metadata_channel(md, &mut channel)));
+
+            let mut flag: u8 = 0;
+            let mut time = Option::None;
+            try!(check(sys::uhd_async_metadata_has_time_spec(md, &mut flag)));
+            if flag != 0 {
+                let mut full: sys::time_t = 0;
+                let mut frac: f64 = 0.0;
+                try!(check(sys::uhd_async_metadata_time_spec(md, &mut full, &mut frac)));
+                time = Option::Some(TimeSpec::new(full as i64, frac).as_secs());
+            }
+
+            Result::Ok(Option::Some(SinkEvent {
+                kind:       event_kind(code),
+                channel:    channel as usize,
+                time:       time,
+            }))
+        }
+    }
+}
+
+impl Drop for USRPSink {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.async_handle.is_null() {
+                sys::uhd_async_metadata_free(&mut self.async_handle);
+            }
+            if !self.streamer_handle.is_null() {
+                sys::uhd_tx_streamer_free(&mut self.streamer_handle);
+            }
+            if !self.usrp_handle.is_null() {
+                sys::uhd_usrp_free(&mut self.usrp_handle);
+            }
+        }
+    }
+}
@@ -0,0 +1,83 @@
+///! Commands that take effect at a device time.
+///!
+///! A retune scheduled for a device time is applied by the device exactly
+///! then, so the first sample received on the new frequency can be found
+///! from the timestamps that come with the received samples.
+
+use super::sys;
+use super::meta::TimeSpec;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StreamMode {
+    StartContinuous,
+    StopContinuous,
+    ///! Receive a number of samples and stop.
+    NumSampsAndDone,
+    ///! Receive a number of samples and expect another command to follow.
+    NumSampsAndMore,
+}
+
+impl StreamMode {
+    pub fn to_sys(&self) -> sys::uhd_stream_mode_t {
+        match *self {
+            StreamMode::StartContinuous => sys::UHD_STREAM_MODE_START_CONTINUOUS,
+            StreamMode::StopContinuous => sys::UHD_STREAM_MODE_STOP_CONTINUOUS,
+            StreamMode::NumSampsAndDone => sys::UHD_STREAM_MODE_NUM_SAMPS_AND_DONE,
+            StreamMode::NumSampsAndMore => sys::UHD_STREAM_MODE_NUM_SAMPS_AND_MORE,
+        }
+    }
+}
+
+///! Build a stream command starting at `at`, or immediately if not set.
+pub fn stream_cmd(mode: StreamMode, num_samps: usize, at: Option<TimeSpec>) -> sys::uhd_stream_cmd_t {
+    let (now, full, frac) = match at {
+        Option::Some(t) => (0, t.full_secs, t.frac_secs),
+        Option::None => (1, 0, 0.0),
+    };
+    sys::uhd_stream_cmd_t {
+        stream_mode:            mode.to_sys(),
+        num_samps:              num_samps as sys::size_t,
+        stream_now:             now,
+        time_spec_full_secs:    full as sys::time_t,
+        time_spec_frac_secs:    frac,
+    }
+}
+
+///! A retune that has been scheduled on the device.
+#[derive(Clone, Copy, Debug)]
+pub struct Retune {
+    ///! The index of the channel retuned.
+    pub channel:    usize,
+    pub freq:       f64,
+    ///! The device time at which the retune takes effect.
+    pub time:       TimeSpec,
+    ///! Once applied, the index of the first sample on the new frequency
+    ///! counting every sample received on the channel.
+    pub sample:     Option<u64>,
+}
+
+///! Return the index within a buffer of `n` samples at `rate` starting at
+///! the device time `first` of the sample taken at `when`, if it is among
+///! them.
+pub fn sample_offset(first: TimeSpec, n: usize, rate: f64, when: TimeSpec) -> Option<usize> {
+    // Subtract the whole seconds apart so no precision is lost to them.
+    let secs = (when.full_secs - first.full_secs) as f64 + (when.frac_secs - first.frac_secs);
+    let offset = (secs * rate).round();
+    if offset < 0.0 || offset >= n as f64 {
+        Option::None
+    } else {
+        Option::Some(offset as usize)
+    }
+}
+
+#[test]
+fn test_sample_offset() {
+    let first = TimeSpec::new(1000, 0.5);
+    let rate = 4e6;
+    assert_eq!(sample_offset(first, 1000, rate, TimeSpec::new(1000, 0.5)), Option::Some(0));
+    assert_eq!(sample_offset(first, 1000, rate, TimeSpec::new(1000, 0.5 + 100.0 / rate)), Option::Some(100));
+    assert_eq!(sample_offset(first, 1000, rate, TimeSpec::new(1000, 0.5 + 1000.0 / rate)), Option::None);
+    assert_eq!(sample_offset(first, 1000, rate, TimeSpec::new(1000, 0.4)), Option::None);
+    let first = TimeSpec::new(1000, 1.0 - 10.0 / rate);
+    assert_eq!(sample_offset(first, 1000, rate, TimeSpec::new(1001, 0.0)), Option::Some(10));
+}